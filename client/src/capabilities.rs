@@ -0,0 +1,41 @@
+use serde::Deserialize;
+
+/// What a fabric server told the client about itself, and the
+/// connection to it, in the `HELLO` handshake on connect.
+///
+/// NOTE: Servers from before the handshake existed are described
+/// as speaking protocol version 1, without any features.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Capabilities {
+    /// The name of the server software
+    pub server: String,
+    /// The version of the server software
+    pub version: String,
+    /// The protocol version negotiated for the connection
+    pub protocol: u8,
+    /// The ID the server gave the connection
+    #[serde(rename = "id")]
+    pub connection_id: u64,
+    /// The protocol features enabled, like `framing` or `tls`
+    pub features: Vec<String>,
+    /// The commands the server supports
+    pub commands: Vec<String>,
+}
+impl Capabilities {
+    /// Whether the server has a feature enabled.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            server: "fabric-cache".into(),
+            version: String::new(),
+            protocol: 1,
+            connection_id: 0,
+            features: Vec::new(),
+            commands: ["GET", "SET", "REMOVE"].map(String::from).to_vec(),
+        }
+    }
+}
@@ -1,54 +1,304 @@
-use crate::Error;
+use crate::{Capabilities, Encoding, Error, Pipeline, Subscription, TlsConfig};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
     },
+    net::TcpStream,
 };
 
+pub(crate) type Reader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+pub(crate) type Writer = BufWriter<Box<dyn AsyncWrite + Send + Unpin>>;
+
 /// Client for interacting with your fabric server
 pub struct FabricClient {
-    reader: BufReader<OwnedReadHalf>,
-    writer: BufWriter<OwnedWriteHalf>,
+    reader: Reader,
+    writer: Writer,
+    framed: bool,
+    /// How values are sent to and from the server
+    encoding: Encoding,
+    /// The ID to tag the next request with, once request IDs are in use
+    next_id: Option<u64>,
+    capabilities: Capabilities,
 }
 impl FabricClient {
     /// Open a connection to your fabric server.
+    ///
+    /// NOTE: Every connection starts with a `HELLO` handshake, whose outcome
+    /// is kept as the client's `capabilities`. Servers from before the
+    /// handshake hang up on it, so they're connected to again without it.
     pub async fn connect(addr: &str) -> Result<Self, Error> {
-        let stream = TcpStream::connect(addr).await?;
-        let (read_half, write_half) = stream.into_split();
-        let reader = BufReader::new(read_half);
-        let writer = BufWriter::new(write_half);
-        Ok(FabricClient { reader, writer })
+        let mut client = FabricClient::from_stream(TcpStream::connect(addr).await?);
+        if !client.hello().await? {
+            client = FabricClient::from_stream(TcpStream::connect(addr).await?);
+        }
+        Ok(client)
     }
 
-    /// Perform the SET command on a provided key to
-    /// either insert, or update the value of the key.
+    /// Open a TLS connection to your fabric server.
     ///
-    /// NOTE: That any data structure `T` for the value
-    /// must implement the `serde::Serialize` trait.
-    pub async fn set<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), Error> {
-        let serialized_data = serde_json::to_string(value).map_err(Error::BadDataStructure)?;
+    /// NOTE: The server's certificate is verified against the
+    /// host in `addr`, which can be a domain name or an IP address.
+    pub async fn connect_tls(addr: &str, tls: &TlsConfig) -> Result<Self, Error> {
+        let host = addr
+            .rsplit_once(':')
+            .map_or(addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(|e| Error::Tls(format!("Bad Server Name \"{host}\": {e}")))?;
 
-        let command = format!("SET {} {}\n", key, serialized_data);
-        self.writer.write_all(command.as_bytes()).await?;
-        self.writer.flush().await?;
+        let connector = &tls.connector()?;
+        let open = || async {
+            let stream = TcpStream::connect(addr).await?;
+            let stream = connector.connect(server_name.clone(), stream).await?;
+            Ok::<_, Error>(FabricClient::from_stream(stream))
+        };
+        let mut client = open().await?;
+        if !client.hello().await? {
+            client = open().await?;
+        }
+        Ok(client)
+    }
 
-        let mut resp = String::new();
-        let bytes = self.reader.read_line(&mut resp).await?;
+    /// Open a connection to your fabric server over
+    /// the unix domain socket at `path`.
+    #[cfg(unix)]
+    pub async fn connect_unix<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut client = FabricClient::from_stream(tokio::net::UnixStream::connect(path).await?);
+        if !client.hello().await? {
+            client = FabricClient::from_stream(tokio::net::UnixStream::connect(path).await?);
+        }
+        Ok(client)
+    }
 
-        if bytes == 0 {
-            return Err(Error::Unknown("Disconnected".into()));
+    /// Wrap any connected stream to a fabric server.
+    fn from_stream<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let reader = BufReader::new(Box::new(read_half) as Box<dyn AsyncRead + Send + Unpin>);
+        let writer = BufWriter::new(Box::new(write_half) as Box<dyn AsyncWrite + Send + Unpin>);
+        FabricClient {
+            reader,
+            writer,
+            framed: false,
+            encoding: Encoding::Json,
+            next_id: None,
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    /// Perform the HELLO handshake, where the server describes itself and
+    /// which features it supports, returning false if the server hung up.
+    async fn hello(&mut self) -> Result<bool, Error> {
+        match self.request("HELLO", "").await {
+            Ok(resp) => self.capabilities = serde_json::from_str(&resp)?,
+            // Servers from before the handshake don't know it, and
+            // the oldest ones disconnect on any command they don't know
+            Err(Error::UnsupportedCommand(_)) => {}
+            Err(e) if is_disconnect(&e) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        Ok(true)
+    }
+
+    /// What the server supports, as told in the handshake on connect.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Switch the connection to the framed protocol (version 2), where
+    /// every request and response is prefixed with its length, so
+    /// neither has to fit on a single line.
+    pub async fn use_framing(&mut self) -> Result<(), Error> {
+        let resp = self.request("PROTOCOL 2", "").await?;
+        if resp.trim() == "OK" {
+            self.framed = true;
+            self.capabilities.protocol = 2;
+            Ok(())
+        } else {
+            Err(Error::Unknown(resp))
         }
+    }
 
-        if resp.contains("OK") {
+    /// Send values to and from the server in `encoding` from now on,
+    /// switching to the framed protocol first for binary encodings,
+    /// so it's best picked right after connecting.
+    ///
+    /// NOTE: Values are still stored as JSON, the encoding only
+    /// changes how they're sent, and is only ever used for SET and GET.
+    pub async fn use_encoding(&mut self, encoding: Encoding) -> Result<(), Error> {
+        if encoding != Encoding::Json && !self.framed {
+            self.use_framing().await?;
+        }
+        let resp = self
+            .request(&format!("ENCODING {}", encoding.name()), "")
+            .await?;
+        if resp.trim() == "OK" {
+            self.encoding = encoding;
             Ok(())
         } else {
             Err(Error::Unknown(resp))
         }
     }
 
+    /// Tag every request from now on with an ID, as `#<id> <command>`,
+    /// which the server echoes back in its response, so responses are
+    /// matched to their requests by ID rather than by order.
+    pub fn use_request_ids(&mut self) {
+        self.next_id.get_or_insert(1);
+    }
+
+    /// Send every command queued in a pipeline before reading any of
+    /// their responses, so they cost one round trip instead of one each.
+    ///
+    /// NOTE: A command failing doesn't stop the ones after it, so its
+    /// error is returned in its place, among the results of the others.
+    pub async fn execute(
+        &mut self,
+        pipeline: &Pipeline,
+    ) -> Result<Vec<Result<Value, Error>>, Error> {
+        let encoding = self.encoding;
+        let requests = pipeline
+            .commands
+            .iter()
+            .map(|queued| {
+                let command = match &queued.value {
+                    Some(value) => encoding.request(&queued.command, value)?,
+                    None => queued.command.clone().into_bytes(),
+                };
+                Ok(self.tag(command))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // Responses are read while requests are still being written, so
+        // neither side gets stuck on a full buffer with a long pipeline
+        let framed = self.framed;
+        let write = async {
+            for (_, command) in &requests {
+                write_request(&mut self.writer, framed, command).await?;
+            }
+            self.writer.flush().await?;
+            Ok::<_, Error>(())
+        };
+        let read = async {
+            let mut responses = Vec::with_capacity(requests.len());
+            for _ in &requests {
+                responses.push(read_response(&mut self.reader, framed).await?);
+            }
+            Ok::<_, Error>(responses)
+        };
+        let ((), responses) = tokio::try_join!(write, read)?;
+
+        let mut results: Vec<Option<Result<Value, Error>>> =
+            (0..requests.len()).map(|_| None).collect();
+        for (i, resp) in responses.into_iter().enumerate() {
+            let (id, resp) = self.split_tag(resp);
+            let i = match id {
+                Some(id) => requests
+                    .iter()
+                    .position(|(request_id, _)| *request_id == Some(id))
+                    .ok_or_else(|| Error::Unknown(format!("Response For Unknown Request {id}")))?,
+                None => i,
+            };
+
+            let queued = &pipeline.commands[i];
+            let result = match Error::from_bytes(&resp, &queued.key) {
+                Some(e) => Err(e),
+                None => queued.reply.parse(resp, encoding),
+            };
+            if results[i].replace(result).is_some() {
+                return Err(Error::Unknown(format!(
+                    "Duplicate Response For Request {i}"
+                )));
+            }
+        }
+
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Send a command about `key` and read the response to it, as
+    /// a line or as a frame depending on the protocol in use.
+    ///
+    /// NOTE: `ERR <code> <message>` replies are returned as the matching error.
+    async fn request(&mut self, command: &str, key: &str) -> Result<String, Error> {
+        let resp = self.request_bytes(command.as_bytes().to_vec(), key).await?;
+        String::from_utf8(resp).map_err(|_| Error::Unknown("Response Is Not UTF-8".into()))
+    }
+
+    /// Send a command about `key` and read the response to it as is,
+    /// since with a binary encoding neither has to be UTF-8.
+    async fn request_bytes(&mut self, command: Vec<u8>, key: &str) -> Result<Vec<u8>, Error> {
+        let (request_id, command) = self.tag(command);
+        write_request(&mut self.writer, self.framed, &command).await?;
+        self.writer.flush().await?;
+
+        let resp = read_response(&mut self.reader, self.framed).await?;
+        let (id, resp) = self.split_tag(resp);
+        if id.is_some() && id != request_id {
+            return Err(Error::Unknown(format!(
+                "Response For Request {id:?} Instead Of {request_id:?}"
+            )));
+        }
+        Error::from_bytes(&resp, key).map_or(Ok(resp), Err)
+    }
+
+    /// Tag a command with the next request ID, if request IDs are in use.
+    fn tag(&mut self, command: Vec<u8>) -> (Option<u64>, Vec<u8>) {
+        match self.next_id.as_mut() {
+            Some(next_id) => {
+                let id = *next_id;
+                *next_id += 1;
+                (Some(id), [format!("#{id} ").as_bytes(), &command].concat())
+            }
+            None => (None, command),
+        }
+    }
+
+    /// Split the request ID off of a response, if request IDs are in use.
+    fn split_tag(&self, resp: Vec<u8>) -> (Option<u64>, Vec<u8>) {
+        if self.next_id.is_none() {
+            return (None, resp);
+        }
+        let tagged = resp
+            .strip_prefix(b"#")
+            .and_then(|tagged| {
+                let space = tagged.iter().position(|b| *b == b' ')?;
+                Some((&tagged[..space], &tagged[space + 1..]))
+            })
+            .and_then(|(id, resp)| {
+                let id = std::str::from_utf8(id).ok()?.parse().ok()?;
+                Some((id, resp.to_vec()))
+            });
+        match tagged {
+            Some((id, resp)) => (Some(id), resp),
+            None => (None, resp),
+        }
+    }
+
+    /// Perform the SET command on a provided key to
+    /// either insert, or update the value of the key.
+    ///
+    /// NOTE: That any data structure `T` for the value
+    /// must implement the `serde::Serialize` trait.
+    pub async fn set<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        let command = self
+            .encoding
+            .request(&format!("SET {}", quote(key)), value)?;
+        let resp = self.request_bytes(command, key).await?;
+
+        if resp.trim_ascii() == b"OK" {
+            Ok(())
+        } else {
+            Err(Error::Unknown(String::from_utf8_lossy(&resp).into()))
+        }
+    }
+
     /// Perform the GET command on a provided key to
     /// grab the current value of the key.
     ///
@@ -58,65 +308,269 @@ impl FabricClient {
     where
         T: for<'de> Deserialize<'de>,
     {
-        let command = format!("GET {}\n", key.into());
-        self.writer.write_all(command.as_bytes()).await?;
-        self.writer.flush().await?;
-
-        let mut resp = String::new();
-        self.reader.read_line(&mut resp).await?;
+        let key = key.into();
+        let resp = self
+            .request_bytes(format!("GET {}", quote(&key)).into_bytes(), &key)
+            .await?;
 
-        let value: T = serde_json::from_str(&resp)?;
-        Ok(value)
+        self.encoding.value(&resp)
     }
 
     /// Perform the REMOVE command on a provided key to
     /// remove the key/value pair from cache.
     pub async fn remove(&mut self, key: &str) -> Result<(), Error> {
-        let command = format!("REMOVE {}\n", key);
-        self.writer.write_all(command.as_bytes()).await?;
-        self.writer.flush().await?;
+        let resp = self.request(&format!("REMOVE {}", quote(key)), key).await?;
+
+        if resp.trim() == "OK" {
+            Ok(())
+        } else {
+            Err(Error::Unknown(resp))
+        }
+    }
+
+    /// Perform the SETBLOB command on a provided top-level key to insert,
+    /// or update, an opaque binary value, like an image or a protobuf payload.
+    ///
+    /// NOTE: Blobs are sent as is in frames, so this switches
+    /// the connection to the framed protocol if it isn't already.
+    pub async fn set_blob<B: AsRef<[u8]>>(&mut self, key: &str, blob: B) -> Result<(), Error> {
+        if !self.framed {
+            self.use_framing().await?;
+        }
+
+        let command = [
+            format!("SETBLOB {}\n", quote(key)).as_bytes(),
+            blob.as_ref(),
+        ]
+        .concat();
+        let resp = self.request_bytes(command, key).await?;
+
+        if resp.trim_ascii() == b"OK" {
+            Ok(())
+        } else {
+            Err(Error::Unknown(String::from_utf8_lossy(&resp).into()))
+        }
+    }
+
+    /// Perform the GETBLOB command on a provided key to grab
+    /// the current opaque binary value of the key.
+    ///
+    /// NOTE: Blobs are sent as is in frames, so this switches
+    /// the connection to the framed protocol if it isn't already.
+    pub async fn get_blob(&mut self, key: &str) -> Result<Vec<u8>, Error> {
+        if !self.framed {
+            self.use_framing().await?;
+        }
 
-        let mut resp = String::new();
-        self.reader.read_line(&mut resp).await?;
+        let resp = self
+            .request_bytes(format!("GETBLOB {}", quote(key)).into_bytes(), key)
+            .await?;
+        match resp.strip_prefix(b"VALUE\n") {
+            Some(blob) => Ok(blob.to_vec()),
+            None => Err(Error::Unknown(String::from_utf8_lossy(&resp).into())),
+        }
+    }
+
+    /// Perform the DUMP command on a provided key to serialize
+    /// its value into a portable blob, which can be restored on
+    /// any fabric server with `restore`.
+    pub async fn dump(&mut self, key: &str) -> Result<String, Error> {
+        let resp = self.request(&format!("DUMP {}", quote(key)), key).await?;
+        Ok(resp.trim().into())
+    }
+
+    /// Perform the RESTORE command on a provided key to set its
+    /// value from a blob created by `dump`.
+    ///
+    /// NOTE: Unless `replace` is true, restoring onto
+    /// a key that already exists fails.
+    pub async fn restore(&mut self, key: &str, blob: &str, replace: bool) -> Result<(), Error> {
+        let command = if replace {
+            format!("RESTORE {} {} REPLACE", quote(key), blob)
+        } else {
+            format!("RESTORE {} {}", quote(key), blob)
+        };
+        let resp = self.request(&command, key).await?;
 
-        if resp.contains("OK") {
+        if resp.trim() == "OK" {
             Ok(())
         } else {
             Err(Error::Unknown(resp))
         }
     }
+
+    /// Perform the STATS command to grab the counters about the
+    /// server's clients and the limits it enforces on them.
+    pub async fn stats(&mut self) -> Result<Value, Error> {
+        let resp = self.request("STATS", "").await?;
+        Ok(serde_json::from_str(&resp)?)
+    }
+
+    /// Perform the PUBLISH command to send a message to whoever is
+    /// subscribed to a channel, returning how many subscribers got it.
+    ///
+    /// NOTE: Messages spanning lines are sent in frames, so this switches the
+    /// connection to the framed protocol if it has to, and the whitespace
+    /// around a message is trimmed off, like around any other command.
+    pub async fn publish(&mut self, channel: &str, message: &str) -> Result<usize, Error> {
+        if message.contains('\n') && !self.framed {
+            self.use_framing().await?;
+        }
+
+        let resp = self
+            .request(&format!("PUBLISH {} {}", quote(channel), message), "")
+            .await?;
+        resp.trim()
+            .parse()
+            .map_err(|_| Error::Unknown(resp.clone()))
+    }
+
+    /// Perform the SUBSCRIBE command to start receiving every message
+    /// published to any of `channels`, turning the connection into
+    /// a `Subscription`, since it can't send other commands anymore.
+    pub async fn subscribe(self, channels: &[&str]) -> Result<Subscription, Error> {
+        Subscription::start(self.reader, self.writer, self.framed, "SUBSCRIBE", channels).await
+    }
+
+    /// Perform the PSUBSCRIBE command to start receiving every message
+    /// published to a channel matching any of the glob `patterns`,
+    /// like `news.*`, turning the connection into a `Subscription`.
+    pub async fn psubscribe(self, patterns: &[&str]) -> Result<Subscription, Error> {
+        Subscription::start(
+            self.reader,
+            self.writer,
+            self.framed,
+            "PSUBSCRIBE",
+            patterns,
+        )
+        .await
+    }
+}
+
+/// Quote an argument if the server wouldn't parse it back as a single token as
+/// is, like a key holding whitespace, the way the server quotes them itself.
+pub(crate) fn quote(arg: &str) -> Cow<'_, str> {
+    let plain =
+        !arg.is_empty() && !arg.starts_with(['"', '\'']) && !arg.contains(char::is_whitespace);
+    if plain {
+        return Cow::Borrowed(arg);
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// Write a command as a line or as a frame, without flushing it.
+pub(crate) async fn write_request(
+    writer: &mut Writer,
+    framed: bool,
+    command: &[u8],
+) -> Result<(), Error> {
+    if framed {
+        let len =
+            u32::try_from(command.len()).map_err(|_| Error::Unknown("Command Too Large".into()))?;
+        writer.write_all(&len.to_be_bytes()).await?;
+        writer.write_all(command).await?;
+    } else {
+        writer.write_all(command).await?;
+        writer.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Read the next response as a line or as a frame.
+pub(crate) async fn read_response(reader: &mut Reader, framed: bool) -> Result<Vec<u8>, Error> {
+    if framed {
+        let len = match reader.read_u32().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::Unknown("Disconnected".into()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut resp = vec![0; len as usize];
+        reader.read_exact(&mut resp).await?;
+        Ok(resp)
+    } else {
+        let mut resp = Vec::new();
+        if reader.read_until(b'\n', &mut resp).await? == 0 {
+            return Err(Error::Unknown("Disconnected".into()));
+        }
+        Ok(resp)
+    }
+}
+
+/// Whether an error means the server closed the connection, rather than replied.
+fn is_disconnect(e: &Error) -> bool {
+    match e {
+        Error::Unknown(e) => e == "Disconnected",
+        Error::IO(e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe
+        ),
+        _ => false,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use serde_json::json;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::AsyncWriteExt;
     use tokio::net::TcpListener;
 
     async fn mock_server() -> String {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
         tokio::spawn(async move {
-            if let Ok((mut socket, _)) = listener.accept().await {
-                let mut buffer = [0; 512];
-                let n = socket.read(&mut buffer).await.unwrap();
-                let command = String::from_utf8_lossy(&buffer[..n]);
-                let response = if command.starts_with("SET") {
-                    "OK\n"
-                } else if command.starts_with("GET") {
-                    r#""value""#
-                } else if command.starts_with("REMOVE") {
-                    "OK\n"
-                } else {
-                    "ERROR\n"
-                };
-                socket.write_all(response.as_bytes()).await.unwrap();
+            if let Ok((socket, _)) = listener.accept().await {
+                mock_reply(socket).await;
             }
         });
         addr.to_string()
     }
 
+    async fn mock_reply<S: AsyncRead + AsyncWrite + Unpin>(socket: S) {
+        let mut socket = BufReader::new(socket);
+        let mut command = String::new();
+        while socket.read_line(&mut command).await.unwrap() > 0 {
+            let response = if command.starts_with("HELLO") {
+                concat!(
+                    r#"{"server":"fabric-cache","version":"0.1.3","protocol":1,"id":4,"#,
+                    r#""features":["framing"],"commands":["GET","SET"]}"#,
+                    "\n"
+                )
+            } else if command.starts_with("SET") {
+                "OK\n"
+            } else if command.starts_with("GET") {
+                "\"value\"\n"
+            } else if command.starts_with("REMOVE") {
+                "OK\n"
+            } else if command.starts_with("DUMP") {
+                "ERR KEY_NOT_FOUND Key: \"test_key\" Not Found.\n"
+            } else {
+                "ERROR\n"
+            };
+            socket.write_all(response.as_bytes()).await.unwrap();
+            command.clear();
+        }
+    }
+
     #[tokio::test]
     async fn test_set_command() {
         let addr = mock_server().await;
@@ -152,4 +606,124 @@ mod tests {
 
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_error_replies() {
+        let addr = mock_server().await;
+        let mut client = FabricClient::connect(&addr).await.unwrap();
+
+        let result = client.dump("test_key").await;
+        assert!(matches!(result, Err(Error::KeyNotFound(key)) if key == "test_key"));
+    }
+
+    #[tokio::test]
+    async fn test_hello_handshake() {
+        let addr = mock_server().await;
+        let client = FabricClient::connect(&addr).await.unwrap();
+
+        let capabilities = client.capabilities();
+        assert_eq!(capabilities.version, "0.1.3");
+        assert_eq!(capabilities.connection_id, 4);
+        assert!(capabilities.supports("framing"));
+        assert!(!capabilities.supports("tls"));
+    }
+
+    #[test]
+    fn test_parse_error_replies() {
+        assert!(Error::from_reply("OK\n", "a").is_none());
+        assert!(Error::from_reply("\"ERR not an error\"\n", "a").is_none());
+        assert!(matches!(
+            Error::from_reply("ERR INVALID_KEY_PATH \"a.b\" Is Not A Valid Key Path.\n", "a.b"),
+            Some(Error::InvalidKeyPath(key)) if key == "a.b"
+        ));
+        assert!(matches!(
+            Error::from_reply("ERR BAD_DUMP Bad Dump: Too Short.", "a"),
+            Some(Error::Server { code, message }) if code == "BAD_DUMP" && message == "Bad Dump: Too Short."
+        ));
+        assert!(matches!(
+            Error::from_reply("ERR Overloaded\n", "a"),
+            Some(Error::Server { code, message }) if code.is_empty() && message == "Overloaded"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_unix() {
+        let path = std::env::temp_dir().join(format!("fabric-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            if let Ok((socket, _)) = listener.accept().await {
+                mock_reply(socket).await;
+            }
+        });
+
+        let mut client = FabricClient::connect_unix(&path).await.unwrap();
+        assert!(client.set("test_key", &json!(1)).await.is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_matches_responses_by_request_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut hello = String::new();
+            socket.read_line(&mut hello).await.unwrap();
+            socket
+                .write_all(b"ERR UNSUPPORTED_COMMAND \"HELLO\" Is Not A Supported Command.\n")
+                .await
+                .unwrap();
+
+            let mut requests = String::new();
+            for _ in 0..3 {
+                socket.read_line(&mut requests).await.unwrap();
+            }
+            assert_eq!(requests, "#1 SET a 1\n#2 GET a\n#3 DUMP b\n");
+
+            // Reply out of order, like a multiplexing server could
+            socket
+                .write_all(b"#3 ERR KEY_NOT_FOUND Key: \"b\" Not Found.\n#2 1\n#1 OK\n")
+                .await
+                .unwrap();
+        });
+
+        let mut client = FabricClient::connect(&addr).await.unwrap();
+        assert_eq!(client.capabilities(), &Capabilities::default());
+        client.use_request_ids();
+
+        let mut pipeline = Pipeline::new();
+        pipeline.set("a", &1).unwrap().get("a").dump("b");
+        let results = client.execute(&pipeline).await.unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &Value::Null);
+        assert_eq!(results[1].as_ref().unwrap(), &json!(1));
+        assert!(matches!(&results[2], Err(Error::KeyNotFound(key)) if key == "b"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_to_servers_that_hang_up_on_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            // Like the oldest servers, hang up on the unknown HELLO
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut hello = String::new();
+            socket.read_line(&mut hello).await.unwrap();
+            assert_eq!(hello, "HELLO\n");
+            drop(socket);
+
+            let (socket, _) = listener.accept().await.unwrap();
+            mock_reply(socket).await;
+        });
+
+        let mut client = FabricClient::connect(&addr).await.unwrap();
+        assert_eq!(client.capabilities(), &Capabilities::default());
+        assert!(client.set("test_key", &json!(1)).await.is_ok());
+    }
 }
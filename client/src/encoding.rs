@@ -0,0 +1,112 @@
+use crate::Error;
+use serde::Deserialize;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+/// How values are sent to and from your fabric server, picked with
+/// `FabricClient::use_encoding` right after connecting.
+///
+/// NOTE: The binary encodings are more compact than JSON, especially
+/// for numbers, but need the framed protocol (version 2).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    /// Values are sent as JSON text
+    #[default]
+    Json,
+    /// Values are sent as MessagePack
+    MessagePack,
+    /// Values are sent as CBOR (RFC 8949)
+    Cbor,
+}
+impl Encoding {
+    /// The name of the encoding, as the server knows it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    /// A command carrying a value, where a binary encoding puts
+    /// the encoded value after the command, on a new line.
+    pub(crate) fn request<T: Serialize>(&self, command: &str, value: &T) -> Result<Vec<u8>, Error> {
+        if *self == Encoding::Json {
+            let serialized_data = serde_json::to_string(value).map_err(Error::BadDataStructure)?;
+            return Ok(format!("{} {}", command, serialized_data).into_bytes());
+        }
+
+        // Values go through the JSON model first, so they're stored the same
+        // whatever the encoding, like with map keys that aren't strings
+        let value = serde_json::to_value(value).map_err(Error::BadDataStructure)?;
+        let mut request = format!("{}\n", command).into_bytes();
+        match self {
+            Encoding::MessagePack => {
+                rmp_serde::encode::write(&mut request, &value).map_err(|e| bad(&e.to_string()))?
+            }
+            _ => ciborium::into_writer(&value, &mut request).map_err(|e| bad(&e.to_string()))?,
+        }
+        Ok(request)
+    }
+
+    /// Parse the reply carrying a value, which is either JSON, or `VALUE`
+    /// on a line of its own followed by the value in a binary encoding.
+    pub(crate) fn value<T: DeserializeOwned>(&self, resp: &[u8]) -> Result<T, Error> {
+        let Some(encoded) = resp.strip_prefix(b"VALUE\n") else {
+            return Ok(serde_json::from_slice(resp)?);
+        };
+
+        let mut rest = encoded;
+        let value = match self {
+            Encoding::MessagePack => {
+                Value::deserialize(&mut rmp_serde::Deserializer::new(&mut rest))
+                    .map_err(|e| bad(&e.to_string()))?
+            }
+            Encoding::Cbor => {
+                ciborium::from_reader::<Value, _>(&mut rest).map_err(|e| bad(&e.to_string()))?
+            }
+            Encoding::Json => return Err(bad("Binary Value Without A Binary Encoding")),
+        };
+        if !rest.is_empty() {
+            return Err(bad("Bytes Left Over After The Value"));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// A value that couldn't be encoded or decoded.
+fn bad(reason: &str) -> Error {
+    Error::BadDataStructure(serde::de::Error::custom(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_encodings_round_trip() {
+        let value = json!({
+            "scores": [0, 255, 65535, u64::MAX, -1, -200, -70000, i64::MIN, 0.25],
+            "name": "y".repeat(70000),
+            "flags": [true, false, null],
+        });
+        for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+            let request = encoding.request("SET a", &value).unwrap();
+            let encoded = request.strip_prefix(b"SET a\n").unwrap();
+
+            let resp = [&b"VALUE\n"[..], encoded].concat();
+            let decoded: Value = encoding.value(&resp).unwrap();
+            assert_eq!(decoded, value, "{encoding:?}");
+
+            // Errors and JSON replies still parse as before
+            let decoded: Value = encoding.value(b"[1]\n").unwrap();
+            assert_eq!(decoded, json!([1]));
+        }
+
+        assert_eq!(
+            Encoding::Json.request("SET a", &json!([1])).unwrap(),
+            b"SET a [1]"
+        );
+    }
+}
@@ -5,6 +5,16 @@ pub enum Error {
     IO(std::io::Error),
     BadDataStructure(serde_json::Error),
     UnsupportedCommand(String),
+    InvalidKeyPath(String),
+    KeyExists(String),
+    /// The key holds a blob where a JSON value was expected, or the other way around
+    WrongType(String),
+    Tls(String),
+    /// Any other `ERR <code> <message>` reply from the server
+    Server {
+        code: String,
+        message: String,
+    },
     Unknown(String),
 }
 impl std::error::Error for Error {}
@@ -17,10 +27,49 @@ impl std::fmt::Display for Error {
             Error::IO(e) => write!(f, "IO Error:\n {}", e),
             Error::BadDataStructure(e) => write!(f, "Bad Data Structure: Error:\n {}", e),
             Error::UnsupportedCommand(cmd) => write!(f, "\"{}\" Is Not A Supported Command.", cmd),
+            Error::InvalidKeyPath(key_path) => {
+                write!(f, "\"{}\" Is Not A Valid Key Path.", key_path)
+            }
+            Error::KeyExists(key) => write!(f, "Key: \"{}\" Already Exists.", key),
+            Error::WrongType(key) => {
+                write!(f, "Key: \"{}\" Holds A Different Type Of Value.", key)
+            }
+            Error::Tls(reason) => write!(f, "TLS Error: {}", reason),
+            Error::Server { code, message } => write!(f, "Server Error {}: {}", code, message),
             Error::Unknown(err_msg) => write!(f, "Unknown Error:\n {}", err_msg),
         }
     }
 }
+impl Error {
+    /// Parse an `ERR <code> <message>` reply into the matching
+    /// error, where `key` is the key the command was about.
+    pub(crate) fn from_reply(reply: &str, key: &str) -> Option<Error> {
+        let error = reply.trim_end().strip_prefix("ERR ")?;
+        // A reply without a code is still worth keeping as the message
+        let (code, message) = error.split_once(' ').unwrap_or(("", error));
+
+        Some(match code {
+            "KEY_NOT_FOUND" => Error::KeyNotFound(key.into()),
+            "INVALID_KEY_PATH" => Error::InvalidKeyPath(key.into()),
+            "KEY_EXISTS" => Error::KeyExists(key.into()),
+            "WRONG_TYPE" => Error::WrongType(key.into()),
+            "UNSUPPORTED_COMMAND" => Error::UnsupportedCommand(message.into()),
+            _ => Error::Server {
+                code: code.into(),
+                message: message.into(),
+            },
+        })
+    }
+
+    /// Parse an `ERR <code> <message>` reply that may not be UTF-8, since
+    /// replies carrying values in a binary encoding aren't.
+    pub(crate) fn from_bytes(reply: &[u8], key: &str) -> Option<Error> {
+        if !reply.starts_with(b"ERR ") {
+            return None;
+        }
+        Error::from_reply(&String::from_utf8_lossy(reply), key)
+    }
+}
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::IO(err)
@@ -24,7 +24,7 @@
 //! For more thorough information, read the [docs](https://docs.rs/fabric-cache-client/latest/fabric_cache_client/).
 //!
 //! Simple example for a game leaderboard cache:
-//! ```rust
+//! ```rust,no_run
 //! use fabric_cache_client::FabricClient;
 //! use serde::{Deserialize, Serialize};
 //!
@@ -42,8 +42,9 @@
 //! }
 //!
 //! #[tokio::main]
-//! async fn main() -> Result<(), anyhow::Error> {
-//!     // Create a connection with the fabric-cache server
+//! async fn main() -> Result<(), fabric_cache_client::Error> {
+//!     // Create a connection with the fabric-cache server, or with one running
+//!     // as a sidecar with `FabricClient::connect_unix("/run/fabric.sock")`
 //!     let mut cache = FabricClient::connect("127.0.0.1:8731").await.unwrap();
 //!
 //!     // Some dummy data for the example
@@ -98,8 +99,18 @@
 //! }
 //! ```
 
+mod capabilities;
 mod client;
+mod encoding;
 mod error;
+mod pipeline;
+mod subscription;
+mod tls;
 
+pub use capabilities::Capabilities;
 pub use client::FabricClient;
+pub use encoding::Encoding;
 pub use error::Error;
+pub use pipeline::Pipeline;
+pub use subscription::{Message, Subscription};
+pub use tls::TlsConfig;
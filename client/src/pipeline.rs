@@ -0,0 +1,113 @@
+use crate::{client::quote, Encoding, Error};
+use serde::Serialize;
+use serde_json::Value;
+
+/// A batch of commands to send to your fabric server all at
+/// once with `FabricClient::execute`, before reading any of
+/// their responses.
+///
+/// NOTE: Every command is answered with a `serde_json::Value`, which is
+/// the value for a GET, the blob for a DUMP and `Value::Null` otherwise.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    pub(crate) commands: Vec<Queued>,
+}
+impl Pipeline {
+    /// Initialize an empty pipeline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a SET command, to insert or update the value of a key.
+    ///
+    /// NOTE: The value is encoded once the pipeline is executed, in
+    /// whichever encoding the client is using by then.
+    pub fn set<T: Serialize>(&mut self, key: &str, value: &T) -> Result<&mut Self, Error> {
+        let value = serde_json::to_value(value).map_err(Error::BadDataStructure)?;
+        self.commands.push(Queued {
+            command: format!("SET {}", quote(key)),
+            value: Some(value),
+            key: key.into(),
+            reply: Reply::Ok,
+        });
+        Ok(self)
+    }
+
+    /// Queue a GET command, to grab the current value of a key.
+    pub fn get(&mut self, key: &str) -> &mut Self {
+        self.queue(format!("GET {}", quote(key)), key, Reply::Value)
+    }
+
+    /// Queue a REMOVE command, to remove a key/value pair from cache.
+    pub fn remove(&mut self, key: &str) -> &mut Self {
+        self.queue(format!("REMOVE {}", quote(key)), key, Reply::Ok)
+    }
+
+    /// Queue a DUMP command, to serialize the value of a key into a portable blob.
+    pub fn dump(&mut self, key: &str) -> &mut Self {
+        self.queue(format!("DUMP {}", quote(key)), key, Reply::Blob)
+    }
+
+    /// Queue a RESTORE command, to set the value of a key from a blob.
+    pub fn restore(&mut self, key: &str, blob: &str, replace: bool) -> &mut Self {
+        let command = if replace {
+            format!("RESTORE {} {} REPLACE", quote(key), blob)
+        } else {
+            format!("RESTORE {} {}", quote(key), blob)
+        };
+        self.queue(command, key, Reply::Ok)
+    }
+
+    /// The number of commands queued.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Whether no commands are queued.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    fn queue(&mut self, command: String, key: &str, reply: Reply) -> &mut Self {
+        self.commands.push(Queued {
+            command,
+            value: None,
+            key: key.into(),
+            reply,
+        });
+        self
+    }
+}
+
+/// A command waiting in a pipeline.
+#[derive(Debug)]
+pub(crate) struct Queued {
+    pub(crate) command: String,
+    /// The value the command carries, sent after it in the client's encoding
+    pub(crate) value: Option<Value>,
+    /// The key the command is about, for errors
+    pub(crate) key: String,
+    pub(crate) reply: Reply,
+}
+
+/// The kind of reply a command gets, when it doesn't fail.
+#[derive(Debug)]
+pub(crate) enum Reply {
+    /// `OK`
+    Ok,
+    /// A value, as JSON or in the client's encoding
+    Value,
+    /// A DUMP blob
+    Blob,
+}
+impl Reply {
+    /// Parse a successful response.
+    pub(crate) fn parse(&self, resp: Vec<u8>, encoding: Encoding) -> Result<Value, Error> {
+        match self {
+            Reply::Ok if resp.trim_ascii() == b"OK" => Ok(Value::Null),
+            Reply::Ok => Err(Error::Unknown(String::from_utf8_lossy(&resp).into())),
+            Reply::Value => encoding.value(&resp),
+            Reply::Blob => Ok(Value::String(String::from_utf8_lossy(&resp).trim().into())),
+        }
+    }
+}
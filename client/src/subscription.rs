@@ -0,0 +1,122 @@
+use crate::{
+    client::{quote, read_response, write_request, Reader, Writer},
+    Error,
+};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+/// A message published to a channel the subscription listens to.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Message {
+    pub channel: String,
+    /// The pattern the channel matched, if it was subscribed to by pattern
+    #[serde(default)]
+    pub pattern: Option<String>,
+    pub message: String,
+}
+
+/// What the server pushes to a subscribed connection.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Push {
+    Message(Message),
+    PMessage(Message),
+    /// Acknowledging a change to the subscription, or a ping
+    #[serde(other)]
+    Ack,
+}
+
+/// A connection subscribed to channels, which receives every message
+/// published to them, created by `FabricClient::subscribe` or `psubscribe`.
+///
+/// NOTE: Messages are received one at a time with `next`, like from a `Stream`,
+/// so the subscription can be turned into one with `futures::stream::unfold`.
+pub struct Subscription {
+    reader: Reader,
+    writer: Writer,
+    framed: bool,
+}
+impl Subscription {
+    /// Subscribe a connection with `verb` to `names`, waiting
+    /// until the server acknowledged every one of them.
+    pub(crate) async fn start(
+        reader: Reader,
+        writer: Writer,
+        framed: bool,
+        verb: &str,
+        names: &[&str],
+    ) -> Result<Subscription, Error> {
+        if names.is_empty() {
+            return Err(Error::Unknown(format!("{verb} Needs A Channel")));
+        }
+        let mut subscription = Subscription {
+            reader,
+            writer,
+            framed,
+        };
+        subscription.send(verb, names).await?;
+        for _ in names {
+            let resp = read_response(&mut subscription.reader, framed).await?;
+            if let Some(e) = Error::from_bytes(&resp, "") {
+                return Err(e);
+            }
+        }
+        Ok(subscription)
+    }
+
+    /// Wait for the next message published to what's subscribed to,
+    /// returning `None` once the server closed the connection.
+    pub async fn next(&mut self) -> Option<Result<Message, Error>> {
+        loop {
+            let resp = match read_response(&mut self.reader, self.framed).await {
+                Ok(resp) => resp,
+                Err(Error::Unknown(e)) if e == "Disconnected" => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some(e) = Error::from_bytes(&resp, "") {
+                return Some(Err(e));
+            }
+            match serde_json::from_slice(&resp) {
+                Ok(Push::Message(message) | Push::PMessage(message)) => return Some(Ok(message)),
+                Ok(Push::Ack) => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+
+    /// Subscribe to more channels as well.
+    pub async fn subscribe(&mut self, channels: &[&str]) -> Result<(), Error> {
+        self.send("SUBSCRIBE", channels).await
+    }
+
+    /// Subscribe to every channel matching any of the glob `patterns` as well.
+    pub async fn psubscribe(&mut self, patterns: &[&str]) -> Result<(), Error> {
+        self.send("PSUBSCRIBE", patterns).await
+    }
+
+    /// Unsubscribe from some channels, or every one of them when none are given.
+    ///
+    /// NOTE: Once unsubscribed from every channel and pattern, the
+    /// server stops pushing messages, and the subscription is done.
+    pub async fn unsubscribe(&mut self, channels: &[&str]) -> Result<(), Error> {
+        self.send("UNSUBSCRIBE", channels).await
+    }
+
+    /// Unsubscribe from some patterns, or every one of them when none are given.
+    pub async fn punsubscribe(&mut self, patterns: &[&str]) -> Result<(), Error> {
+        self.send("PUNSUBSCRIBE", patterns).await
+    }
+
+    /// Send a request changing the subscription, whose acknowledgements
+    /// are skipped over among the messages, rather than waited for.
+    async fn send(&mut self, verb: &str, names: &[&str]) -> Result<(), Error> {
+        let command = [verb.into()]
+            .into_iter()
+            .chain(names.iter().map(|name| quote(name)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write_request(&mut self.writer, self.framed, command.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::Error;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore,
+};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::TlsConnector;
+
+/// TLS settings for connecting to your fabric server
+/// with `FabricClient::connect_tls`.
+///
+/// NOTE: By default the server's certificate must be signed by one of
+/// the public web CAs, use `with_root_ca` to trust your own CA instead.
+pub struct TlsConfig {
+    roots: RootCertStore,
+    client_cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            roots: RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+            client_cert: None,
+        }
+    }
+}
+impl TlsConfig {
+    /// Initialize TLS settings trusting the public web CAs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust only the CA certificates in a PEM file, like
+    /// the private CA your server's certificate is signed by.
+    pub fn with_root_ca<P: AsRef<Path>>(mut self, ca_file: P) -> Result<Self, Error> {
+        self.roots = RootCertStore::empty();
+        for cert in load_certs(ca_file.as_ref())? {
+            self.roots
+                .add(cert)
+                .map_err(|e| Error::Tls(e.to_string()))?;
+        }
+        Ok(self)
+    }
+
+    /// Present the certificate chain and private key in PEM files to
+    /// servers that require clients to authenticate with a certificate.
+    pub fn with_client_cert<P: AsRef<Path>>(
+        mut self,
+        cert_file: P,
+        key_file: P,
+    ) -> Result<Self, Error> {
+        let certs = load_certs(cert_file.as_ref())?;
+        let key = PrivateKeyDer::from_pem_file(key_file.as_ref())
+            .map_err(|e| Error::Tls(format!("Bad Private Key: {e}")))?;
+        self.client_cert = Some((certs, key));
+        Ok(self)
+    }
+
+    /// Build the connector to establish TLS connections with.
+    pub(crate) fn connector(&self) -> Result<TlsConnector, Error> {
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?
+            .with_root_certificates(self.roots.clone());
+
+        let config = match &self.client_cert {
+            Some((certs, key)) => builder
+                .with_client_auth_cert(certs.clone(), key.clone_key())
+                .map_err(|e| Error::Tls(e.to_string()))?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+
+/// Load every certificate in a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::Tls(format!("Bad Certificates {}: {e}", path.display())))?;
+
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("No Certificates In {}", path.display())));
+    }
+    Ok(certs)
+}
@@ -0,0 +1,905 @@
+use crate::{
+    command::Command,
+    fabric::{Fabric, Limits},
+    integrity::{self, Corruption},
+    storage::StorageCodec,
+    tokens, Error, ThreadSafeFabric,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// How often the append-only log is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Flush after every record, slowest but nothing is ever lost
+    Always,
+    /// Flush once a second in the background, at most a second is lost
+    EverySec,
+    /// Never flush explicitly and leave it up to the operating system
+    No,
+}
+impl std::str::FromStr for FsyncPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(Error::InvalidConfig(format!(
+                "\"{s}\" Is Not A Valid Fsync Policy (always, everysec, no)."
+            ))),
+        }
+    }
+}
+impl std::fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::EverySec => write!(f, "everysec"),
+            FsyncPolicy::No => write!(f, "no"),
+        }
+    }
+}
+
+/// What to do on startup when the append-only log is corrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Refuse to start until the log is repaired with `fabric-check`
+    Refuse,
+    /// Truncate the log to the last valid record and start anyway
+    Repair,
+}
+impl std::str::FromStr for CorruptionPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "refuse" => Ok(CorruptionPolicy::Refuse),
+            "repair" => Ok(CorruptionPolicy::Repair),
+            _ => Err(Error::InvalidConfig(format!(
+                "\"{s}\" Is Not A Valid Corruption Policy (refuse, repair)."
+            ))),
+        }
+    }
+}
+impl std::fmt::Display for CorruptionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CorruptionPolicy::Refuse => write!(f, "refuse"),
+            CorruptionPolicy::Repair => write!(f, "repair"),
+        }
+    }
+}
+
+/// An append-only log of every mutating command, one checksummed record per line.
+///
+/// NOTE: Records are appended while the caller holds the write lock on
+/// `Fabric`, so the log order always matches the order commands were applied.
+pub struct AppendOnlyLog {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    /// How records are compressed and encrypted on disk
+    codec: StorageCodec,
+    /// Whether records were appended since the last flush to disk
+    dirty: AtomicBool,
+    /// The current size of the log in bytes
+    size: u64,
+    /// The size of the log in bytes right after it was last rewritten
+    base_size: u64,
+    /// Records appended while a rewrite is in progress, which still
+    /// need to be added to the end of the rewritten log
+    rewrite_buffer: Option<String>,
+}
+impl AppendOnlyLog {
+    /// Open the append-only log at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path, policy: FsyncPolicy, codec: StorageCodec) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            policy,
+            codec,
+            dirty: AtomicBool::new(false),
+            size,
+            base_size: size,
+            rewrite_buffer: None,
+        })
+    }
+
+    /// Append a command to the end of the log.
+    pub fn append(&mut self, record: &str) -> Result<(), Error> {
+        let line = integrity::encode_record(&self.codec.encode(record)?);
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        if self.policy == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        } else {
+            self.dirty.store(true, Ordering::Release);
+        }
+
+        if let Some(rewrite_buffer) = self.rewrite_buffer.as_mut() {
+            rewrite_buffer.push_str(&line);
+        }
+
+        Ok(())
+    }
+
+    /// A handle to flush the log with if records were appended since
+    /// the last flush, so the flush can happen without holding a lock.
+    fn unsynced_file(&self) -> Result<Option<File>, Error> {
+        if self.dirty.swap(false, Ordering::AcqRel) {
+            Ok(Some(self.file.try_clone()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Flush every record appended so far to disk, whatever the fsync policy.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.file.sync_all()?;
+        self.dirty.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether a background rewrite of the log is in progress.
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    /// Whether the log has grown enough since it was last rewritten
+    /// that it should be rewritten automatically.
+    ///
+    /// NOTE: A `percentage` of 0 disables automatic rewrites.
+    pub fn should_rewrite(&self, percentage: u64, min_size: u64) -> bool {
+        if percentage == 0 || self.is_rewriting() || self.size < min_size {
+            return false;
+        }
+
+        let growth = self.size.saturating_sub(self.base_size);
+        growth.saturating_mul(100) >= self.base_size.max(1).saturating_mul(percentage)
+    }
+
+    /// Where the rewritten log is written before it replaces the current one.
+    fn rewrite_path(&self) -> PathBuf {
+        rewrite_path(&self.path)
+    }
+
+    /// Add the records buffered during the rewrite to the end of the
+    /// rewritten log, then atomically swap it in for the current log.
+    fn finish_rewrite(&mut self, rewrite_path: &Path) -> Result<(), Error> {
+        let rewrite_buffer = self.rewrite_buffer.take().unwrap_or_default();
+
+        let mut file = OpenOptions::new().append(true).open(rewrite_path)?;
+        file.write_all(rewrite_buffer.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(rewrite_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        self.base_size = self.size;
+        self.dirty.store(false, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Give up on an in-progress rewrite, keeping the current log.
+    fn abort_rewrite(&mut self, rewrite_path: &Path) {
+        self.rewrite_buffer = None;
+        let _ = std::fs::remove_file(rewrite_path);
+    }
+}
+
+/// Replay the append-only log at `path` into `fabric`, returning
+/// the number of records that were replayed.
+///
+/// NOTE: A final record without a trailing newline was cut off by a
+/// crash mid-write, so it is dropped and the file truncated to the last
+/// complete record instead of refusing to start. Any other record failing
+/// its checksum is handled according to `on_corruption`.
+///
+/// NOTE: A record whose checksum matches was written as it is, so when it
+/// can't be decoded or applied, replay fails with where it is and leaves the
+/// log as it is, whatever `on_corruption` says, rather than truncating it and
+/// every valid record after it.
+///
+/// NOTE: Records are decoded based on how each one says it was encoded, so
+/// the log loads no matter which compression it was written with.
+pub async fn replay(
+    path: &Path,
+    fabric: &ThreadSafeFabric,
+    codec: &StorageCodec,
+    on_corruption: CorruptionPolicy,
+) -> Result<usize, Error> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let scan = integrity::scan(&contents);
+    if let Some(corruption) = scan.corruption.as_ref().filter(|c| c.intact) {
+        return Err(unapplicable(path, corruption));
+    }
+
+    let mut fabric = fabric.write().await;
+    let mut replayed = 0;
+    for record in &scan.records {
+        let applied = codec
+            .decode(record.payload)
+            .and_then(|payload| apply_record(&mut fabric, &payload));
+        if let Err(e) = applied {
+            // A record that passed its checksum but can't be decrypted means the
+            // key is wrong rather than the log corrupted, so never discard it
+            if let Error::Encryption(_) = e {
+                return Err(e);
+            }
+            let corruption = Corruption {
+                record: replayed + 1,
+                offset: record.start,
+                reason: e.to_string(),
+                intact: true,
+            };
+            return Err(unapplicable(path, &corruption));
+        }
+        replayed += 1;
+    }
+
+    let valid_len = scan.valid_len();
+    if let Some(corruption) = scan.corruption {
+        match on_corruption {
+            CorruptionPolicy::Refuse => {
+                return Err(Error::CorruptLog(format!(
+                    "{corruption} (Run `fabric-check --fix {}` To Truncate It.)",
+                    path.display()
+                )));
+            }
+            CorruptionPolicy::Repair => eprintln!(
+                "Append-only log {:?} is corrupted at {}, discarding the last {} bytes.",
+                path,
+                corruption,
+                contents.len() as u64 - valid_len
+            ),
+        }
+    } else if scan.truncated_tail > 0 {
+        eprintln!(
+            "Append-only log {:?} ends with a truncated record ({} bytes), discarding it.",
+            path, scan.truncated_tail
+        );
+    }
+
+    if valid_len < contents.len() as u64 {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_len)?;
+    }
+
+    Ok(replayed)
+}
+
+/// The error for an intact record in the log at `path` that can't be replayed.
+fn unapplicable(path: &Path, corruption: &Corruption) -> Error {
+    Error::CorruptLog(format!(
+        "{corruption} (The Record Is Intact, So {} Was Left As Is.)",
+        path.display()
+    ))
+}
+
+/// Apply a single verified record from the log to `fabric`.
+///
+/// NOTE: Records were checked against the limits in place when they were
+/// logged, which may not be the ones now, so they're applied without any.
+fn apply_record(fabric: &mut Fabric, payload: &str) -> Result<(), Error> {
+    let limits = std::mem::replace(&mut fabric.limits, Limits::unlimited());
+    let applied = match Command::parse(payload) {
+        Ok(Command::Set { key, value }) => fabric.set(key.split('.').collect(), &value),
+        Ok(Command::Remove { key }) => fabric.remove(key.split('.').collect()),
+        Ok(Command::SetBlob { key, blob }) => fabric.set_blob(&key, blob),
+        _ => Err(Error::CorruptLog(format!(
+            "\"{payload}\" Is Not A Valid Record."
+        ))),
+    };
+    fabric.limits = limits;
+    applied
+}
+
+/// Where the log at `path` is rewritten to before it replaces it.
+fn rewrite_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".rewrite");
+    path.with_file_name(file_name)
+}
+
+/// Rewrite the append-only log at `path` right away, before it's opened for
+/// appending, with every record encoded by `codec`, like to encrypt a log
+/// written before there was an encryption key.
+pub fn rewrite_now(path: &Path, fabric: &Fabric, codec: &StorageCodec) -> Result<(), Error> {
+    let rewrite_path = rewrite_path(path);
+    write_snapshot(&rewrite_path, &fabric.cache, &fabric.blobs, codec)?;
+    std::fs::rename(&rewrite_path, path)?;
+    Ok(())
+}
+
+/// Start rewriting the append-only log in the background, producing
+/// the minimal log needed to rebuild the current state of `fabric`.
+///
+/// NOTE: New commands keep being appended to the current log while the
+/// rewrite runs, and are also buffered so they can be carried over to
+/// the rewritten log right before it replaces the current one.
+pub async fn start_rewrite(fabric: &ThreadSafeFabric) -> Result<(), Error> {
+    let (snapshot, rewrite_path, codec) = {
+        let mut fabric = fabric.write().await;
+        let snapshot = (fabric.cache.clone(), fabric.blobs.clone());
+        let aof = fabric.aof.as_mut().ok_or(Error::AppendOnlyDisabled)?;
+        if aof.is_rewriting() {
+            return Err(Error::RewriteInProgress);
+        }
+        aof.rewrite_buffer = Some(String::new());
+
+        (snapshot, aof.rewrite_path(), aof.codec.clone())
+    };
+
+    let fabric = fabric.clone();
+    tokio::spawn(async move {
+        let write_path = rewrite_path.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            let (cache, blobs) = &snapshot;
+            write_snapshot(&write_path, cache, blobs, &codec)
+        })
+        .await
+        .map_err(|e| Error::IO(e.into()))
+        .and_then(|result| result);
+
+        let mut fabric = fabric.write().await;
+        let Some(aof) = fabric.aof.as_mut() else {
+            return;
+        };
+        match written.and_then(|_| aof.finish_rewrite(&rewrite_path)) {
+            Ok(_) => println!("Append-only log rewritten to {} bytes", aof.size),
+            Err(e) => {
+                eprintln!("Error rewriting append-only log: {:?}", e);
+                aof.abort_rewrite(&rewrite_path);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Write the minimal set of records that rebuild `cache` and `blobs` to `path`.
+fn write_snapshot(
+    path: &Path,
+    cache: &HashMap<String, Value>,
+    blobs: &HashMap<String, Vec<u8>>,
+    codec: &StorageCodec,
+) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (key, value) in cache {
+        let record = format!(
+            "SET {} {}",
+            tokens::quote(key),
+            serde_json::to_string(value)?
+        );
+        writer.write_all(integrity::encode_record(&codec.encode(&record)?).as_bytes())?;
+    }
+    for (key, blob) in blobs {
+        let record = format!("SETBLOB {} {}", tokens::quote(key), BASE64.encode(blob));
+        writer.write_all(integrity::encode_record(&codec.encode(&record)?).as_bytes())?;
+    }
+
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Run the append-only log's housekeeping once a second in the background,
+/// flushing it to disk under the `everysec` policy and rewriting it once it
+/// has grown by `rewrite_percentage` percent since it was last rewritten.
+pub fn spawn_cron(fabric: ThreadSafeFabric, rewrite_percentage: u64, rewrite_min_size: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+
+            let (file, should_rewrite) = match fabric.read().await.aof.as_ref() {
+                Some(aof) => (
+                    match aof.policy {
+                        FsyncPolicy::EverySec => aof.unsynced_file(),
+                        _ => Ok(None),
+                    },
+                    aof.should_rewrite(rewrite_percentage, rewrite_min_size),
+                ),
+                None => break,
+            };
+
+            let result = match file {
+                Ok(Some(file)) => tokio::task::spawn_blocking(move || file.sync_data())
+                    .await
+                    .map_err(|e| Error::IO(e.into()))
+                    .and_then(|result| result.map_err(Error::IO)),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Error flushing append-only log: {:?}", e);
+            }
+
+            if should_rewrite {
+                println!("Append-only log has grown enough, starting automatic rewrite");
+                if let Err(e) = start_rewrite(&fabric).await {
+                    eprintln!("Error starting append-only log rewrite: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Compression, EncryptionKey};
+    use integrity::encode_record;
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn temp_log_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("fabric-aof-{}-{}.aof", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn new_fabric() -> ThreadSafeFabric {
+        Arc::new(RwLock::new(Fabric::new()))
+    }
+
+    #[tokio::test]
+    async fn logs_and_replays_mutating_commands() {
+        let path = temp_log_path("replay");
+
+        let fabric = new_fabric();
+        fabric.write().await.aof =
+            Some(AppendOnlyLog::open(&path, FsyncPolicy::Always, StorageCodec::default()).unwrap());
+        for line in [
+            "SET users {\"a\": {\"age\": 21}, \"b\": {\"age\": 28}}",
+            "SET users.a.age 22",
+            "GET users.a",
+            "REMOVE users.b",
+        ] {
+            Command::parse(line).unwrap().handle(&fabric).await.unwrap();
+        }
+
+        let restored = new_fabric();
+        assert_eq!(
+            replay(
+                &path,
+                &restored,
+                &StorageCodec::default(),
+                CorruptionPolicy::Refuse
+            )
+            .await
+            .unwrap(),
+            3
+        );
+        assert_eq!(
+            restored.read().await.get(vec!["users"]).unwrap(),
+            json!({"a": {"age": 22}})
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_records_beyond_the_limits() {
+        let path = temp_log_path("limits");
+
+        let fabric = new_fabric();
+        fabric.write().await.aof =
+            Some(AppendOnlyLog::open(&path, FsyncPolicy::Always, StorageCodec::default()).unwrap());
+        for line in ["SET a.b.c [[1]]", "SET large \"xxxxxxxx\""] {
+            Command::parse(line).unwrap().handle(&fabric).await.unwrap();
+        }
+        rewrite_now(&path, &*fabric.read().await, &StorageCodec::default()).unwrap();
+
+        // Records are applied even if the limits were lowered since
+        let restored = new_fabric();
+        restored.write().await.limits.max_json_depth = 2;
+        restored.write().await.limits.max_value_size = 4;
+        replay(
+            &path,
+            &restored,
+            &StorageCodec::default(),
+            CorruptionPolicy::Refuse,
+        )
+        .await
+        .unwrap();
+        assert_eq!(restored.read().await.cache, fabric.read().await.cache);
+        assert_eq!(restored.read().await.limits.max_value_size, 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_records_of_quoted_keys() {
+        let path = temp_log_path("quoted");
+
+        let fabric = new_fabric();
+        fabric.write().await.aof =
+            Some(AppendOnlyLog::open(&path, FsyncPolicy::Always, StorageCodec::default()).unwrap());
+        for line in [
+            "SET \"my key\" {\"a b\": 1}",
+            "SETBLOB 'my blob' AA==",
+            "SET gone 1",
+            "REMOVE \"gone\"",
+        ] {
+            Command::parse(line).unwrap().handle(&fabric).await.unwrap();
+        }
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let restored = new_fabric();
+        let replayed = replay(
+            &path,
+            &restored,
+            &StorageCodec::default(),
+            CorruptionPolicy::Refuse,
+        )
+        .await
+        .unwrap();
+        assert_eq!(replayed, 4);
+        assert_eq!(
+            restored.read().await.get(vec!["my key"]).unwrap(),
+            json!({"a b": 1})
+        );
+        assert_eq!(restored.read().await.blobs["my blob"], vec![0]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_commands_are_not_logged() {
+        let path = temp_log_path("failed");
+
+        let fabric = new_fabric();
+        fabric.write().await.aof =
+            Some(AppendOnlyLog::open(&path, FsyncPolicy::No, StorageCodec::default()).unwrap());
+        let line = "SET users {not json}";
+        assert!(Command::parse(line).unwrap().handle(&fabric).await.is_err());
+
+        assert!(std::fs::read_to_string(&path).unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn discards_truncated_final_record() {
+        let path = temp_log_path("truncated");
+        let first = encode_record("SET a 1");
+        let second = encode_record("SET b {\"x\": 3}");
+        std::fs::write(&path, format!("{first}{}", &second[..12])).unwrap();
+
+        let fabric = new_fabric();
+        assert_eq!(
+            replay(
+                &path,
+                &fabric,
+                &StorageCodec::default(),
+                CorruptionPolicy::Refuse
+            )
+            .await
+            .unwrap(),
+            1
+        );
+        assert_eq!(fabric.read().await.get(vec!["a"]).unwrap(), json!(1));
+        assert!(fabric.read().await.get(vec!["b"]).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), first);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_corrupt_records() {
+        let path = temp_log_path("corrupt");
+        let contents = [
+            encode_record("SET a 1"),
+            encode_record("SET b 2").replace('2', "3"),
+            encode_record("SET c 3"),
+        ]
+        .concat();
+        std::fs::write(&path, &contents).unwrap();
+
+        let fabric = new_fabric();
+        assert!(matches!(
+            replay(
+                &path,
+                &fabric,
+                &StorageCodec::default(),
+                CorruptionPolicy::Refuse
+            )
+            .await,
+            Err(Error::CorruptLog(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn repairs_corrupt_records() {
+        let path = temp_log_path("repair");
+        let first = encode_record("SET a 1");
+        let contents = [
+            first.clone(),
+            encode_record("SET b 2").replace('2', "3"),
+            encode_record("SET c 3"),
+        ]
+        .concat();
+        std::fs::write(&path, &contents).unwrap();
+
+        let fabric = new_fabric();
+        assert_eq!(
+            replay(
+                &path,
+                &fabric,
+                &StorageCodec::default(),
+                CorruptionPolicy::Repair
+            )
+            .await
+            .unwrap(),
+            1
+        );
+        assert_eq!(fabric.read().await.get(vec!["a"]).unwrap(), json!(1));
+        assert!(fabric.read().await.get(vec!["c"]).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), first);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_truncates_intact_records_it_cant_apply() {
+        let path = temp_log_path("intact");
+        let first = encode_record("SET a 1");
+        let contents = [
+            first.clone(),
+            encode_record("REMOVE a.b"),
+            encode_record("SET c 3"),
+        ]
+        .concat();
+        std::fs::write(&path, &contents).unwrap();
+
+        let replayed = replay(
+            &path,
+            &new_fabric(),
+            &StorageCodec::default(),
+            CorruptionPolicy::Repair,
+        )
+        .await;
+        let Err(Error::CorruptLog(reason)) = replayed else {
+            panic!("Expected the replay to fail, got {replayed:?}");
+        };
+        let at = format!("Record 2 At Byte {}", first.len());
+        assert!(reason.starts_with(&at), "{reason}");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_compressed_and_encrypted_logs() {
+        let path = temp_log_path("encrypted");
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let codec = StorageCodec::new(Compression::Zstd, Some(&key));
+        std::fs::write(&path, encode_record("SET plain 1")).unwrap();
+
+        // A record written before encryption was enabled is rejected, unless migrating
+        assert!(matches!(
+            replay(&path, &new_fabric(), &codec, CorruptionPolicy::Repair).await,
+            Err(Error::Encryption(_))
+        ));
+        let migrated = new_fabric();
+        let migrating = codec.clone().accepting_plaintext();
+        replay(&path, &migrated, &migrating, CorruptionPolicy::Refuse)
+            .await
+            .unwrap();
+        rewrite_now(&path, &*migrated.read().await, &codec).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("plain"));
+
+        let fabric = new_fabric();
+        fabric.write().await.aof =
+            Some(AppendOnlyLog::open(&path, FsyncPolicy::No, codec.clone()).unwrap());
+        let line = "SET strategies {\"b7be9512\": {\"account_number\": \"some_id\"}}";
+        Command::parse(line).unwrap().handle(&fabric).await.unwrap();
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("account_number"));
+
+        let restored = new_fabric();
+        assert_eq!(
+            replay(&path, &restored, &codec, CorruptionPolicy::Repair)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            restored
+                .read()
+                .await
+                .get(vec!["strategies", "b7be9512", "account_number"])
+                .unwrap(),
+            json!("some_id")
+        );
+
+        // Without the key the log is never discarded as corrupted
+        assert!(matches!(
+            replay(
+                &path,
+                &new_fabric(),
+                &StorageCodec::default(),
+                CorruptionPolicy::Repair
+            )
+            .await,
+            Err(Error::Encryption(_))
+        ));
+        assert_eq!(
+            integrity::scan(&std::fs::read(&path).unwrap())
+                .records
+                .len(),
+            2
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_log_replays_nothing() {
+        let path = temp_log_path("missing");
+
+        let fabric = new_fabric();
+        assert_eq!(
+            replay(
+                &path,
+                &fabric,
+                &StorageCodec::default(),
+                CorruptionPolicy::Refuse
+            )
+            .await
+            .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrites_log_while_writes_keep_landing() {
+        let path = temp_log_path("rewrite");
+
+        let fabric = new_fabric();
+        fabric.write().await.aof =
+            Some(AppendOnlyLog::open(&path, FsyncPolicy::No, StorageCodec::default()).unwrap());
+        for score in 0..100 {
+            let line = format!("SET leaderboard {{\"top\": {score}}}");
+            Command::parse(&line)
+                .unwrap()
+                .handle(&fabric)
+                .await
+                .unwrap();
+        }
+
+        start_rewrite(&fabric).await.unwrap();
+        assert!(matches!(
+            start_rewrite(&fabric).await,
+            Err(Error::RewriteInProgress)
+        ));
+        let line = "SET players [\"kinda l33t\"]";
+        Command::parse(line).unwrap().handle(&fabric).await.unwrap();
+
+        while fabric.read().await.aof.as_ref().unwrap().is_rewriting() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+
+        let restored = new_fabric();
+        replay(
+            &path,
+            &restored,
+            &StorageCodec::default(),
+            CorruptionPolicy::Refuse,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            restored.read().await.get(vec!["leaderboard"]).unwrap(),
+            json!({"top": 99})
+        );
+        assert_eq!(
+            restored.read().await.get(vec!["players"]).unwrap(),
+            json!(["kinda l33t"])
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn logs_and_rewrites_blobs() {
+        let path = temp_log_path("blobs");
+
+        let fabric = new_fabric();
+        fabric.write().await.aof =
+            Some(AppendOnlyLog::open(&path, FsyncPolicy::No, StorageCodec::default()).unwrap());
+        let png = Command::SetBlob {
+            key: "img".into(),
+            blob: b"\x89PNG\r\n\x1a\n".to_vec(),
+        };
+        png.handle(&fabric).await.unwrap();
+
+        let restored = new_fabric();
+        let codec = StorageCodec::default();
+        replay(&path, &restored, &codec, CorruptionPolicy::Refuse)
+            .await
+            .unwrap();
+        assert_eq!(
+            restored.read().await.get_blob("img").unwrap(),
+            b"\x89PNG\r\n\x1a\n"
+        );
+
+        // Rewrites keep blobs too
+        start_rewrite(&fabric).await.unwrap();
+        while fabric.read().await.aof.as_ref().unwrap().is_rewriting() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let restored = new_fabric();
+        replay(&path, &restored, &codec, CorruptionPolicy::Refuse)
+            .await
+            .unwrap();
+        assert_eq!(
+            restored.read().await.get_blob("img").unwrap(),
+            b"\x89PNG\r\n\x1a\n"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn triggers_rewrite_once_log_has_grown_enough() {
+        let path = temp_log_path("auto-rewrite");
+        std::fs::write(&path, encode_record("SET a 1")).unwrap();
+
+        let mut aof = AppendOnlyLog::open(&path, FsyncPolicy::No, StorageCodec::default()).unwrap();
+        assert!(!aof.should_rewrite(100, 0));
+        aof.append("SET a 2").unwrap();
+        assert!(aof.should_rewrite(100, 0));
+        assert!(!aof.should_rewrite(100, 1024));
+        assert!(!aof.should_rewrite(0, 0));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_fsync_policies() {
+        assert_eq!(
+            "always".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!(
+            "EverySec".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::EverySec
+        );
+        assert_eq!("no".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::No);
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
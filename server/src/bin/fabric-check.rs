@@ -0,0 +1,93 @@
+//! Verify the integrity of a fabric append-only log, and optionally
+//! truncate it to the last valid record.
+//!
+//! Usage: `fabric-check [--fix] <path>`
+//!
+//! Exits with 0 when the log is valid (or was fixed), 1 when it is
+//! corrupted and 2 when the log couldn't be checked at all.
+//!
+//! NOTE: A record whose checksum matches but isn't a valid record was written
+//! that way, so it's reported but never truncated, since that would throw
+//! away every valid record after it too.
+
+// NOTE: Only the verifying half of these modules is needed here.
+#[allow(dead_code)]
+#[path = "../integrity.rs"]
+mod integrity;
+#[allow(dead_code)]
+#[path = "../tokens.rs"]
+mod tokens;
+
+use std::{fs::OpenOptions, process::ExitCode};
+
+fn main() -> ExitCode {
+    let mut fix = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            "-h" | "--help" => {
+                println!("Usage: fabric-check [--fix] <path>");
+                return ExitCode::SUCCESS;
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                eprintln!("Usage: fabric-check [--fix] <path>");
+                return ExitCode::from(2);
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("Usage: fabric-check [--fix] <path>");
+        return ExitCode::from(2);
+    };
+
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Could not read {path}: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let scan = integrity::scan(&contents);
+    let valid_len = scan.valid_len();
+    let discarded = contents.len() as u64 - valid_len;
+    println!("{} valid records ({} bytes)", scan.records.len(), valid_len);
+
+    match &scan.corruption {
+        Some(corruption) if corruption.intact => {
+            println!("Invalid: {corruption}");
+            println!("The record's checksum matches, so it can't be fixed by truncating it");
+            return ExitCode::from(1);
+        }
+        Some(corruption) => println!("Corrupted: {corruption}"),
+        None if scan.truncated_tail > 0 => {
+            println!("Truncated: the final record is incomplete")
+        }
+        None => {
+            println!("{path} is valid");
+            return ExitCode::SUCCESS;
+        }
+    }
+
+    if !fix {
+        println!("Run with --fix to discard the last {discarded} bytes");
+        return ExitCode::from(1);
+    }
+
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_len(valid_len).and_then(|_| file.sync_all()));
+    match truncated {
+        Ok(_) => {
+            println!("Discarded the last {discarded} bytes, {path} is valid");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Could not truncate {path}: {e}");
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+/// How many changes a subscriber can fall behind on before it misses some.
+const CAPACITY: usize = 1024;
+
+/// A change to a value in cache.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// The value at a key path was set to a new value
+    Set { path: Vec<String>, value: Value },
+    /// The value at a key path was removed
+    Remove { path: Vec<String> },
+}
+impl Change {
+    /// The key path of the value that changed.
+    pub fn path(&self) -> &[String] {
+        match self {
+            Change::Set { path, .. } | Change::Remove { path } => path,
+        }
+    }
+
+    /// The name of the operation that made the change.
+    pub fn operation(&self) -> &'static str {
+        match self {
+            Change::Set { .. } => "set",
+            Change::Remove { .. } => "remove",
+        }
+    }
+
+    /// How the change looks to a subscriber of `subscription`, if it
+    /// changes anything at or under that key path at all.
+    ///
+    /// NOTE: A change under the subscription is seen as is, where one above it
+    /// is narrowed down to the value at the subscription, which is seen as
+    /// removed when the change left nothing there.
+    pub fn seen_from(&self, subscription: &[String]) -> Option<Change> {
+        let path = self.path();
+        if path.starts_with(subscription) {
+            return Some(self.clone());
+        }
+        if !subscription.starts_with(path) {
+            return None;
+        }
+
+        let removed = Change::Remove {
+            path: subscription.to_vec(),
+        };
+        let Change::Set { value, .. } = self else {
+            return Some(removed);
+        };
+        let narrowed = subscription[path.len()..]
+            .iter()
+            .try_fold(value, |value, key| value.as_object()?.get(key));
+        Some(match narrowed {
+            Some(value) => Change::Set {
+                path: subscription.to_vec(),
+                value: value.clone(),
+            },
+            None => removed,
+        })
+    }
+}
+
+/// Broadcasts every change to values in cache to whoever subscribes.
+pub struct Changes(broadcast::Sender<Change>);
+impl Default for Changes {
+    fn default() -> Self {
+        Changes(broadcast::channel(CAPACITY).0)
+    }
+}
+impl Changes {
+    /// Start receiving every change from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.0.subscribe()
+    }
+
+    /// Whether anyone is subscribed, so changes are worth describing.
+    pub fn is_watched(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+
+    /// Announce a change to every subscriber, if there's one.
+    pub fn publish(&self, change: Option<Change>) {
+        if let Some(change) = change {
+            // Nobody may be subscribed anymore, which is fine
+            let _ = self.0.send(change);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn narrows_changes_down_to_subscriptions() {
+        let set = Change::Set {
+            path: path(&["users"]),
+            value: json!({"alice": {"score": 1}}),
+        };
+
+        // At or under the subscription, the change is seen as is
+        assert_eq!(set.seen_from(&path(&["users"])), Some(set.clone()));
+        assert_eq!(set.seen_from(&path(&[])), Some(set.clone()));
+
+        // Above it, only what's at the subscription is seen
+        assert_eq!(
+            set.seen_from(&path(&["users", "alice", "score"])),
+            Some(Change::Set {
+                path: path(&["users", "alice", "score"]),
+                value: json!(1)
+            })
+        );
+        assert_eq!(
+            set.seen_from(&path(&["users", "bob"])),
+            Some(Change::Remove {
+                path: path(&["users", "bob"])
+            })
+        );
+
+        // Anywhere else, it isn't seen at all
+        assert_eq!(set.seen_from(&path(&["teams"])), None);
+        assert_eq!(set.seen_from(&path(&["user"])), None);
+    }
+}
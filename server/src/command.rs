@@ -1,70 +1,524 @@
-use crate::{Error, ThreadSafeFabric};
+use crate::{
+    aof, dump,
+    encoding::Encoding,
+    frame::Framing,
+    shutdown::{ShutdownMode, SHUTDOWN},
+    stats::STATS,
+    tokens::{quote, Tokens},
+    Error, Fabric, Limits, ThreadSafeFabric,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::Value;
+use std::path::{Component, Path, PathBuf};
 
-/// The different types of supported commands
+/// The different types of supported commands, with their arguments
+#[derive(Debug, PartialEq)]
 pub enum Command {
     /// Get an entry in cache
-    Get,
+    Get { key: String },
     /// Set an entry in cache
-    Set,
+    Set { key: String, value: String },
+    /// Set an entry in cache from a value decoded from a binary encoding
+    SetValue { key: String, value: Value },
     /// Remove an entry from cache
-    Remove,
+    Remove { key: String },
+    /// Get an opaque binary entry in cache
+    GetBlob { key: String },
+    /// Set an opaque binary entry in cache, at a top-level key
+    SetBlob { key: String, blob: Vec<u8> },
+    /// Rewrite the append-only log in the background
+    BgRewriteAof,
+    /// Serialize an entry in cache into a portable blob
+    Dump { key: String },
+    /// Set an entry in cache from a blob created by `Dump`
+    Restore {
+        key: String,
+        blob: String,
+        replace: bool,
+    },
+    /// Write an entry in cache to a JSON file on the server
+    Export { key: String, file: String },
+    /// Set an entry in cache from a JSON file on the server
+    Import { key: String, file: String },
+    /// Report the counters about clients and the limits in effect
+    Stats,
+    /// Shut the server down once the connected clients finish their commands
+    Shutdown { mode: ShutdownMode },
+    /// Send a message to whoever is subscribed to a channel
+    Publish { channel: String, message: String },
 }
 impl Command {
-    /// Initialize a command from client input
-    pub fn from(input: &str) -> Result<Command, Error> {
-        let trimmed = input.trim();
-        if trimmed.starts_with("GET") {
-            Ok(Command::Get)
-        } else if trimmed.starts_with("SET") {
-            Ok(Command::Set)
-        } else if trimmed.starts_with("REMOVE") {
-            Ok(Command::Remove)
-        } else {
-            let parts: Vec<&str> = trimmed.split(" ").collect();
-            let cmd = parts[0].to_string();
-
-            Err(Error::UnsupportedCommand(cmd))
+    /// Parse a command from client input.
+    ///
+    /// NOTE: Verbs are case-insensitive and arguments are split on whitespace,
+    /// unless quoted, except for the value of a SET, which is the rest of the
+    /// line as JSON, the blob of a SETBLOB, which is base64 encoded, and the
+    /// message of a PUBLISH, which is the rest of the line as is.
+    pub fn parse(input: &str) -> Result<Command, Error> {
+        let mut tokens = Tokens::new(input);
+        let verb = tokens
+            .next()?
+            .ok_or_else(|| Error::UnsupportedCommand(String::new()))?
+            .to_uppercase();
+
+        let cmd = match verb.as_str() {
+            "GET" => Command::Get {
+                key: tokens.arg(&verb)?,
+            },
+            "SET" => {
+                let key = tokens.arg(&verb)?;
+                let value = tokens.rest();
+                if value.is_empty() {
+                    return Err(Error::WrongArity(verb));
+                }
+                Command::Set {
+                    key,
+                    value: value.to_string(),
+                }
+            }
+            "REMOVE" => Command::Remove {
+                key: tokens.arg(&verb)?,
+            },
+            "GETBLOB" => Command::GetBlob {
+                key: tokens.arg(&verb)?,
+            },
+            "SETBLOB" => Command::SetBlob {
+                key: tokens.arg(&verb)?,
+                blob: BASE64
+                    .decode(tokens.arg(&verb)?)
+                    .map_err(|_| Error::Syntax("Blob Is Not Valid Base64".to_string()))?,
+            },
+            "BGREWRITEAOF" => Command::BgRewriteAof,
+            "STATS" => Command::Stats,
+            "SHUTDOWN" => Command::Shutdown {
+                mode: match tokens.next()? {
+                    Some(flag) => flag.parse()?,
+                    None => ShutdownMode::Default,
+                },
+            },
+            "DUMP" => Command::Dump {
+                key: tokens.arg(&verb)?,
+            },
+            "RESTORE" => {
+                let key = tokens.arg(&verb)?;
+                let blob = tokens.arg(&verb)?;
+                let replace = match tokens.next()? {
+                    Some(flag) if flag.eq_ignore_ascii_case("REPLACE") => true,
+                    Some(flag) => return Err(Error::Syntax(format!("Unknown Flag \"{flag}\""))),
+                    None => false,
+                };
+                Command::Restore { key, blob, replace }
+            }
+            "EXPORT" => Command::Export {
+                key: tokens.arg(&verb)?,
+                file: tokens.arg(&verb)?,
+            },
+            "IMPORT" => Command::Import {
+                key: tokens.arg(&verb)?,
+                file: tokens.arg(&verb)?,
+            },
+            "PUBLISH" => {
+                let channel = tokens.arg(&verb)?;
+                let message = tokens.rest();
+                if message.is_empty() {
+                    return Err(Error::WrongArity(verb));
+                }
+                Command::Publish {
+                    channel,
+                    message: message.to_string(),
+                }
+            }
+            _ => return Err(Error::UnsupportedCommand(verb)),
+        };
+
+        // Every argument has been taken, so anything left over is one too many
+        if tokens.next()?.is_some() {
+            return Err(Error::WrongArity(verb));
+        }
+        Ok(cmd)
+    }
+
+    /// Parse a command whose last argument follows its command line in a frame,
+    /// as raw bytes: the blob of a SETBLOB, or the value of a SET in a binary
+    /// encoding, which is decoded into JSON nesting at most `max_depth` levels.
+    pub fn parse_with_payload(
+        input: &str,
+        payload: &[u8],
+        encoding: Encoding,
+        limits: &Limits,
+    ) -> Result<Command, Error> {
+        let mut tokens = Tokens::new(input);
+        let verb = tokens
+            .next()?
+            .ok_or_else(|| Error::UnsupportedCommand(String::new()))?
+            .to_uppercase();
+        let key = tokens.arg(&verb)?;
+        if tokens.next()?.is_some() {
+            return Err(Error::WrongArity(verb));
+        }
+
+        match verb.as_str() {
+            "SETBLOB" => Ok(Command::SetBlob {
+                key,
+                blob: payload.to_vec(),
+            }),
+            "SET" => {
+                limits.check_size(payload.len())?;
+                Ok(Command::SetValue {
+                    key,
+                    value: encoding.decode(payload, limits.max_json_depth)?,
+                })
+            }
+            _ => Err(Error::UnsupportedCommand(verb)),
         }
     }
 
-    /// Handle the functionality behind a command
-    pub async fn handle(&self, line: &str, fabric: &ThreadSafeFabric) -> Result<Vec<u8>, Error> {
+    /// Handle the functionality behind a command, returning
+    /// `OK` or the value asked for on success.
+    ///
+    /// NOTE: Errors are sent back to the client as `ERR <code> <message>`.
+    pub async fn handle(&self, fabric: &ThreadSafeFabric) -> Result<Vec<u8>, Error> {
         match self {
-            Command::Get => {
-                let key = line.strip_prefix("GET ").unwrap_or("");
-                let keys = key.split('.').collect();
-                match fabric.read().await.get(keys) {
-                    Ok(value) => Ok(format!("{}\n", value).into_bytes()),
-                    Err(_) => Ok(format!("{}\n", Error::KeyNotFound(key.into())).into_bytes()),
-                }
+            Command::Get { key } => {
+                let value = get(&*fabric.read().await, key)?;
+                Ok(format!("{}\n", value).into_bytes())
+            }
+            Command::Set { key, value } => {
+                let mut fabric = fabric.write().await;
+                let keys = key.split('.').collect::<Vec<_>>();
+                fabric.limits.check_value(&keys, value)?;
+                set_and_log(&mut fabric, key, serde_json::from_str(value)?)
+            }
+            Command::SetValue { key, value } => {
+                set_and_log(&mut *fabric.write().await, key, value.clone())
+            }
+            Command::Remove { key } => {
+                let mut fabric = fabric.write().await;
+                fabric.remove(key.split('.').collect())?;
+                fabric.log(&format!("REMOVE {}", quote(key)))?;
+                Ok(b"OK\n".to_vec())
+            }
+            Command::GetBlob { key } => {
+                let fabric = fabric.read().await;
+                Ok(format!("{}\n", BASE64.encode(fabric.get_blob(key)?)).into_bytes())
             }
-            Command::Set => {
-                let line = line.trim_end();
-                let cmd_str = line.strip_prefix("SET ").unwrap_or("");
-                let parts: Vec<&str> = cmd_str.splitn(2, ' ').collect();
-
-                if parts.len() == 2 {
-                    let key = parts[0].to_string();
-                    let keys = key.split('.').collect();
-                    let value = parts[1].to_string();
-
-                    match fabric.write().await.set(keys, &value) {
-                        Ok(_) => Ok(b"OK\n".to_vec()),
-                        Err(e) => Ok(format!("SET ERROR For Key: {key}: {e:?}").into_bytes()),
-                    }
-                } else {
-                    Ok(b"Invalid SET Command\n".to_vec())
+            Command::SetBlob { key, blob } => {
+                let mut fabric = fabric.write().await;
+                fabric.set_blob(key, blob.clone())?;
+                fabric.log(&format!("SETBLOB {} {}", quote(key), BASE64.encode(blob)))?;
+                Ok(b"OK\n".to_vec())
+            }
+            Command::BgRewriteAof => {
+                aof::start_rewrite(fabric).await?;
+                Ok(b"OK\n".to_vec())
+            }
+            Command::Dump { key } => {
+                let value = get(&*fabric.read().await, key)?;
+                Ok(format!("{}\n", dump::serialize(&value)?).into_bytes())
+            }
+            Command::Restore { key, blob, replace } => {
+                let value = dump::deserialize(blob)?;
+
+                let mut fabric = fabric.write().await;
+                let exists = matches!(
+                    fabric.get(key.split('.').collect()),
+                    Ok(_) | Err(Error::WrongType(_))
+                );
+                if !replace && exists {
+                    return Err(Error::KeyExists(key.clone()));
                 }
+                set_and_log(&mut fabric, key, value)
+            }
+            Command::Export { key, file } => {
+                let (value, path) = {
+                    let fabric = fabric.read().await;
+                    (get(&fabric, key)?, export_path(&fabric, file)?)
+                };
+                let json = format!("{}\n", serde_json::to_string_pretty(&value)?);
+                tokio::fs::write(path, json).await?;
+                Ok(b"OK\n".to_vec())
             }
-            Command::Remove => {
-                let key = line.strip_prefix("REMOVE ").unwrap_or("");
-                let keys = key.split('.').collect();
+            Command::Import { key, file } => {
+                let path = export_path(&*fabric.read().await, file)?;
+                let json = tokio::fs::read_to_string(path).await?;
 
-                match fabric.write().await.remove(keys) {
-                    Ok(_) => Ok(b"OK\n".to_vec()),
-                    Err(e) => Ok(format!("REMOVE Error For Key: {key}: {e:?}").into_bytes()),
+                let mut fabric = fabric.write().await;
+                let keys = key.split('.').collect::<Vec<_>>();
+                fabric.limits.check_value(&keys, &json)?;
+                set_and_log(&mut fabric, key, serde_json::from_str(&json)?)
+            }
+            Command::Stats => {
+                let limits = fabric.read().await.limits;
+                Ok(format!("{}\n", STATS.report(&limits)).into_bytes())
+            }
+            Command::Shutdown { mode } => {
+                // There's nothing to save without the append-only log
+                if *mode == ShutdownMode::Save && fabric.read().await.aof.is_none() {
+                    return Err(Error::AppendOnlyDisabled);
+                }
+                if SHUTDOWN.request(*mode) {
+                    println!("Client sent SHUTDOWN, shutting down");
                 }
+                Ok(b"OK\n".to_vec())
+            }
+            Command::Publish { channel, message } => {
+                // Messages are only for whoever's listening now, so they're never logged
+                let received = fabric.read().await.pubsub.publish(channel, message);
+                Ok(format!("{received}\n").into_bytes())
             }
         }
     }
+
+    /// Handle a command on a connection speaking Fabric with `framing` and
+    /// sending values in `encoding`, where the value asked for by a GET is
+    /// encoded rather than JSON, and a blob is sent as is in a frame.
+    pub async fn handle_with(
+        &self,
+        fabric: &ThreadSafeFabric,
+        framing: Framing,
+        encoding: Encoding,
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            Command::Get { key } if encoding != Encoding::Json => {
+                let value = get(&*fabric.read().await, key)?;
+                Ok(encoding.reply(&value))
+            }
+            Command::GetBlob { key } if framing == Framing::LengthPrefixed => {
+                let fabric = fabric.read().await;
+                Ok(payload_reply(fabric.get_blob(key)?))
+            }
+            _ => self.handle(fabric).await,
+        }
+    }
+}
+
+/// Get an entry in cache, where anything missing along
+/// the key path means the key isn't found.
+fn get(fabric: &Fabric, key: &str) -> Result<serde_json::Value, Error> {
+    if key.is_empty() {
+        return Err(Error::InvalidKeyPath(key.into()));
+    }
+    fabric.get(key.split('.').collect()).map_err(|e| match e {
+        Error::WrongType(_) => e,
+        _ => Error::KeyNotFound(key.into()),
+    })
+}
+
+/// The reply carrying raw bytes in a frame, which has `VALUE`
+/// on a line of its own, followed by the bytes as is.
+pub fn payload_reply(payload: &[u8]) -> Vec<u8> {
+    // Replies end in a newline, which isn't sent in a frame
+    [&b"VALUE\n"[..], payload, b"\n"].concat()
+}
+
+/// Set an entry in cache from a parsed value, recording
+/// it in the append-only log as a plain SET.
+///
+/// NOTE: Values can span lines in framed requests and files, but records in
+/// the log can't, so they're logged as compact JSON, and only with a log.
+fn set_and_log(fabric: &mut Fabric, key: &str, value: Value) -> Result<Vec<u8>, Error> {
+    let record = fabric
+        .aof
+        .is_some()
+        .then(|| format!("SET {} {value}", quote(key)));
+    fabric.set_value(key.split('.').collect(), value)?;
+    if let Some(record) = record {
+        fabric.log(&record)?;
+    }
+    Ok(b"OK\n".to_vec())
+}
+
+/// Resolve the file of an `EXPORT` or `IMPORT` inside the export directory,
+/// refusing paths that could reach outside of it, so clients can't read or
+/// write anything else the server has access to.
+fn export_path(fabric: &Fabric, file: &str) -> Result<PathBuf, Error> {
+    let export_dir = fabric.export_dir.as_ref().ok_or(Error::ExportDisabled)?;
+    let file = Path::new(file);
+    let relative = file.components().next().is_some()
+        && file
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !relative {
+        return Err(Error::Syntax(format!(
+            "\"{}\" Must Be A Relative Path Inside The Export Directory",
+            file.display()
+        )));
+    }
+    Ok(export_dir.join(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    async fn run(line: &str, fabric: &ThreadSafeFabric) -> String {
+        let output = match Command::parse(line) {
+            Ok(cmd) => cmd.handle(fabric).await,
+            Err(e) => Err(e),
+        };
+        String::from_utf8(output.unwrap_or_else(|e| e.reply())).unwrap()
+    }
+
+    #[test]
+    fn parses_typed_commands() {
+        assert_eq!(
+            Command::parse("get user.name").unwrap(),
+            Command::Get {
+                key: "user.name".into()
+            }
+        );
+        assert_eq!(
+            Command::parse("SET \"my key\" {\"a\": \"b c\"}").unwrap(),
+            Command::Set {
+                key: "my key".into(),
+                value: "{\"a\": \"b c\"}".into()
+            }
+        );
+        assert_eq!(
+            Command::parse("PUBLISH news  hello,  world ").unwrap(),
+            Command::Publish {
+                channel: "news".into(),
+                message: "hello,  world".into()
+            }
+        );
+        assert_eq!(
+            Command::parse("Restore k blob replace").unwrap(),
+            Command::Restore {
+                key: "k".into(),
+                blob: "blob".into(),
+                replace: true
+            }
+        );
+
+        assert_eq!(
+            Command::parse("setblob img iVBORw==").unwrap(),
+            Command::SetBlob {
+                key: "img".into(),
+                blob: b"\x89PNG".to_vec()
+            }
+        );
+        let limits = Limits::default();
+        assert_eq!(
+            Command::parse_with_payload("SETBLOB img", b"\x89PNG\n", Encoding::Json, &limits)
+                .unwrap(),
+            Command::SetBlob {
+                key: "img".into(),
+                blob: b"\x89PNG\n".to_vec()
+            }
+        );
+        // Binary values are decoded once, and kept as they are
+        assert_eq!(
+            Command::parse_with_payload("set a", &[0x91, 0x01], Encoding::MessagePack, &limits)
+                .unwrap(),
+            Command::SetValue {
+                key: "a".into(),
+                value: serde_json::json!([1])
+            }
+        );
+
+        assert_eq!(
+            Command::parse("shutdown nosave").unwrap(),
+            Command::Shutdown {
+                mode: ShutdownMode::NoSave
+            }
+        );
+
+        for (input, code) in [
+            ("GETX foo", "UNSUPPORTED_COMMAND"),
+            ("SHUTDOWN NOW", "SYNTAX"),
+            ("GET", "WRONG_ARITY"),
+            ("GET a b", "WRONG_ARITY"),
+            ("SET a", "WRONG_ARITY"),
+            ("BGREWRITEAOF now", "WRONG_ARITY"),
+            ("RESTORE k blob FORCE", "SYNTAX"),
+            ("SETBLOB k not-base64", "SYNTAX"),
+            ("EXPORT k \"unterminated", "SYNTAX"),
+        ] {
+            assert_eq!(Command::parse(input).unwrap_err().code(), code, "{input}");
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_ok_values_or_errors() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+
+        for (line, expected) in [
+            ("SET user {\"age\": 3}", "OK\n"),
+            ("GET user", "{\"age\":3}\n"),
+            ("get user.age", "3\n"),
+            (
+                "GET nobody",
+                "ERR KEY_NOT_FOUND Key: \"nobody\" Not Found.\n",
+            ),
+            (
+                "GET \"\"",
+                "ERR INVALID_KEY_PATH \"\" Is Not A Valid Key Path.\n",
+            ),
+            (
+                "SET user.age.years 3",
+                "ERR INVALID_KEY_PATH \"user.age.years\" Is Not A Valid Key Path.\n",
+            ),
+            (
+                "SET user",
+                "ERR WRONG_ARITY Wrong Number Of Arguments For SET.\n",
+            ),
+            ("RESTORE user AAAA", "ERR BAD_DUMP Bad Dump: Too Short.\n"),
+            ("SETBLOB img iVBORw==", "OK\n"),
+            ("GETBLOB img", "iVBORw==\n"),
+            (
+                "GET img",
+                "ERR WRONG_TYPE Key: \"img\" Holds A Different Type Of Value.\n",
+            ),
+            (
+                "GETBLOB user",
+                "ERR WRONG_TYPE Key: \"user\" Holds A Different Type Of Value.\n",
+            ),
+            (
+                "SETBLOB user.age AA==",
+                "ERR INVALID_KEY_PATH \"user.age\" Is Not A Valid Key Path.\n",
+            ),
+            ("REMOVE img", "OK\n"),
+            ("GETBLOB img", "ERR KEY_NOT_FOUND Key: \"img\" Not Found.\n"),
+            ("REMOVE user", "OK\n"),
+        ] {
+            assert_eq!(run(line, &fabric).await, expected, "{line}");
+        }
+
+        // Errors spanning lines are collapsed onto one
+        let reply = run("SET user {", &fabric).await;
+        assert!(reply.starts_with("ERR BAD_DATA_STRUCTURE "));
+        assert_eq!(reply.lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn confines_exports_to_the_export_dir() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+        run("SET user {\"age\": 3}", &fabric).await;
+
+        let reply = run("EXPORT user user.json", &fabric).await;
+        assert!(reply.starts_with("ERR EXPORT_DISABLED "), "{reply}");
+
+        let dir = std::env::temp_dir().join(format!("fabric-exports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        fabric.write().await.export_dir = Some(dir.clone());
+
+        for file in [
+            "../user.json",
+            "/tmp/user.json",
+            "a/../../user.json",
+            "\"\"",
+        ] {
+            let reply = run(&format!("EXPORT user {file}"), &fabric).await;
+            assert!(reply.starts_with("ERR SYNTAX "), "{file}: {reply}");
+        }
+
+        assert_eq!(run("EXPORT user user.json", &fabric).await, "OK\n");
+        assert!(dir.join("user.json").exists());
+        assert_eq!(run("IMPORT copy ./user.json", &fabric).await, "OK\n");
+        assert_eq!(run("GET copy.age", &fabric).await, "3\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
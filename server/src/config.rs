@@ -0,0 +1,714 @@
+use crate::{
+    aof::{CorruptionPolicy, FsyncPolicy},
+    storage::{Compression, EncryptionKey},
+    Error,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+/// Every setting, with a short description of it for `--help`.
+///
+/// NOTE: Each one can be set with `--<setting> <value>` on the command line, a
+/// `FABRIC_<SETTING>` environment variable or a `<setting> = <value>` line in the
+/// config file, in order of precedence.
+const SETTINGS: &[(&str, &str)] = &[
+    ("bind", "The IP addresses to listen on, comma separated"),
+    ("port", "The TCP port to listen on"),
+    ("unixsocket", "The unix domain socket to listen on, if any"),
+    (
+        "unixsocketperm",
+        "The octal file permissions of the unix socket",
+    ),
+    (
+        "tls_cert_file",
+        "PEM certificate chain to serve TCP connections over TLS with",
+    ),
+    ("tls_key_file", "PEM private key of the TLS certificate"),
+    (
+        "tls_ca_cert_file",
+        "PEM CA certificates that client certificates must be signed by",
+    ),
+    (
+        "http_port",
+        "The TCP port to serve the HTTP gateway on, if any",
+    ),
+    (
+        "websocket_port",
+        "The TCP port to serve WebSocket subscriptions on, if any",
+    ),
+    ("maxclients", "Most clients connected at once"),
+    (
+        "timeout",
+        "Seconds a client can idle before it's disconnected, 0 never",
+    ),
+    (
+        "client_output_buffer_limit",
+        "Bytes of replies a client can fall behind reading, before it's disconnected",
+    ),
+    (
+        "max_request_size",
+        "Largest request in bytes, before the client is disconnected",
+    ),
+    (
+        "max_json_depth",
+        "Deepest nesting of a JSON value, up to 128",
+    ),
+    ("max_value_size", "Largest value in bytes that can be set"),
+    (
+        "shutdown_timeout",
+        "Seconds to wait for clients to finish when shutting down",
+    ),
+    (
+        "export_dir",
+        "Directory EXPORT and IMPORT read and write files in, unset disables them",
+    ),
+    (
+        "appendonly",
+        "Record mutating commands in the append-only log (yes, no)",
+    ),
+    ("appendfilename", "Where the append-only log lives on disk"),
+    (
+        "appendfsync",
+        "When to fsync the append-only log (always, everysec, no)",
+    ),
+    (
+        "aof_on_corruption",
+        "What to do with a corrupted log on startup (refuse, repair)",
+    ),
+    (
+        "aof_compression",
+        "How to compress log records (none, zstd, lz4)",
+    ),
+    (
+        "encryption_key",
+        "Hex encoded 256 bit key to encrypt log records with",
+    ),
+    (
+        "encryption_key_file",
+        "File holding the hex encoded encryption key",
+    ),
+    (
+        "aof_migrate_plaintext",
+        "Load unencrypted log records once and rewrite the log encrypted (yes, no)",
+    ),
+    (
+        "auto_aof_rewrite_percentage",
+        "Log growth that triggers a rewrite, 0 disables it",
+    ),
+    (
+        "auto_aof_rewrite_min_size",
+        "Size in bytes the log must reach to be rewritten",
+    ),
+];
+
+/// The server settings.
+pub struct Config {
+    /// The IP addresses to listen on
+    pub bind: Vec<IpAddr>,
+    /// The TCP port to listen on
+    pub port: u16,
+    /// The unix domain socket to listen on, if any
+    pub unixsocket: Option<PathBuf>,
+    /// The file permissions of the unix domain socket
+    pub unixsocketperm: u32,
+    /// The certificate chain TCP connections are served over TLS with, if any
+    pub tls_cert_file: Option<PathBuf>,
+    /// The private key of the TLS certificate
+    pub tls_key_file: Option<PathBuf>,
+    /// The CA certificates client certificates are verified against, if any
+    pub tls_ca_cert_file: Option<PathBuf>,
+    /// The TCP port the HTTP gateway is served on, if any
+    pub http_port: Option<u16>,
+    /// The TCP port WebSocket subscriptions are served on, if any
+    pub websocket_port: Option<u16>,
+    /// The most clients that can be connected at once
+    pub maxclients: usize,
+    /// How many seconds a client can idle before it's disconnected (0 never)
+    pub timeout: u64,
+    /// How many bytes of replies a client can fall behind reading before it's disconnected
+    pub client_output_buffer_limit: usize,
+    /// The largest request in bytes a client can send, before it's disconnected
+    pub max_request_size: usize,
+    /// How deeply JSON values can nest objects and arrays
+    pub max_json_depth: usize,
+    /// The largest value in bytes, as compact JSON, that can be set
+    pub max_value_size: usize,
+    /// How many seconds to wait for clients to finish their commands when shutting down
+    pub shutdown_timeout: u64,
+    /// The directory `EXPORT` and `IMPORT` are confined to, if they're enabled at all
+    pub export_dir: Option<PathBuf>,
+    /// Whether every mutating command is recorded in the append-only log
+    pub appendonly: bool,
+    /// Where the append-only log lives on disk
+    pub appendfilename: PathBuf,
+    /// How often the append-only log is flushed to disk
+    pub appendfsync: FsyncPolicy,
+    /// What to do on startup when the append-only log is corrupted
+    pub aof_on_corruption: CorruptionPolicy,
+    /// How records in the append-only log are compressed
+    pub aof_compression: Compression,
+    /// The key records in the append-only log are encrypted with, if any
+    pub encryption_key: Option<EncryptionKey>,
+    /// The file the encryption key is read from, unless the key itself is set
+    pub encryption_key_file: Option<PathBuf>,
+    /// Whether unencrypted records in the append-only log are loaded despite
+    /// the encryption key, to rewrite the log encrypted on startup
+    pub aof_migrate_plaintext: bool,
+    /// How much the append-only log has to grow since it was last
+    /// rewritten, as a percentage, to be rewritten automatically (0 disables it)
+    pub auto_aof_rewrite_percentage: u64,
+    /// The size in bytes the append-only log has to reach before
+    /// it is rewritten automatically
+    pub auto_aof_rewrite_min_size: u64,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: 8731,
+            unixsocket: None,
+            unixsocketperm: 0o700,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            http_port: None,
+            websocket_port: None,
+            maxclients: 10000,
+            timeout: 0,
+            client_output_buffer_limit: 64 * 1024 * 1024,
+            max_request_size: 512 * 1024 * 1024,
+            max_json_depth: 128,
+            max_value_size: 512 * 1024 * 1024,
+            shutdown_timeout: 10,
+            export_dir: None,
+            appendonly: false,
+            appendfilename: PathBuf::from("fabric.aof"),
+            appendfsync: FsyncPolicy::EverySec,
+            aof_on_corruption: CorruptionPolicy::Refuse,
+            aof_compression: Compression::None,
+            encryption_key: None,
+            encryption_key_file: None,
+            aof_migrate_plaintext: false,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+        }
+    }
+}
+impl Config {
+    /// Initialize the config from the defaults, overridden by the
+    /// config file, then the `FABRIC_*` environment variables and
+    /// then the command line flags.
+    pub fn load(cli: &Cli, env: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let mut config = Self::default();
+
+        let config_path = cli
+            .config_path
+            .clone()
+            .or_else(|| env("FABRIC_CONFIG").map(PathBuf::from));
+        if let Some(path) = config_path {
+            config.apply_file(&path)?;
+        }
+
+        for (setting, _) in SETTINGS {
+            let var = format!("FABRIC_{}", setting.to_uppercase());
+            if let Some(value) = env(&var) {
+                config.set(setting, &value)?;
+            }
+        }
+
+        for (setting, value) in &cli.overrides {
+            config.set(setting, value)?;
+        }
+
+        // The key itself wins over a key file, wherever either of them was set
+        if config.encryption_key.is_none() {
+            if let Some(path) = &config.encryption_key_file {
+                config.encryption_key = Some(EncryptionKey::from_file(path)?);
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Apply every setting in a TOML config file.
+    fn apply_file(&mut self, path: &Path) -> Result<(), Error> {
+        let contents = std::fs::read_to_string(path)?;
+        let table: toml::Table = contents.parse().map_err(|e| {
+            Error::InvalidConfig(format!("{} Is Not Valid TOML: {}", path.display(), e))
+        })?;
+
+        for (setting, value) in table {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                toml::Value::Array(values) => values
+                    .iter()
+                    .map(|value| value.as_str().map(str::to_string))
+                    .collect::<Option<Vec<String>>>()
+                    .ok_or_else(|| {
+                        Error::InvalidConfig(format!("{setting} expects an array of strings"))
+                    })?
+                    .join(","),
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "{setting} expects a string, number, boolean or array"
+                    )))
+                }
+            };
+            self.set(&setting, &value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Set a single setting from its textual value.
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), Error> {
+        let setting = setting.trim().to_lowercase().replace('-', "_");
+        match setting.as_str() {
+            "bind" => {
+                self.bind = value
+                    .split([',', ' '])
+                    .filter(|ip| !ip.is_empty())
+                    .map(|ip| {
+                        ip.parse().map_err(|_| {
+                            Error::InvalidConfig(format!("bind expects IP addresses, got \"{ip}\""))
+                        })
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "port" => {
+                self.port = value.trim().parse().map_err(|_| {
+                    Error::InvalidConfig(format!("port expects a port number, got \"{value}\""))
+                })?
+            }
+            "unixsocket" => self.unixsocket = parse_path(value),
+            "unixsocketperm" => {
+                self.unixsocketperm = u32::from_str_radix(value.trim().trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|perm| *perm <= 0o777)
+                    .ok_or_else(|| {
+                        Error::InvalidConfig(format!(
+                            "unixsocketperm expects octal permissions, got \"{value}\""
+                        ))
+                    })?
+            }
+            "tls_cert_file" => self.tls_cert_file = parse_path(value),
+            "tls_key_file" => self.tls_key_file = parse_path(value),
+            "tls_ca_cert_file" => self.tls_ca_cert_file = parse_path(value),
+            "http_port" => self.http_port = parse_port(&setting, value)?,
+            "websocket_port" => self.websocket_port = parse_port(&setting, value)?,
+            "maxclients" => self.maxclients = parse_number(&setting, value)?,
+            "timeout" => self.timeout = parse_number(&setting, value)?,
+            "client_output_buffer_limit" => {
+                self.client_output_buffer_limit = parse_number(&setting, value)?
+            }
+            "max_request_size" => self.max_request_size = parse_number(&setting, value)?,
+            "max_json_depth" => self.max_json_depth = parse_number(&setting, value)?,
+            "max_value_size" => self.max_value_size = parse_number(&setting, value)?,
+            "shutdown_timeout" => self.shutdown_timeout = parse_number(&setting, value)?,
+            "export_dir" => self.export_dir = parse_path(value),
+            "appendonly" => self.appendonly = parse_bool(&setting, value)?,
+            "appendfilename" => self.appendfilename = PathBuf::from(value),
+            "appendfsync" => self.appendfsync = value.parse()?,
+            "aof_on_corruption" => self.aof_on_corruption = value.parse()?,
+            "aof_compression" => self.aof_compression = value.parse()?,
+            "encryption_key" => self.encryption_key = Some(EncryptionKey::from_hex(value)?),
+            "encryption_key_file" => self.encryption_key_file = parse_path(value),
+            "aof_migrate_plaintext" => self.aof_migrate_plaintext = parse_bool(&setting, value)?,
+            "auto_aof_rewrite_percentage" => {
+                self.auto_aof_rewrite_percentage = parse_number(&setting, value)?
+            }
+            "auto_aof_rewrite_min_size" => {
+                self.auto_aof_rewrite_min_size = parse_number(&setting, value)?
+            }
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "\"{setting}\" Is Not A Known Setting."
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Verify the settings make sense together.
+    fn validate(&self) -> Result<(), Error> {
+        if self.bind.is_empty() && self.unixsocket.is_none() {
+            return Err(Error::InvalidConfig(
+                "There must be at least one address to bind or a unixsocket".to_string(),
+            ));
+        }
+        if cfg!(not(unix)) && self.unixsocket.is_some() {
+            return Err(Error::InvalidConfig(
+                "unixsocket is only supported on unix platforms".to_string(),
+            ));
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return Err(Error::InvalidConfig(
+                "tls_cert_file and tls_key_file must be set together".to_string(),
+            ));
+        }
+        if self.tls_ca_cert_file.is_some() && self.tls_cert_file.is_none() {
+            return Err(Error::InvalidConfig(
+                "tls_ca_cert_file requires tls_cert_file and tls_key_file".to_string(),
+            ));
+        }
+        let ports = [Some(self.port), self.http_port, self.websocket_port];
+        let ports: Vec<u16> = ports.into_iter().flatten().collect();
+        if (1..ports.len()).any(|i| ports[..i].contains(&ports[i])) {
+            return Err(Error::InvalidConfig(
+                "port, http_port and websocket_port must all be different".to_string(),
+            ));
+        }
+        if self.maxclients == 0 {
+            return Err(Error::InvalidConfig(
+                "maxclients must be at least 1".to_string(),
+            ));
+        }
+        if self.client_output_buffer_limit == 0
+            || self.max_request_size == 0
+            || self.max_value_size == 0
+        {
+            return Err(Error::InvalidConfig(
+                "client_output_buffer_limit, max_request_size and max_value_size must be at least 1"
+                    .to_string(),
+            ));
+        }
+        // Deeper JSON than this is refused by the parser itself
+        if !(1..=128).contains(&self.max_json_depth) {
+            return Err(Error::InvalidConfig(
+                "max_json_depth must be between 1 and 128".to_string(),
+            ));
+        }
+        if self.appendonly && self.appendfilename.as_os_str().is_empty() {
+            return Err(Error::InvalidConfig(
+                "appendfilename can't be empty when appendonly is enabled".to_string(),
+            ));
+        }
+        if self.aof_migrate_plaintext && self.encryption_key.is_none() {
+            return Err(Error::InvalidConfig(
+                "aof_migrate_plaintext requires encryption_key or encryption_key_file".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+/// Display the effective config in the same TOML format the config file uses.
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let bind: Vec<String> = self.bind.iter().map(|ip| format!("\"{ip}\"")).collect();
+        writeln!(f, "bind = [{}]", bind.join(", "))?;
+        writeln!(f, "port = {}", self.port)?;
+        if let Some(unixsocket) = &self.unixsocket {
+            writeln!(f, "unixsocket = {:?}", unixsocket.display())?;
+        }
+        writeln!(f, "unixsocketperm = \"{:o}\"", self.unixsocketperm)?;
+        for (setting, path) in [
+            ("tls_cert_file", &self.tls_cert_file),
+            ("tls_key_file", &self.tls_key_file),
+            ("tls_ca_cert_file", &self.tls_ca_cert_file),
+        ] {
+            if let Some(path) = path {
+                writeln!(f, "{setting} = {:?}", path.display())?;
+            }
+        }
+        for (setting, port) in [
+            ("http_port", self.http_port),
+            ("websocket_port", self.websocket_port),
+        ] {
+            if let Some(port) = port {
+                writeln!(f, "{setting} = {port}")?;
+            }
+        }
+        writeln!(f, "maxclients = {}", self.maxclients)?;
+        writeln!(f, "timeout = {}", self.timeout)?;
+        writeln!(
+            f,
+            "client_output_buffer_limit = {}",
+            self.client_output_buffer_limit
+        )?;
+        writeln!(f, "max_request_size = {}", self.max_request_size)?;
+        writeln!(f, "max_json_depth = {}", self.max_json_depth)?;
+        writeln!(f, "max_value_size = {}", self.max_value_size)?;
+        writeln!(f, "shutdown_timeout = {}", self.shutdown_timeout)?;
+        if let Some(export_dir) = &self.export_dir {
+            writeln!(f, "export_dir = {:?}", export_dir.display())?;
+        }
+        writeln!(f, "appendonly = {}", self.appendonly)?;
+        writeln!(f, "appendfilename = {:?}", self.appendfilename.display())?;
+        writeln!(f, "appendfsync = \"{}\"", self.appendfsync)?;
+        writeln!(f, "aof_on_corruption = \"{}\"", self.aof_on_corruption)?;
+        writeln!(f, "aof_compression = \"{}\"", self.aof_compression)?;
+        if let Some(path) = &self.encryption_key_file {
+            writeln!(f, "encryption_key_file = {:?}", path.display())?;
+        }
+        if self.encryption_key.is_some() {
+            writeln!(f, "# encryption_key = <redacted>")?;
+        }
+        writeln!(f, "aof_migrate_plaintext = {}", self.aof_migrate_plaintext)?;
+        writeln!(
+            f,
+            "auto_aof_rewrite_percentage = {}",
+            self.auto_aof_rewrite_percentage
+        )?;
+        writeln!(
+            f,
+            "auto_aof_rewrite_min_size = {}",
+            self.auto_aof_rewrite_min_size
+        )
+    }
+}
+
+/// The parsed command line flags.
+#[derive(Default)]
+pub struct Cli {
+    /// The TOML config file to load, from `--config`
+    pub config_path: Option<PathBuf>,
+    /// Whether to print the effective config and exit, from `--print-config`
+    pub print_config: bool,
+    /// Whether to print the usage and exit, from `--help`
+    pub help: bool,
+    /// Every `--<setting> <value>` pair, in the order they were given
+    pub overrides: Vec<(String, String)>,
+}
+impl Cli {
+    /// Parse the command line flags (without the program name).
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> {
+        let mut cli = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(Error::InvalidConfig(format!(
+                    "Unexpected Argument \"{arg}\"."
+                )));
+            };
+            // Support both `--setting value` and `--setting=value`
+            let (flag, inline_value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (flag, None),
+            };
+
+            match flag {
+                "help" => cli.help = true,
+                "print-config" => cli.print_config = true,
+                _ => {
+                    let value = inline_value.or_else(|| args.next()).ok_or_else(|| {
+                        Error::InvalidConfig(format!("--{flag} Is Missing A Value."))
+                    })?;
+                    if flag == "config" {
+                        cli.config_path = Some(PathBuf::from(value));
+                    } else {
+                        cli.overrides.push((flag.to_string(), value));
+                    }
+                }
+            }
+        }
+
+        Ok(cli)
+    }
+
+    /// The usage shown for `--help`.
+    pub fn usage() -> String {
+        let mut usage = String::from(
+            "Usage: fabric-cache [--config <file>] [--print-config] [--<setting> <value>...]\n\n\
+             Settings (also FABRIC_<SETTING> environment variables or config file keys):\n",
+        );
+        for (setting, description) in SETTINGS {
+            usage.push_str(&format!(
+                "  --{:<30}{}\n",
+                setting.replace('_', "-"),
+                description
+            ));
+        }
+        usage
+    }
+}
+
+/// Parse a boolean setting, accepting the usual spellings.
+fn parse_bool(setting: &str, value: &str) -> Result<bool, Error> {
+    match value.trim().to_lowercase().as_str() {
+        "yes" | "true" | "on" | "1" => Ok(true),
+        "no" | "false" | "off" | "0" => Ok(false),
+        _ => Err(Error::InvalidConfig(format!(
+            "{setting} expects yes or no, got \"{value}\""
+        ))),
+    }
+}
+
+/// Parse an optional path setting, where an empty value unsets it.
+fn parse_path(value: &str) -> Option<PathBuf> {
+    Some(PathBuf::from(value)).filter(|_| !value.is_empty())
+}
+
+/// Parse an optional port setting, where an empty value unsets it.
+fn parse_port(setting: &str, value: &str) -> Result<Option<u16>, Error> {
+    match value.trim() {
+        "" => Ok(None),
+        port => port.parse().map(Some).map_err(|_| {
+            Error::InvalidConfig(format!("{setting} expects a port number, got \"{value}\""))
+        }),
+    }
+}
+
+/// Parse a numeric setting.
+fn parse_number<T: std::str::FromStr>(setting: &str, value: &str) -> Result<T, Error> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidConfig(format!("{setting} expects a number, got \"{value}\"")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn defaults_to_localhost() {
+        let config = Config::load(&cli(&[]), no_env).unwrap();
+        assert_eq!(config.bind, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert_eq!(config.port, 8731);
+        assert!(!config.appendonly);
+    }
+
+    #[test]
+    fn layers_file_then_env_then_flags() {
+        let path = std::env::temp_dir().join(format!("fabric-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "bind = [\"127.0.0.1\", \"::1\"]\nport = 9000\nappendonly = true\nappendfsync = \"always\"\n",
+        )
+        .unwrap();
+
+        let env = HashMap::from([
+            ("FABRIC_PORT".to_string(), "9001".to_string()),
+            ("FABRIC_APPENDFSYNC".to_string(), "no".to_string()),
+        ]);
+        let config = Config::load(
+            &cli(&["--config", path.to_str().unwrap(), "--appendfsync=everysec"]),
+            |var| env.get(var).cloned(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.bind,
+            vec![
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                "::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert_eq!(config.port, 9001);
+        assert!(config.appendonly);
+        assert_eq!(config.appendfsync, FsyncPolicy::EverySec);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        for args in [
+            &["--port", "99999"][..],
+            &["--bind", "localhost"],
+            &["--appendonly", "maybe"],
+            &["--appendfsync", "sometimes"],
+            &["--no-such-setting", "1"],
+            &["--appendonly", "yes", "--appendfilename", ""],
+            &["--bind", ""],
+            &["--unixsocketperm", "800"],
+            &["--max-request-size", "0"],
+            &["--maxclients", "0"],
+            &["--http-port", "8731"],
+            &["--http-port", "70000"],
+            &["--http-port", "9000", "--websocket-port", "9000"],
+            &["--timeout", "-1"],
+            &["--max-json-depth", "129"],
+            &["--tls-cert-file", "cert.pem"],
+            &["--tls-key-file", "key.pem", "--tls-ca-cert-file", "ca.pem"],
+        ] {
+            assert!(Config::load(&cli(args), no_env).is_err(), "{args:?}");
+        }
+
+        assert!(Cli::parse(["--port".to_string()]).is_err());
+        assert!(Cli::parse(["8731".to_string()]).is_err());
+    }
+
+    #[test]
+    fn prints_config_that_loads_back() {
+        let config = Config::load(
+            &cli(&[
+                "--port",
+                "9002",
+                "--bind",
+                "0.0.0.0, ::",
+                "--unixsocket",
+                "/tmp/fabric.sock",
+                "--unixsocketperm",
+                "770",
+                "--aof-compression",
+                "lz4",
+                "--max-value-size",
+                "1024",
+                "--timeout",
+                "300",
+                "--http-port",
+                "8080",
+                "--websocket-port",
+                "8081",
+            ]),
+            no_env,
+        )
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!("fabric-printed-{}.toml", std::process::id()));
+        std::fs::write(&path, config.to_string()).unwrap();
+        let reloaded = Config::load(&cli(&["--config", path.to_str().unwrap()]), no_env).unwrap();
+        assert_eq!(reloaded.port, 9002);
+        assert_eq!(reloaded.bind, config.bind);
+        assert_eq!(reloaded.unixsocket, Some(PathBuf::from("/tmp/fabric.sock")));
+        assert_eq!(reloaded.unixsocketperm, 0o770);
+        assert_eq!(reloaded.aof_compression, Compression::Lz4);
+        assert_eq!(reloaded.max_value_size, 1024);
+        assert_eq!(reloaded.timeout, 300);
+        assert_eq!(reloaded.http_port, Some(8080));
+        assert_eq!(reloaded.websocket_port, Some(8081));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn never_prints_the_encryption_key() {
+        let key = "ab".repeat(32);
+        let config = Config::load(&cli(&["--encryption-key", &key]), no_env).unwrap();
+
+        assert!(config.encryption_key.is_some());
+        assert!(!config.to_string().contains(&key));
+    }
+
+    #[test]
+    fn prefers_the_encryption_key_over_a_key_file() {
+        let missing = std::env::temp_dir().join("fabric-no-such-key-file");
+        let key = "ab".repeat(32);
+        let env = |var: &str| (var == "FABRIC_ENCRYPTION_KEY").then(|| key.clone());
+
+        // The key file is never read when the key itself is set
+        let args = ["--encryption-key-file", missing.to_str().unwrap()];
+        let config = Config::load(&cli(&args), env).unwrap();
+        assert!(config.encryption_key.is_some());
+
+        assert!(Config::load(&cli(&args), no_env).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use crate::Error;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+
+/// The version of the dump format, bumped whenever it changes
+/// so older servers refuse dumps they can't understand.
+const DUMP_FORMAT_VERSION: u64 = 1;
+
+/// Serialize a value into a portable blob that can be restored
+/// on any fabric server with `RESTORE`.
+///
+/// The blob is the base64 encoding of a JSON envelope holding the
+/// format version and the value, followed by its CRC32 checksum.
+pub fn serialize(value: &Value) -> Result<String, Error> {
+    let envelope = json!({
+        "format": DUMP_FORMAT_VERSION,
+        "value": value,
+    });
+
+    let mut bytes = serde_json::to_vec(&envelope)?;
+    let checksum = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+
+    Ok(BASE64.encode(bytes))
+}
+
+/// Deserialize a blob created by `serialize` back into a value.
+pub fn deserialize(blob: &str) -> Result<Value, Error> {
+    let bytes = BASE64
+        .decode(blob.trim())
+        .map_err(|e| Error::BadDump(format!("Not Valid Base64: {e}")))?;
+    if bytes.len() < 4 {
+        return Err(Error::BadDump("Too Short.".to_string()));
+    }
+
+    let (envelope, checksum) = bytes.split_at(bytes.len() - 4);
+    let expected = u32::from_le_bytes(checksum.try_into().unwrap_or_default());
+    if crc32fast::hash(envelope) != expected {
+        return Err(Error::BadDump("Checksum Mismatch.".to_string()));
+    }
+
+    let mut envelope: Value = serde_json::from_slice(envelope)?;
+    match envelope.get("format").and_then(Value::as_u64) {
+        Some(DUMP_FORMAT_VERSION) => {}
+        Some(version) => {
+            return Err(Error::BadDump(format!(
+                "Unsupported Format Version {version}."
+            )))
+        }
+        None => return Err(Error::BadDump("Missing Format Version.".to_string())),
+    }
+
+    envelope
+        .get_mut("value")
+        .map(Value::take)
+        .ok_or_else(|| Error::BadDump("Missing Value.".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_values() {
+        let value = json!({
+            "b7be9512-69d2-40c9-9a01-329ffe79e2ff": {
+                "name": "10 Year Scalper",
+                "position_size": 2,
+                "open_trade": null
+            }
+        });
+
+        let blob = serialize(&value).unwrap();
+        assert!(!blob.contains('\n'));
+        assert_eq!(deserialize(&blob).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_tampered_blobs() {
+        let mut bytes = BASE64.decode(serialize(&json!({"x": 5})).unwrap()).unwrap();
+        bytes[12] ^= 1;
+
+        assert!(matches!(
+            deserialize(&BASE64.encode(bytes)),
+            Err(Error::BadDump(_))
+        ));
+        assert!(matches!(deserialize("not base64!"), Err(Error::BadDump(_))));
+    }
+
+    #[test]
+    fn rejects_unknown_format_versions() {
+        let mut bytes = serde_json::to_vec(&json!({"format": 99, "value": 1})).unwrap();
+        let checksum = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        assert!(matches!(
+            deserialize(&BASE64.encode(bytes)),
+            Err(Error::BadDump(_))
+        ));
+    }
+}
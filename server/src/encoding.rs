@@ -0,0 +1,239 @@
+use crate::{command, frame, tokens::Tokens, Error};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// How values are sent over a connection speaking Fabric,
+/// negotiated per connection with `ENCODING <name>`.
+///
+/// NOTE: Values are stored as JSON whatever the encoding,
+/// which only changes how they're sent to and from clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    /// Values are sent as JSON text
+    #[default]
+    Json,
+    /// Values are sent as MessagePack
+    MessagePack,
+    /// Values are sent as CBOR (RFC 8949)
+    Cbor,
+}
+impl std::str::FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MessagePack),
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(Error::Protocol(format!("Unsupported Encoding {s}"))),
+        }
+    }
+}
+impl Encoding {
+    /// Split a framed request into its command line and the raw payload
+    /// following the first newline, for a SETBLOB, or a SET in a binary encoding.
+    ///
+    /// NOTE: A SET without a newline still takes its value inline as JSON.
+    pub fn split_payload<'a>(&self, request: &'a [u8]) -> (&'a [u8], Option<&'a [u8]>) {
+        let Some(newline) = request.iter().position(|b| *b == b'\n') else {
+            return (request, None);
+        };
+        let (line, value) = (&request[..newline], &request[newline + 1..]);
+
+        let Ok(command) = std::str::from_utf8(line) else {
+            return (request, None);
+        };
+        let (_, command) = frame::split_tag(command.trim_start());
+        let verb = command.split_whitespace().next().unwrap_or_default();
+        let binary_set = *self != Encoding::Json && verb.eq_ignore_ascii_case("SET");
+        if !binary_set && !verb.eq_ignore_ascii_case("SETBLOB") {
+            return (request, None);
+        }
+        (line, Some(value))
+    }
+
+    /// The reply carrying a value asked for, where a binary encoding has
+    /// `VALUE` on a line of its own, followed by the encoded value.
+    pub fn reply(&self, value: &Value) -> Vec<u8> {
+        match self {
+            Encoding::Json => format!("{value}\n").into_bytes(),
+            _ => command::payload_reply(&self.encode(value)),
+        }
+    }
+
+    /// Encode a value.
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        match self {
+            Encoding::Json => value.to_string().into_bytes(),
+            // Writing a JSON value into memory can't fail in either encoding
+            Encoding::MessagePack => {
+                rmp_serde::to_vec(value).expect("JSON values encode as MessagePack")
+            }
+            Encoding::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(value, &mut out).expect("JSON values encode as CBOR");
+                out
+            }
+        }
+    }
+
+    /// Decode a value, nesting objects and arrays at most `max_depth` levels.
+    ///
+    /// NOTE: Anything JSON can't hold, like binary data or keys that
+    /// aren't strings, fails to decode rather than being converted.
+    pub fn decode(&self, bytes: &[u8], max_depth: usize) -> Result<Value, Error> {
+        let too_deep =
+            || Error::LimitExceeded(format!("Value Nests Deeper Than {max_depth} Levels"));
+        let mut rest = bytes;
+        let value = match self {
+            Encoding::Json => return Ok(serde_json::from_slice(bytes)?),
+            Encoding::MessagePack => {
+                let mut decoder = rmp_serde::Deserializer::new(&mut rest);
+                // The decoder counts the value itself as a level too
+                decoder.set_max_depth(max_depth.saturating_add(1));
+                Value::deserialize(&mut decoder).map_err(|e| match e {
+                    rmp_serde::decode::Error::DepthLimitExceeded => too_deep(),
+                    e => Error::BadEncoding(e.to_string()),
+                })?
+            }
+            Encoding::Cbor => ciborium::de::from_reader_with_recursion_limit(&mut rest, max_depth)
+                .map_err(|e| match e {
+                    ciborium::de::Error::RecursionLimitExceeded => too_deep(),
+                    e => Error::BadEncoding(e.to_string()),
+                })?,
+        };
+        if !rest.is_empty() {
+            return Err(Error::BadEncoding(format!(
+                "{} Bytes Left Over After The Value",
+                rest.len()
+            )));
+        }
+        Ok(value)
+    }
+}
+
+/// Parse an `ENCODING <name>` request into the encoding it asks
+/// for, returning `None` when it's not an `ENCODING`.
+pub fn parse(request: &str) -> Option<Result<Encoding, Error>> {
+    let mut tokens = Tokens::new(request);
+    if !tokens.next().ok()??.eq_ignore_ascii_case("ENCODING") {
+        return None;
+    }
+
+    let args = match tokens.all() {
+        Ok(args) => args,
+        Err(e) => return Some(Err(e.into())),
+    };
+    Some(match &args[..] {
+        [name] => name.parse(),
+        _ => Err(Error::WrongArity("ENCODING".to_string())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn round_trips_values() {
+        let value = json!({
+            "prices": [0, 127, 128, 65536, 4294967296u64, u64::MAX, -1, -33, -129, i64::MIN],
+            "ratios": [0.5, -1024.125],
+            "name": "x".repeat(300),
+            "flags": [true, false, null],
+            "nested": {"empty": {}, "list": []},
+        });
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let encoded = encoding.encode(&value);
+            assert_eq!(encoding.decode(&encoded, 8).unwrap(), value, "{encoding:?}");
+        }
+
+        // Numbers take as few bytes as they fit in
+        let value = json!({"a": [1, -1, 1.5]});
+        assert_eq!(
+            hex(&Encoding::MessagePack.encode(&value)),
+            "81a1619301ffcb3ff8000000000000"
+        );
+        assert_eq!(hex(&Encoding::Cbor.encode(&value)), "a16161830120f93e00");
+    }
+
+    #[test]
+    fn decodes_what_other_encoders_send() {
+        // MessagePack 32 bit floats and 8 bit strings, CBOR half floats
+        assert_eq!(
+            Encoding::MessagePack
+                .decode(&[0x92, 0xca, 0x3f, 0xc0, 0, 0, 0xd9, 1, b'a'], 8)
+                .unwrap(),
+            json!([1.5, "a"])
+        );
+        assert_eq!(
+            Encoding::Cbor
+                .decode(&[0x82, 0xf9, 0x3e, 0x00, 0x1a, 0, 0, 0, 1], 8)
+                .unwrap(),
+            json!([1.5, 1])
+        );
+    }
+
+    #[test]
+    fn rejects_what_json_cant_hold() {
+        let rejected = [
+            (Encoding::MessagePack, vec![0xc4, 1, 0]),
+            (Encoding::MessagePack, vec![0x81, 1, 2]),
+            (Encoding::MessagePack, vec![0xc0, 0xc0]),
+            (Encoding::MessagePack, vec![0xdd, 0xff, 0xff, 0xff, 0xff]),
+            (Encoding::Cbor, vec![0x41, 0]),
+            (Encoding::Cbor, vec![0xc1, 0x1a, 0, 0, 0, 1]),
+            (Encoding::Cbor, vec![0x3b, 0xff, 0, 0, 0, 0, 0, 0, 0]),
+        ];
+        for (encoding, bytes) in rejected {
+            assert!(
+                matches!(encoding.decode(&bytes, 8), Err(Error::BadEncoding(_))),
+                "{encoding:?} {bytes:?}"
+            );
+        }
+
+        // Values nest no deeper than the limit
+        assert!(Encoding::Cbor.decode(&[0x81, 0x81, 0x80], 3).is_ok());
+        assert!(matches!(
+            Encoding::Cbor.decode(&[0x81, 0x81, 0x81, 0x80], 3),
+            Err(Error::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn splits_payloads_off_of_requests() {
+        let request = b"#4 set \"a b\"\n\x0a\x01";
+        assert_eq!(
+            Encoding::MessagePack.split_payload(request),
+            (&b"#4 set \"a b\""[..], Some(&b"\x0a\x01"[..]))
+        );
+        assert_eq!(Encoding::Json.split_payload(request), (&request[..], None));
+        assert_eq!(
+            Encoding::Cbor.split_payload(b"SET a 1"),
+            (&b"SET a 1"[..], None)
+        );
+        assert_eq!(
+            Encoding::Cbor.split_payload(b"{\n\"method\": \"set\"}"),
+            (&b"{\n\"method\": \"set\"}"[..], None)
+        );
+
+        // Blobs are raw whatever the encoding
+        assert_eq!(
+            Encoding::Json.split_payload(b"SETBLOB img\n\x89PNG\n"),
+            (&b"SETBLOB img"[..], Some(&b"\x89PNG\n"[..]))
+        );
+
+        assert!(parse("GET encoding").is_none());
+        assert_eq!(parse("encoding CBOR").unwrap().unwrap(), Encoding::Cbor);
+        assert!(matches!(
+            parse("ENCODING bson"),
+            Some(Err(Error::Protocol(_)))
+        ));
+        assert!(matches!(parse("ENCODING"), Some(Err(Error::WrongArity(_)))));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::tokens::TokenError;
 use std::error::Error as StdErrorTrait;
 
 #[derive(Debug)]
@@ -8,6 +9,21 @@ pub enum Error {
     BadDataStructure(serde_json::Error),
     UnsupportedCommand(String),
     InvalidKeyPath(String),
+    CorruptLog(String),
+    InvalidConfig(String),
+    AppendOnlyDisabled,
+    RewriteInProgress,
+    Encryption(String),
+    BadDump(String),
+    KeyExists(String),
+    Tls(String),
+    Protocol(String),
+    WrongArity(String),
+    Syntax(String),
+    LimitExceeded(String),
+    BadEncoding(String),
+    WrongType(String),
+    ExportDisabled,
 }
 impl StdErrorTrait for Error {}
 /// Implement display trait for `Error`
@@ -22,6 +38,70 @@ impl std::fmt::Display for Error {
             Error::InvalidKeyPath(key_path) => {
                 write!(f, "\"{}\" Is Not A Valid Key Path.", key_path)
             }
+            Error::CorruptLog(reason) => write!(f, "Corrupt Append-Only Log: {}", reason),
+            Error::InvalidConfig(reason) => write!(f, "Invalid Config: {}", reason),
+            Error::AppendOnlyDisabled => write!(f, "The Append-Only Log Is Disabled."),
+            Error::BadDump(reason) => write!(f, "Bad Dump: {}", reason),
+            Error::KeyExists(key) => write!(f, "Key: \"{}\" Already Exists.", key),
+            Error::Encryption(reason) => write!(f, "Encryption Error: {}", reason),
+            Error::Tls(reason) => write!(f, "TLS Error: {}", reason),
+            Error::Protocol(reason) => write!(f, "Protocol Error: {}", reason),
+            Error::WrongArity(cmd) => write!(f, "Wrong Number Of Arguments For {}.", cmd),
+            Error::Syntax(reason) => write!(f, "Syntax Error: {}.", reason),
+            Error::LimitExceeded(reason) => write!(f, "Limit Exceeded: {}.", reason),
+            Error::BadEncoding(reason) => write!(f, "Bad Encoding: {}.", reason),
+            Error::WrongType(key) => {
+                write!(f, "Key: \"{}\" Holds A Different Type Of Value.", key)
+            }
+            Error::RewriteInProgress => {
+                write!(f, "An Append-Only Log Rewrite Is Already In Progress.")
+            }
+            Error::ExportDisabled => {
+                write!(f, "EXPORT And IMPORT Are Disabled Without An export_dir.")
+            }
+        }
+    }
+}
+impl Error {
+    /// The code identifying the kind of error in `ERR <code> <message>` replies.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::KeyNotFound(_) => "KEY_NOT_FOUND",
+            Error::IO(_) => "IO",
+            Error::BadDataStructure(_) => "BAD_DATA_STRUCTURE",
+            Error::UnsupportedCommand(_) => "UNSUPPORTED_COMMAND",
+            Error::InvalidKeyPath(_) => "INVALID_KEY_PATH",
+            Error::CorruptLog(_) => "CORRUPT_LOG",
+            Error::InvalidConfig(_) => "INVALID_CONFIG",
+            Error::AppendOnlyDisabled => "APPEND_ONLY_DISABLED",
+            Error::RewriteInProgress => "REWRITE_IN_PROGRESS",
+            Error::Encryption(_) => "ENCRYPTION",
+            Error::BadDump(_) => "BAD_DUMP",
+            Error::KeyExists(_) => "KEY_EXISTS",
+            Error::Tls(_) => "TLS",
+            Error::Protocol(_) => "PROTOCOL",
+            Error::WrongArity(_) => "WRONG_ARITY",
+            Error::Syntax(_) => "SYNTAX",
+            Error::LimitExceeded(_) => "LIMIT_EXCEEDED",
+            Error::BadEncoding(_) => "BAD_ENCODING",
+            Error::WrongType(_) => "WRONG_TYPE",
+            Error::ExportDisabled => "EXPORT_DISABLED",
+        }
+    }
+
+    /// The `ERR <code> <message>` reply sent to clients for the error,
+    /// with the message collapsed onto one line.
+    pub fn reply(&self) -> Vec<u8> {
+        let message = self.to_string();
+        let message: Vec<&str> = message.split_whitespace().collect();
+        format!("ERR {} {}\n", self.code(), message.join(" ")).into_bytes()
+    }
+}
+impl From<TokenError> for Error {
+    fn from(err: TokenError) -> Self {
+        match err {
+            TokenError::Syntax(reason) => Error::Syntax(reason),
+            TokenError::WrongArity(verb) => Error::WrongArity(verb),
         }
     }
 }
@@ -1,11 +1,143 @@
-use crate::Error;
+use crate::{
+    aof::AppendOnlyLog,
+    changes::{Change, Changes},
+    config::Config,
+    pubsub::PubSub,
+    Error,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 /// The data structure store.
 #[derive(Default)]
 pub struct Fabric {
     pub cache: HashMap<String, Value>,
+    /// Opaque binary values, which are only ever stored at top-level keys
+    pub blobs: HashMap<String, Vec<u8>>,
+    /// The log every mutating command is recorded in, if persistence is enabled
+    pub aof: Option<AppendOnlyLog>,
+    /// The caps on what clients can send
+    pub limits: Limits,
+    /// The directory `EXPORT` and `IMPORT` are confined to, if they're enabled
+    pub export_dir: Option<PathBuf>,
+    /// Where every change to a value is announced
+    pub changes: Changes,
+    /// Where messages published to channels are delivered from
+    pub pubsub: PubSub,
+}
+
+/// Caps on clients and what they can send, so no single
+/// client can exhaust the server's memory or starve the others.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// The most clients connected at once
+    pub max_clients: usize,
+    /// How many seconds a client can idle (0 never)
+    pub timeout: u64,
+    /// How many bytes of replies a client can fall behind reading
+    pub client_output_buffer_limit: usize,
+    /// The largest request in bytes
+    pub max_request_size: usize,
+    /// How deeply values can nest objects and arrays
+    pub max_json_depth: usize,
+    /// The largest value in bytes, as JSON or as a blob
+    pub max_value_size: usize,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self::from(&Config::default())
+    }
+}
+impl From<&Config> for Limits {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_clients: config.maxclients,
+            timeout: config.timeout,
+            client_output_buffer_limit: config.client_output_buffer_limit,
+            max_request_size: config.max_request_size,
+            max_json_depth: config.max_json_depth,
+            max_value_size: config.max_value_size,
+        }
+    }
+}
+impl Limits {
+    /// How long a client can idle before it's disconnected, if ever.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.timeout > 0).then(|| Duration::from_secs(self.timeout))
+    }
+
+    /// No caps on what's set at all, for replaying records that were
+    /// within whatever limits applied when they were logged.
+    pub fn unlimited() -> Self {
+        Self {
+            max_request_size: usize::MAX,
+            max_json_depth: usize::MAX,
+            max_value_size: usize::MAX,
+            ..Self::default()
+        }
+    }
+
+    /// Verify a JSON value set at `keys` is within the limits, before it's parsed.
+    ///
+    /// NOTE: Every key below the top-level one nests the value another level
+    /// deeper in the tree, so those count towards the depth of the value too.
+    pub fn check_value(&self, keys: &[&str], value: &str) -> Result<(), Error> {
+        self.check_size(value.len())?;
+        self.check_depth(keys, json_depth(value))
+    }
+
+    /// Verify a value of `len` bytes, however it's encoded, is within the limits.
+    pub fn check_size(&self, len: usize) -> Result<(), Error> {
+        if len > self.max_value_size {
+            return Err(Error::LimitExceeded(format!(
+                "Value Of {} Bytes Is Larger Than {} Bytes",
+                len, self.max_value_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Verify a value nesting `depth` levels deep, set at `keys`, is within the limits.
+    pub fn check_depth(&self, keys: &[&str], depth: usize) -> Result<(), Error> {
+        if keys.len().saturating_sub(1) + depth > self.max_json_depth {
+            return Err(Error::LimitExceeded(format!(
+                "Value Nests Deeper Than {} Levels",
+                self.max_json_depth
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How deeply a parsed value nests objects and arrays.
+fn value_depth(value: &Value) -> usize {
+    match value {
+        Value::Array(values) => 1 + values.iter().map(value_depth).max().unwrap_or(0),
+        Value::Object(map) => 1 + map.values().map(value_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// How deeply a JSON value nests objects and arrays, without parsing it.
+fn json_depth(json: &str) -> usize {
+    let (mut depth, mut max_depth) = (0usize, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for b in json.bytes() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            _ if in_string => {}
+            b'{' | b'[' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max_depth
 }
 
 impl Fabric {
@@ -19,6 +151,9 @@ impl Fabric {
         if keys.is_empty() {
             return Err(Error::InvalidKeyPath("Empty key path".to_string()));
         }
+        if self.blobs.contains_key(keys[0]) {
+            return Err(Error::WrongType(keys[0].to_string()));
+        }
 
         let mut current_value = self
             .cache
@@ -44,12 +179,38 @@ impl Fabric {
             return Err(Error::InvalidKeyPath("Empty key path".to_string()));
         }
 
+        self.limits.check_value(&keys, value)?;
         let parsed_value: Value = serde_json::from_str(value)?;
+        self.insert(keys, parsed_value)
+    }
+
+    /// Set a value that's already parsed in the cache, like one
+    /// decoded from a binary encoding, without going through JSON.
+    pub fn set_value(&mut self, keys: Vec<&str>, value: Value) -> Result<(), Error> {
+        if keys.is_empty() {
+            return Err(Error::InvalidKeyPath("Empty key path".to_string()));
+        }
+
+        self.limits.check_depth(&keys, value_depth(&value))?;
+        self.insert(keys, value)
+    }
+
+    /// Insert a value within the limits at a key path that isn't empty.
+    fn insert(&mut self, keys: Vec<&str>, parsed_value: Value) -> Result<(), Error> {
+        let change = self.is_watched().then(|| Change::Set {
+            path: owned(&keys),
+            value: parsed_value.clone(),
+        });
 
         if keys.len() == 1 {
+            self.blobs.remove(keys[0]);
             self.cache.insert(keys[0].to_string(), parsed_value);
+            self.announce(change);
             return Ok(());
         }
+        if self.blobs.contains_key(keys[0]) {
+            return Err(Error::WrongType(keys[0].to_string()));
+        }
 
         let mut current_value = self
             .cache
@@ -70,17 +231,46 @@ impl Fabric {
             .ok_or_else(|| Error::InvalidKeyPath(keys.join(".")))?
             .insert(final_key.to_string(), parsed_value);
 
+        self.announce(change);
         Ok(())
     }
 
+    /// Merge a JSON merge patch (RFC 7386) into a value in the cache,
+    /// returning the merged value as JSON.
+    ///
+    /// NOTE: Objects in the patch are merged into the value key by key, where a
+    /// `null` removes a key, and anything else replaces the value outright.
+    pub fn merge(&mut self, keys: Vec<&str>, patch: &str) -> Result<String, Error> {
+        self.limits.check_value(&keys, patch)?;
+        let patch: Value = serde_json::from_str(patch)?;
+
+        // A missing value is merged into as if it were `null`, but a blob isn't JSON
+        let mut value = match self.get(keys.clone()) {
+            Err(e @ Error::WrongType(_)) => return Err(e),
+            value => value.unwrap_or(Value::Null),
+        };
+        merge_patch(&mut value, patch);
+
+        let value = value.to_string();
+        self.set(keys, &value)?;
+        Ok(value)
+    }
+
     /// Remove a key/value pair in the cache.
     pub fn remove(&mut self, keys: Vec<&str>) -> Result<(), Error> {
         if keys.is_empty() {
             return Err(Error::InvalidKeyPath("Empty key path".to_string()));
         }
 
+        let change = self
+            .is_watched()
+            .then(|| Change::Remove { path: owned(&keys) });
+
         if keys.len() == 1 {
-            self.cache.remove(keys[0]);
+            let blob = self.blobs.remove(keys[0]);
+            if self.cache.remove(keys[0]).is_some() || blob.is_some() {
+                self.announce(change);
+            }
             return Ok(());
         }
 
@@ -102,8 +292,93 @@ impl Fabric {
             .and_then(|obj| obj.remove(*final_key))
             .ok_or_else(|| Error::InvalidKeyPath(keys.join(".")))?;
 
+        self.announce(change);
+        Ok(())
+    }
+
+    /// Get an opaque binary value from the cache.
+    pub fn get_blob(&self, key: &str) -> Result<&[u8], Error> {
+        match self.blobs.get(key) {
+            Some(blob) => Ok(blob),
+            None if self.cache.contains_key(key) => Err(Error::WrongType(key.to_string())),
+            None => Err(Error::KeyNotFound(key.to_string())),
+        }
+    }
+
+    /// Set an opaque binary value at a top-level key in the cache.
+    ///
+    /// NOTE: Like a SET, this overwrites whatever value the key had, where
+    /// subscribers see the key set to the blob's base64 encoding.
+    pub fn set_blob(&mut self, key: &str, blob: Vec<u8>) -> Result<(), Error> {
+        if key.is_empty() || key.contains('.') {
+            return Err(Error::InvalidKeyPath(key.to_string()));
+        }
+        if blob.len() > self.limits.max_value_size {
+            return Err(Error::LimitExceeded(format!(
+                "Blob Of {} Bytes Is Larger Than {} Bytes",
+                blob.len(),
+                self.limits.max_value_size
+            )));
+        }
+
+        let change = self.is_watched().then(|| Change::Set {
+            path: vec![key.to_string()],
+            value: Value::String(BASE64.encode(&blob)),
+        });
+        self.cache.remove(key);
+        self.blobs.insert(key.to_string(), blob);
+        self.announce(change);
         Ok(())
     }
+
+    /// Whether anyone is subscribed to changes or to channels,
+    /// so changes are worth describing.
+    fn is_watched(&self) -> bool {
+        self.changes.is_watched() || self.pubsub.is_watched()
+    }
+
+    /// Announce a change to its subscribers, and as a keyspace notification.
+    fn announce(&self, change: Option<Change>) {
+        if let Some(change) = &change {
+            self.pubsub.notify(change);
+        }
+        self.changes.publish(change);
+    }
+
+    /// Record a mutating command in the append-only log, if there is one.
+    pub fn log(&mut self, record: &str) -> Result<(), Error> {
+        match self.aof.as_mut() {
+            Some(aof) => aof.append(record),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Own the keys of a key path.
+fn owned(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|key| key.to_string()).collect()
+}
+
+/// Apply a JSON merge patch to a value.
+fn merge_patch(value: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *value = patch;
+        return;
+    };
+    if !value.is_object() {
+        *value = Value::Object(serde_json::Map::new());
+    }
+
+    let Value::Object(object) = value else {
+        unreachable!()
+    };
+    for (key, patch) in patch {
+        if patch.is_null() {
+            object.remove(&key);
+        } else {
+            merge_patch(object.entry(key).or_insert(Value::Null), patch);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -159,6 +434,220 @@ mod tests {
         assert_eq!(test_structs_expected, test_structs_actual);
     }
 
+    #[test]
+    fn can_merge_patches_into_values() {
+        let mut fabric = Fabric::new();
+        fabric
+            .set(
+                vec!["user"],
+                "{\"name\": \"ops\", \"tags\": [1], \"billing\": {\"city\": \"Miami\"}}",
+            )
+            .unwrap();
+
+        let merged = fabric
+            .merge(
+                vec!["user"],
+                "{\"tags\": [2], \"name\": null, \"billing\": {\"state\": \"Florida\"}}",
+            )
+            .unwrap();
+        let expected = serde_json::json!({
+            "tags": [2],
+            "billing": {"city": "Miami", "state": "Florida"},
+        });
+        assert_eq!(serde_json::from_str::<Value>(&merged).unwrap(), expected);
+        assert_eq!(fabric.get(vec!["user"]).unwrap(), expected);
+
+        // Missing values are created, and anything but an object replaces the value
+        fabric.merge(vec!["new", "a"], "{\"b\": 1}").unwrap();
+        assert_eq!(fabric.get(vec!["new", "a", "b"]).unwrap(), 1);
+        fabric.merge(vec!["new"], "5").unwrap();
+        assert_eq!(fabric.get(vec!["new"]).unwrap(), 5);
+    }
+
+    #[test]
+    fn keeps_blobs_apart_from_values() {
+        let mut fabric = Fabric::new();
+        fabric.set(vec!["img"], "{\"width\": 1}").unwrap();
+        fabric.set_blob("img", vec![0, 159, 146, 150]).unwrap();
+
+        // A key holds either a blob or a value, whichever was set last
+        assert_eq!(fabric.get_blob("img").unwrap(), [0, 159, 146, 150]);
+        assert!(matches!(fabric.get(vec!["img"]), Err(Error::WrongType(_))));
+        assert!(matches!(
+            fabric.set(vec!["img", "width"], "2"),
+            Err(Error::WrongType(_))
+        ));
+        assert!(matches!(
+            fabric.merge(vec!["img"], "{\"width\": 2}"),
+            Err(Error::WrongType(_))
+        ));
+        assert_eq!(fabric.get_blob("img").unwrap(), [0, 159, 146, 150]);
+        fabric.set(vec!["img"], "null").unwrap();
+        assert!(matches!(fabric.get_blob("img"), Err(Error::WrongType(_))));
+
+        fabric.set_blob("img", Vec::new()).unwrap();
+        fabric.remove(vec!["img"]).unwrap();
+        assert!(matches!(fabric.get_blob("img"), Err(Error::KeyNotFound(_))));
+
+        // Blobs only live at top-level keys, and are no larger than values
+        assert!(matches!(
+            fabric.set_blob("a.b", Vec::new()),
+            Err(Error::InvalidKeyPath(_))
+        ));
+        fabric.limits.max_value_size = 2;
+        assert!(matches!(
+            fabric.set_blob("img", vec![0; 3]),
+            Err(Error::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn announces_changes_to_values() {
+        let mut fabric = Fabric::new();
+        fabric.set(vec!["unwatched"], "1").unwrap();
+
+        let mut changes = fabric.changes.subscribe();
+        fabric
+            .set(vec!["users", "alice"], "{\"score\": 1}")
+            .unwrap();
+        fabric.remove(vec!["users", "alice", "score"]).unwrap();
+        fabric.remove(vec!["nobody"]).unwrap();
+        fabric.remove(vec!["unwatched"]).unwrap();
+        fabric.set_blob("avatar", vec![1, 2, 3]).unwrap();
+        fabric.remove(vec!["avatar"]).unwrap();
+
+        let path = |keys: &[&str]| owned(keys);
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            Change::Set {
+                path: path(&["users", "alice"]),
+                value: serde_json::json!({"score": 1})
+            }
+        );
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            Change::Remove {
+                path: path(&["users", "alice", "score"])
+            }
+        );
+        // Removing what wasn't there isn't a change
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            Change::Remove {
+                path: path(&["unwatched"])
+            }
+        );
+        // Blobs are seen base64 encoded, like they're sent over lines
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            Change::Set {
+                path: path(&["avatar"]),
+                value: serde_json::json!("AQID")
+            }
+        );
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            Change::Remove {
+                path: path(&["avatar"])
+            }
+        );
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn notifies_keyspace_of_changes_and_their_ancestors() {
+        let mut fabric = Fabric::new();
+        let mut trades = fabric.pubsub.subscription();
+        trades.psubscribe("__keyspace__:strategies.*.open_trade");
+        let mut strategies = fabric.pubsub.subscription();
+        strategies.subscribe("__keyspace__:strategies");
+        strategies.psubscribe("__keyspace__:strategies.*");
+        let mut open_trade = fabric.pubsub.subscription();
+        open_trade.subscribe("__keyspace__:strategies.42.open_trade");
+
+        fabric
+            .set(vec!["strategies", "42", "open_trade"], "{\"qty\": 1}")
+            .unwrap();
+        fabric.remove(vec!["strategies", "42"]).unwrap();
+        fabric
+            .set(vec!["strategies", "7"], "{\"open_trade\": null}")
+            .unwrap();
+
+        let notification = |channel: &str, pattern: Option<&str>, message: &str| {
+            Some(crate::pubsub::Message {
+                channel: format!("__keyspace__:{channel}"),
+                pattern: pattern.map(|pattern| format!("__keyspace__:{pattern}")),
+                message: message.to_string(),
+            })
+        };
+        let set = "set strategies.42.open_trade";
+        assert_eq!(
+            trades.recv().await,
+            notification(
+                "strategies.42.open_trade",
+                Some("strategies.*.open_trade"),
+                set
+            )
+        );
+
+        // Ancestors are notified too, where a pattern is only notified once
+        assert_eq!(
+            strategies.recv().await,
+            notification("strategies", None, set)
+        );
+        assert_eq!(
+            strategies.recv().await,
+            notification("strategies.42.open_trade", Some("strategies.*"), set)
+        );
+        let remove = "remove strategies.42";
+        assert_eq!(
+            strategies.recv().await,
+            notification("strategies", None, remove)
+        );
+        assert_eq!(
+            strategies.recv().await,
+            notification("strategies.42", Some("strategies.*"), remove)
+        );
+
+        // So are channels below, with patterns that could only match below
+        // the key path notified on its channel
+        assert_eq!(
+            trades.recv().await,
+            notification("strategies.42", Some("strategies.*.open_trade"), remove)
+        );
+        assert_eq!(
+            trades.recv().await,
+            notification(
+                "strategies.7",
+                Some("strategies.*.open_trade"),
+                "set strategies.7"
+            )
+        );
+        for message in [set, remove] {
+            assert_eq!(
+                open_trade.recv().await,
+                notification("strategies.42.open_trade", None, message)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn notifies_keyspace_of_blobs() {
+        let mut fabric = Fabric::new();
+        let mut avatars = fabric.pubsub.subscription();
+        avatars.subscribe("__keyspace__:avatar");
+
+        fabric.set_blob("avatar", vec![1, 2, 3]).unwrap();
+        fabric.remove(vec!["avatar"]).unwrap();
+
+        for message in ["set avatar", "remove avatar"] {
+            assert_eq!(
+                avatars.recv().await.map(|message| message.message),
+                Some(message.to_string())
+            );
+        }
+    }
+
     #[test]
     fn can_remove_values() {
         let mut fabric = Fabric::new();
@@ -195,6 +684,36 @@ mod tests {
         assert!(fabric.get(vec!["nonexistent"]).is_err());
     }
 
+    #[test]
+    fn enforces_value_limits() {
+        let mut fabric = Fabric::new();
+        fabric.limits.max_value_size = 16;
+        fabric.limits.max_json_depth = 2;
+
+        // Brackets in strings don't nest anything
+        fabric.set(vec!["a"], r#"{"b":["[[["]}"#).unwrap();
+        fabric.set(vec!["a"], r#"["\"[[[",[]]"#).unwrap();
+        assert!(matches!(
+            fabric.set(vec!["a"], "[[[1]]]"),
+            Err(Error::LimitExceeded(_))
+        ));
+        assert!(matches!(
+            fabric.set(vec!["a"], &format!("\"{}\"", "x".repeat(16))),
+            Err(Error::LimitExceeded(_))
+        ));
+
+        // Setting a nested key nests the value that much deeper in the tree
+        fabric.set(vec!["b", "c"], "[1]").unwrap();
+        assert!(matches!(
+            fabric.set(vec!["b", "c", "d"], "[1]"),
+            Err(Error::LimitExceeded(_))
+        ));
+        assert!(matches!(
+            fabric.set_value(vec!["b", "c"], serde_json::json!([[1]])),
+            Err(Error::LimitExceeded(_))
+        ));
+    }
+
     #[test]
     fn can_get_nested_values_with_special_keys() {
         let mut fabric = Fabric::new();
@@ -0,0 +1,253 @@
+use crate::{shutdown::SHUTDOWN, stats::STATS, Error};
+use std::{future::Future, sync::atomic::Ordering, time::Duration};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// How requests and responses are delimited on a connection,
+/// negotiated per connection with `PROTOCOL <version>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// Version 1, every request and response is a single line
+    Lines,
+    /// Version 2, every request and response is prefixed with its
+    /// length as a 4 byte big-endian integer, so it can span lines
+    LengthPrefixed,
+}
+impl Framing {
+    /// Initialize the framing for a protocol version.
+    pub fn from_version(version: &str) -> Option<Self> {
+        match version.trim() {
+            "1" => Some(Framing::Lines),
+            "2" => Some(Framing::LengthPrefixed),
+            _ => None,
+        }
+    }
+
+    /// The protocol version of the framing.
+    pub fn version(&self) -> u8 {
+        match self {
+            Framing::Lines => 1,
+            Framing::LengthPrefixed => 2,
+        }
+    }
+
+    /// Read the next request, of at most `max_len` bytes, returning
+    /// `None` once the client disconnects.
+    ///
+    /// NOTE: Requests aren't checked to be UTF-8 here, since a request
+    /// that isn't is answered with an error rather than ending the connection.
+    pub async fn read<R: AsyncBufRead + Unpin>(
+        &self,
+        reader: &mut R,
+        max_len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            Framing::Lines => read_line(reader, max_len).await,
+            Framing::LengthPrefixed => {
+                let mut len = [0; 4];
+                match reader.read_exact(&mut len).await {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                }
+
+                let len = u32::from_be_bytes(len) as usize;
+                if len > max_len {
+                    return Err(Error::LimitExceeded(format!(
+                        "Request Of {len} Bytes Is Larger Than {max_len} Bytes"
+                    )));
+                }
+
+                read_bytes(reader, len).await.map(Some)
+            }
+        }
+    }
+
+    /// Encode a response, which is always newline terminated
+    /// when sent as a line and never when sent as a frame.
+    pub fn encode(&self, response: &[u8]) -> Vec<u8> {
+        let response = response.strip_suffix(b"\n").unwrap_or(response);
+        let mut out = Vec::with_capacity(response.len() + 4);
+        match self {
+            Framing::Lines => {
+                out.extend_from_slice(response);
+                out.push(b'\n');
+            }
+            Framing::LengthPrefixed => {
+                out.extend_from_slice(&(response.len() as u32).to_be_bytes());
+                out.extend_from_slice(response);
+            }
+        }
+        out
+    }
+}
+
+/// Read a line, newline included, of at most `max_len` bytes
+/// (not counting the newline), returning `None` at the end of the stream.
+///
+/// NOTE: Reading stops as soon as a line is too long, so a client
+/// that never sends a newline can't make the server buffer forever.
+pub async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = Vec::new();
+    let limit = max_len.saturating_add(1) as u64;
+    if reader.take(limit).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.len() > max_len && line.last() != Some(&b'\n') {
+        return Err(Error::LimitExceeded(format!(
+            "Request Is Larger Than {max_len} Bytes"
+        )));
+    }
+    Ok(Some(line))
+}
+
+/// Read exactly `len` bytes, failing if the stream ends first.
+///
+/// NOTE: The buffer grows as the bytes arrive rather than being allocated
+/// up front, so a client can't make the server allocate a whole request
+/// just by claiming it's that large.
+pub async fn read_bytes<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes).await?;
+    if bytes.len() < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Wait on a read from a client, giving up once it has idled for longer
+/// than the timeout, if there is one, or once the server is shutting down.
+///
+/// NOTE: Clients are only ever given up on between commands, so
+/// any command already running is finished and answered first.
+pub async fn until_idle_or_shutdown<F: Future>(
+    timeout: Option<Duration>,
+    read: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        read = until_idle(timeout, read) => read,
+        _ = SHUTDOWN.requested() => None,
+    }
+}
+
+/// Wait on a read from a client, counting it as idled out on the timeout.
+async fn until_idle<F: Future>(timeout: Option<Duration>, read: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, read).await {
+            Ok(read) => Some(read),
+            Err(_) => {
+                STATS.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        },
+        None => Some(read.await),
+    }
+}
+
+/// Split the request ID off of a request tagged with one, as `#<id> <request>`.
+///
+/// NOTE: No command starts with `#`, so untagged requests are left as is.
+pub fn split_tag(request: &str) -> (Option<&str>, &str) {
+    match request.strip_prefix('#') {
+        Some(tagged) => {
+            let (id, request) = tagged
+                .split_once(char::is_whitespace)
+                .unwrap_or((tagged, ""));
+            (Some(id), request.trim_start())
+        }
+        None => (None, request),
+    }
+}
+
+/// Tag a response with the ID of the request it answers, if it had one,
+/// so clients can match responses to requests without relying on order.
+pub fn tag(id: Option<&str>, response: &[u8]) -> Vec<u8> {
+    match id {
+        Some(id) => [format!("#{id} ").as_bytes(), response].concat(),
+        None => response.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips_frames_spanning_lines() {
+        let framing = Framing::LengthPrefixed;
+        let mut out = framing.encode(b"{\n  \"a\": 1\n}\n");
+        out.extend(framing.encode(b"OK"));
+        assert_eq!(&out[..4], &[0, 0, 0, 12]);
+
+        let mut input = &out[..];
+        assert_eq!(
+            framing.read(&mut input, 64).await.unwrap().as_deref(),
+            Some(&b"{\n  \"a\": 1\n}"[..])
+        );
+        assert_eq!(
+            framing.read(&mut input, 64).await.unwrap().as_deref(),
+            Some(&b"OK"[..])
+        );
+        assert_eq!(framing.read(&mut input, 64).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_truncated_frames() {
+        let mut input: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+        assert!(matches!(
+            Framing::LengthPrefixed.read(&mut input, 64).await,
+            Err(Error::LimitExceeded(_))
+        ));
+
+        let mut input: &[u8] = &[0, 0, 0, 5, b'G', b'E'];
+        assert!(Framing::LengthPrefixed.read(&mut input, 64).await.is_err());
+
+        // Claiming a huge frame without sending it costs nothing up front
+        let mut input: &[u8] = &[0x7f, 0xff, 0xff, 0xff, b'G', b'E'];
+        assert!(Framing::LengthPrefixed
+            .read(&mut input, usize::MAX)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn caps_line_length() {
+        let mut input: &[u8] = b"GET a\nGET b";
+        assert_eq!(
+            read_line(&mut input, 5).await.unwrap().as_deref(),
+            Some(&b"GET a\n"[..])
+        );
+        assert_eq!(
+            read_line(&mut input, 5).await.unwrap().as_deref(),
+            Some(&b"GET b"[..])
+        );
+        assert_eq!(read_line(&mut input, 5).await.unwrap(), None);
+
+        let mut input: &[u8] = b"GET abc\n";
+        assert!(matches!(
+            read_line(&mut input, 5).await,
+            Err(Error::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn splits_and_tags_request_ids() {
+        assert_eq!(split_tag("#7 GET user"), (Some("7"), "GET user"));
+        assert_eq!(split_tag("#abc"), (Some("abc"), ""));
+        assert_eq!(split_tag("GET #7"), (None, "GET #7"));
+
+        assert_eq!(tag(Some("7"), b"OK\n"), b"#7 OK\n");
+        assert_eq!(tag(None, b"OK\n"), b"OK\n");
+    }
+
+    #[test]
+    fn terminates_lines() {
+        assert_eq!(Framing::Lines.encode(b"OK"), b"OK\n");
+        assert_eq!(Framing::Lines.encode(b"OK\n"), b"OK\n");
+    }
+}
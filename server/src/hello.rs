@@ -0,0 +1,137 @@
+use crate::{frame::Framing, tokens::Tokens, Error};
+use serde_json::{json, Value};
+
+/// The commands a connection speaking Fabric can send.
+pub const COMMANDS: &[&str] = &[
+    "GET",
+    "SET",
+    "REMOVE",
+    "GETBLOB",
+    "SETBLOB",
+    "BGREWRITEAOF",
+    "DUMP",
+    "RESTORE",
+    "EXPORT",
+    "IMPORT",
+    "STATS",
+    "SHUTDOWN",
+    "PUBLISH",
+    "SUBSCRIBE",
+    "PSUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "PROTOCOL",
+    "ENCODING",
+    "HELLO",
+];
+
+/// The protocol features every connection supports.
+const FEATURES: &[&str] = &[
+    "framing",
+    "request-ids",
+    "json-rpc",
+    "resp",
+    "msgpack",
+    "cbor",
+];
+
+/// What a client is told about the server and its
+/// connection to it in response to `HELLO`.
+pub struct Hello {
+    /// The protocol version in use once the handshake is done
+    pub framing: Framing,
+    /// The ID the server gave the connection
+    pub connection_id: u64,
+    /// Whether the connection is over TLS
+    pub tls: bool,
+    /// Whether the server records writes in an append-only log
+    pub appendonly: bool,
+}
+impl Hello {
+    /// The reply to `HELLO`, as a JSON object.
+    pub fn reply(&self) -> Value {
+        let mut features = FEATURES.to_vec();
+        if self.tls {
+            features.push("tls");
+        }
+        if self.appendonly {
+            features.push("append-only");
+        }
+
+        json!({
+            "server": "fabric-cache",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": self.framing.version(),
+            "id": self.connection_id,
+            "features": features,
+            "commands": COMMANDS,
+        })
+    }
+}
+
+/// Parse a `HELLO [version]` request into the protocol version
+/// it asks for, if any, returning `None` when it's not a `HELLO`.
+pub fn parse(request: &str) -> Option<Result<Option<Framing>, Error>> {
+    let mut tokens = Tokens::new(request);
+    if !tokens.next().ok()??.eq_ignore_ascii_case("HELLO") {
+        return None;
+    }
+
+    let args = match tokens.all() {
+        Ok(args) => args,
+        Err(e) => return Some(Err(e.into())),
+    };
+    Some(match &args[..] {
+        [] => Ok(None),
+        [version] => Framing::from_version(version)
+            .map(Some)
+            .ok_or_else(|| Error::Protocol(format!("Unsupported Version {version}"))),
+        _ => Err(Error::WrongArity("HELLO".to_string())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hello_with_an_optional_version() {
+        assert!(parse("GET hello").is_none());
+        assert!(matches!(parse("HELLO"), Some(Ok(None))));
+        assert!(matches!(
+            parse("hello 2"),
+            Some(Ok(Some(Framing::LengthPrefixed)))
+        ));
+        assert!(matches!(parse("HELLO 9"), Some(Err(Error::Protocol(_)))));
+        assert!(matches!(
+            parse("HELLO 1 2"),
+            Some(Err(Error::WrongArity(_)))
+        ));
+    }
+
+    #[test]
+    fn describes_server_and_connection() {
+        let hello = Hello {
+            framing: Framing::LengthPrefixed,
+            connection_id: 7,
+            tls: false,
+            appendonly: true,
+        };
+        let reply = hello.reply();
+        assert_eq!(reply["protocol"], json!(2));
+        assert_eq!(reply["id"], json!(7));
+        assert_eq!(
+            reply["features"],
+            json!([
+                "framing",
+                "request-ids",
+                "json-rpc",
+                "resp",
+                "msgpack",
+                "cbor",
+                "append-only"
+            ])
+        );
+        assert_eq!(reply["commands"].as_array().unwrap().len(), COMMANDS.len());
+    }
+}
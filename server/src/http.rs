@@ -0,0 +1,523 @@
+use crate::{command::Command, frame, listener::Connection, tokens, Error, ThreadSafeFabric};
+use serde_json::json;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Where values are served from, followed by their key path.
+const KEYS_PREFIX: &str = "/v1/keys/";
+/// The longest request line or header, in bytes.
+const MAX_LINE_LEN: usize = 8 * 1024;
+/// The most headers a request can have.
+const MAX_HEADERS: usize = 100;
+
+/// The request line and headers of an HTTP request.
+#[derive(Debug, PartialEq)]
+pub struct Head {
+    pub method: String,
+    pub target: String,
+    /// Every header, with its name in lowercase
+    pub headers: Vec<(String, String)>,
+    /// The length of the body following the headers
+    pub content_length: usize,
+    /// Whether the client waits for `100 Continue` before sending the body
+    pub expect_continue: bool,
+    /// Whether the connection stays open after the response
+    pub keep_alive: bool,
+}
+impl Head {
+    /// The value of a header, by its lowercase name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP response, with a JSON body if any.
+#[derive(Debug)]
+pub struct Response {
+    status: u16,
+    body: Option<String>,
+    /// The entity tag of the value in the body, if it's one
+    etag: Option<String>,
+}
+impl Response {
+    fn json(status: u16, body: String) -> Self {
+        Response {
+            status,
+            body: Some(body),
+            etag: None,
+        }
+    }
+
+    fn no_content() -> Self {
+        Response {
+            status: 204,
+            body: None,
+            etag: None,
+        }
+    }
+
+    /// The response for a value, tagged with an ETag derived from it, or a
+    /// `304 Not Modified` when that's among the tags in `if_none_match`.
+    ///
+    /// NOTE: Values have no versions, so the tag is a hash of the value itself,
+    /// which changes whenever the value does, and only then.
+    fn value(value: String, if_none_match: Option<&str>) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, value.as_bytes());
+        let hex: String = digest.as_ref()[..16]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let etag = format!("\"{hex}\"");
+
+        let cached = if_none_match.is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        });
+        Response {
+            status: if cached { 304 } else { 200 },
+            body: (!cached).then_some(value),
+            etag: Some(etag),
+        }
+    }
+
+    /// The response for an error, with its code and message as JSON.
+    pub fn error(status: u16, code: &str, message: &str) -> Self {
+        let body = json!({ "error": { "code": code, "message": message } });
+        Response::json(status, body.to_string())
+    }
+
+    pub fn encode(&self, keep_alive: bool) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if let Some(body) = &self.body {
+            head.push_str("Content-Type: application/json\r\n");
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        if let Some(etag) = &self.etag {
+            head.push_str(&format!("ETag: {etag}\r\n"));
+        }
+        if self.status == 405 {
+            head.push_str("Allow: GET, PUT, PATCH, DELETE\r\n");
+        }
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        let mut out = head.into_bytes();
+        if let Some(body) = &self.body {
+            out.extend_from_slice(body.as_bytes());
+        }
+        out
+    }
+}
+impl From<Error> for Response {
+    fn from(e: Error) -> Self {
+        let status = match e {
+            Error::KeyNotFound(_) => 404,
+            Error::InvalidKeyPath(_)
+            | Error::BadDataStructure(_)
+            | Error::Protocol(_)
+            | Error::Syntax(_)
+            | Error::WrongType(_) => 400,
+            Error::LimitExceeded(_) => 413,
+            _ => 500,
+        };
+        Response::error(status, e.code(), &e.to_string())
+    }
+}
+
+/// The reason phrase for a status code.
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// The response turning a client away when the server is full.
+pub fn reject(e: &Error) -> Vec<u8> {
+    Response::error(503, e.code(), &e.to_string()).encode(false)
+}
+
+/// Serve a client of the HTTP gateway until it disconnects, or
+/// asks for the connection to be closed.
+pub async fn serve(socket: Box<dyn Connection>, fabric: &ThreadSafeFabric) -> Result<(), Error> {
+    let limits = fabric.read().await.limits;
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let read = frame::until_idle_or_shutdown(limits.idle_timeout(), read_head(&mut reader));
+        let head = match read.await {
+            Some(Ok(Some(head))) => head,
+            // Client disconnected or idled out
+            Some(Ok(None)) | None => break,
+            Some(Err(e)) => return hang_up(&mut writer, e).await,
+        };
+        if head.content_length > limits.max_request_size {
+            let e = Error::LimitExceeded(format!(
+                "Request Is Larger Than {} Bytes",
+                limits.max_request_size
+            ));
+            return hang_up(&mut writer, e).await;
+        }
+
+        if head.expect_continue {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        let read = frame::read_bytes(&mut reader, head.content_length);
+        let body = match frame::until_idle_or_shutdown(limits.idle_timeout(), read).await {
+            Some(body) => body?,
+            // Client idled out halfway through the body
+            None => break,
+        };
+
+        let response = handle(&head, body, fabric).await;
+        writer.write_all(&response.encode(head.keep_alive)).await?;
+        if !head.keep_alive {
+            break;
+        }
+    }
+
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Answer a request that leaves the stream out of sync with an error, and hang up.
+async fn hang_up<W: AsyncWrite + Unpin>(writer: &mut W, e: Error) -> Result<(), Error> {
+    writer.write_all(&Response::from(e).encode(false)).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Read the request line and headers of the next request,
+/// returning `None` at the end of the stream.
+///
+/// NOTE: Only bodies with a `Content-Length` are supported, not chunked ones.
+pub async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Head>, Error> {
+    // Blank lines before a request are ignored, like after a body sent with a stray newline
+    let request_line = loop {
+        match frame::read_line(reader, MAX_LINE_LEN).await? {
+            Some(line) if line.trim_ascii().is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+    let request_line = String::from_utf8_lossy(&request_line);
+    let (method, target, version) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, target, version] => (method, target, version),
+        _ => return Err(Error::Protocol("Malformed Request Line".to_string())),
+    };
+    let mut head = Head {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers: Vec::new(),
+        content_length: 0,
+        expect_continue: false,
+        // Only HTTP/1.1 connections are kept alive by default
+        keep_alive: match version {
+            "HTTP/1.1" => true,
+            "HTTP/1.0" => false,
+            _ => return Err(Error::Protocol(format!("Unsupported Version {version}"))),
+        },
+    };
+
+    for headers in 0.. {
+        let Some(line) = frame::read_line(reader, MAX_LINE_LEN).await? else {
+            return Err(Error::Protocol("Request Ended Before Its Body".to_string()));
+        };
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(Error::LimitExceeded(format!(
+                "Request Has More Than {MAX_HEADERS} Headers"
+            )));
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(Error::Protocol("Malformed Header".to_string()));
+        };
+        let (name, value) = (name.to_ascii_lowercase(), value.trim());
+        match name.as_str() {
+            "content-length" => {
+                head.content_length = value
+                    .parse()
+                    .map_err(|_| Error::Protocol("Malformed Content-Length".to_string()))?
+            }
+            "transfer-encoding" => {
+                return Err(Error::Protocol(
+                    "Transfer-Encoding Is Not Supported, Send A Content-Length".to_string(),
+                ))
+            }
+            "expect" => head.expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            "connection" if value.eq_ignore_ascii_case("close") => head.keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => head.keep_alive = true,
+            _ => {}
+        }
+        head.headers.push((name, value.to_string()));
+    }
+
+    Ok(Some(head))
+}
+
+/// Handle a request by mapping it onto the value at its key path.
+async fn handle(head: &Head, body: Vec<u8>, fabric: &ThreadSafeFabric) -> Response {
+    // The query string doesn't mean anything (yet)
+    let path = head.target.split('?').next().unwrap_or_default();
+    let Some(path) = path.strip_prefix(KEYS_PREFIX) else {
+        let message = format!("No Such Route {path}");
+        return Response::error(404, "NOT_FOUND", &message);
+    };
+    let key = match key_path(path) {
+        Ok(key) => key,
+        Err(e) => return e.into(),
+    };
+
+    let handled = match head.method.as_str() {
+        "GET" => Command::Get { key }.handle(fabric).await.map(|value| {
+            let value = String::from_utf8_lossy(&value).trim().to_string();
+            Response::value(value, head.header("if-none-match"))
+        }),
+        "PUT" => match String::from_utf8(body) {
+            Ok(value) => Command::Set { key, value }
+                .handle(fabric)
+                .await
+                .map(|_| Response::no_content()),
+            Err(_) => Err(Error::Protocol("Body Is Not UTF-8".to_string())),
+        },
+        "PATCH" => match String::from_utf8(body) {
+            Ok(patch) => merge(fabric, &key, &patch)
+                .await
+                .map(|merged| Response::json(200, merged)),
+            Err(_) => Err(Error::Protocol("Body Is Not UTF-8".to_string())),
+        },
+        "DELETE" => remove(fabric, &key).await.map(|_| Response::no_content()),
+        method => {
+            let message = format!("Method {method} Is Not Allowed");
+            return Response::error(405, "METHOD_NOT_ALLOWED", &message);
+        }
+    };
+    handled.unwrap_or_else(Response::from)
+}
+
+/// Merge a JSON merge patch into the value at a key, recording
+/// the merged value in the append-only log as a plain SET.
+async fn merge(fabric: &ThreadSafeFabric, key: &str, patch: &str) -> Result<String, Error> {
+    let mut fabric = fabric.write().await;
+    let merged = fabric.merge(key.split('.').collect(), patch)?;
+    fabric.log(&format!("SET {} {merged}", tokens::quote(key)))?;
+    Ok(merged)
+}
+
+/// Remove the value at a key, where a missing one isn't found.
+async fn remove(fabric: &ThreadSafeFabric, key: &str) -> Result<(), Error> {
+    let mut fabric = fabric.write().await;
+    let keys: Vec<&str> = key.split('.').collect();
+    if let Err(Error::InvalidKeyPath(_)) = fabric.get(keys.clone()) {
+        return Err(Error::KeyNotFound(key.to_string()));
+    }
+    fabric.remove(keys)?;
+    fabric.log(&format!("REMOVE {}", tokens::quote(key)))
+}
+
+/// Turn the segments of a URL path, like `user%201/name`, into a key path.
+///
+/// NOTE: Segments are joined with `.`, so like the segments of a
+/// JSON-RPC `path` they can't be empty or contain a `.` of their own.
+fn key_path(path: &str) -> Result<String, Error> {
+    let keys = path
+        .split('/')
+        .map(|segment| percent_decode(segment).filter(|key| !key.is_empty() && !key.contains('.')))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| Error::InvalidKeyPath(path.to_string()))?;
+    Ok(keys.join("."))
+}
+
+/// Decode the `%XX` escapes in a URL path segment.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Fabric;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn head(method: &str, target: &str) -> Head {
+        Head {
+            method: method.into(),
+            target: target.into(),
+            headers: Vec::new(),
+            content_length: 0,
+            expect_continue: false,
+            keep_alive: true,
+        }
+    }
+
+    async fn request(
+        method: &str,
+        target: &str,
+        body: &str,
+        fabric: &ThreadSafeFabric,
+    ) -> (u16, Value) {
+        let response = handle(&head(method, target), body.into(), fabric).await;
+        let body = response
+            .body
+            .map_or(Value::Null, |body| serde_json::from_str(&body).unwrap());
+        (response.status, body)
+    }
+
+    #[tokio::test]
+    async fn reads_request_heads() {
+        let mut input: &[u8] = b"\r\nPUT /v1/keys/user HTTP/1.1\r\nHost: x\r\ncontent-length: 2\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n{}";
+        let head = read_head(&mut input).await.unwrap().unwrap();
+        assert_eq!(head.method, "PUT");
+        assert_eq!(head.target, "/v1/keys/user");
+        assert_eq!(head.content_length, 2);
+        assert!(head.expect_continue);
+        assert!(!head.keep_alive);
+        assert_eq!(head.header("host"), Some("x"));
+        assert_eq!(input, b"{}");
+
+        let mut input: &[u8] = b"GET / HTTP/1.0\r\n\r\n";
+        assert!(!read_head(&mut input).await.unwrap().unwrap().keep_alive);
+
+        for input in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / HTTP/2\r\n\r\n",
+            b"GET / HTTP/1.1\r\nBroken\r\n\r\n",
+            b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n",
+        ] {
+            let mut input = input;
+            assert!(read_head(&mut input).await.is_err());
+        }
+    }
+
+    #[test]
+    fn decodes_key_paths() {
+        assert_eq!(key_path("user%201/name").unwrap(), "user 1.name");
+        assert_eq!(key_path("caf%C3%A9").unwrap(), "café");
+        for path in ["", "a//b", "a/", "a.b", "a%2Eb", "a%2", "a%zz"] {
+            assert!(key_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_methods_onto_values() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+
+        let (status, _) = request(
+            "PUT",
+            "/v1/keys/user",
+            r#"{"name": "ops", "age": 1}"#,
+            &fabric,
+        )
+        .await;
+        assert_eq!(status, 204);
+        assert_eq!(
+            request("GET", "/v1/keys/user/name", "", &fabric).await,
+            (200, json!("ops"))
+        );
+
+        let patched = request(
+            "PATCH",
+            "/v1/keys/user",
+            r#"{"age": null, "city": "Miami"}"#,
+            &fabric,
+        )
+        .await;
+        assert_eq!(patched, (200, json!({"name": "ops", "city": "Miami"})));
+
+        assert_eq!(
+            request("DELETE", "/v1/keys/user/city", "", &fabric).await.0,
+            204
+        );
+        assert_eq!(
+            request("GET", "/v1/keys/user", "", &fabric).await.1,
+            json!({"name": "ops"})
+        );
+
+        for (method, target, body, status, code) in [
+            ("GET", "/v1/keys/nobody", "", 404, "KEY_NOT_FOUND"),
+            ("DELETE", "/v1/keys/nobody", "", 404, "KEY_NOT_FOUND"),
+            ("GET", "/v1/keys/a//b", "", 400, "INVALID_KEY_PATH"),
+            ("PUT", "/v1/keys/user", "{", 400, "BAD_DATA_STRUCTURE"),
+            ("POST", "/v1/keys/user", "", 405, "METHOD_NOT_ALLOWED"),
+            ("GET", "/v2/keys/user", "", 404, "NOT_FOUND"),
+        ] {
+            let (actual, body) = request(method, target, body, &fabric).await;
+            assert_eq!(
+                (actual, body["error"]["code"].as_str()),
+                (status, Some(code)),
+                "{method} {target}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn revalidates_values_by_etag() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+        request("PUT", "/v1/keys/user", r#"{"name": "ops"}"#, &fabric).await;
+
+        let get = |if_none_match: Option<&str>| {
+            let mut head = head("GET", "/v1/keys/user");
+            head.headers
+                .extend(if_none_match.map(|tags| ("if-none-match".to_string(), tags.to_string())));
+            let fabric = fabric.clone();
+            async move { handle(&head, Vec::new(), &fabric).await }
+        };
+
+        let fresh = get(None).await;
+        assert_eq!(fresh.status, 200);
+        let etag = fresh.etag.clone().unwrap();
+        let encoded = String::from_utf8(fresh.encode(true)).unwrap();
+        assert!(encoded.contains(&format!("ETag: {etag}\r\n")));
+
+        for tags in [
+            etag.clone(),
+            format!("\"other\", W/{etag}"),
+            "*".to_string(),
+        ] {
+            let cached = get(Some(&tags)).await;
+            assert_eq!((cached.status, cached.body), (304, None), "{tags}");
+            assert_eq!(cached.etag.as_deref(), Some(etag.as_str()));
+        }
+
+        // Once the value changes, so does its tag
+        request("PATCH", "/v1/keys/user", r#"{"age": 1}"#, &fabric).await;
+        let changed = get(Some(&etag)).await;
+        assert_eq!(changed.status, 200);
+        assert_ne!(changed.etag, Some(etag));
+    }
+}
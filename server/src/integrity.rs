@@ -0,0 +1,277 @@
+//! Integrity checks for the records in an append-only log.
+//!
+//! NOTE: This module only depends on `std`, external crates and the `tokens`
+//! module so that the `fabric-check` binary can share it with the server.
+
+use crate::tokens::{TokenError, Tokens};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+/// A single complete record in a log.
+pub struct Record<'a> {
+    /// The byte offset the record starts at
+    pub start: u64,
+    /// The byte offset right after the record's trailing newline
+    pub end: u64,
+    /// The record itself, without its checksum
+    pub payload: &'a str,
+}
+
+/// The first corrupted record found in a log.
+#[derive(Debug)]
+pub struct Corruption {
+    /// The number of the corrupted record, counting from 1
+    pub record: usize,
+    /// The byte offset the corrupted record starts at
+    pub offset: u64,
+    /// What is wrong with the record
+    pub reason: String,
+    /// Whether the record's checksum matched, so it was written as it is
+    /// rather than damaged on disk, and truncating it would lose data
+    pub intact: bool,
+}
+impl std::fmt::Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Record {} At Byte {}: {}",
+            self.record, self.offset, self.reason
+        )
+    }
+}
+
+/// The result of scanning a log for corruption.
+pub struct Scan<'a> {
+    /// Every valid record before the first corrupted one
+    pub records: Vec<Record<'a>>,
+    /// The first corrupted record, if any
+    pub corruption: Option<Corruption>,
+    /// The length of the trailing record cut off without a newline, if any
+    pub truncated_tail: u64,
+}
+impl Scan<'_> {
+    /// The length the log has to be truncated to, to only keep the valid records.
+    pub fn valid_len(&self) -> u64 {
+        self.records.last().map(|record| record.end).unwrap_or(0)
+    }
+}
+
+/// Encode a record for the log, prefixing it with the CRC32 checksum
+/// of its contents and ending it with a newline.
+pub fn encode_record(payload: &str) -> String {
+    format!("{:08x} {}\n", crc32fast::hash(payload.as_bytes()), payload)
+}
+
+/// Scan the contents of a log, verifying each record until the first corrupted one.
+pub fn scan(contents: &[u8]) -> Scan<'_> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while offset < contents.len() {
+        let Some(newline) = contents[offset..].iter().position(|byte| *byte == b'\n') else {
+            return Scan {
+                records,
+                corruption: None,
+                truncated_tail: (contents.len() - offset) as u64,
+            };
+        };
+        let end = offset + newline + 1;
+
+        let decoded = decode_record(&contents[offset..end - 1])
+            .map_err(|reason| (reason, false))
+            .and_then(|payload| {
+                validate_payload(payload)
+                    .map(|_| payload)
+                    .map_err(|reason| (reason, true))
+            });
+        match decoded {
+            Ok(payload) => records.push(Record {
+                start: offset as u64,
+                end: end as u64,
+                payload,
+            }),
+            Err((reason, intact)) => {
+                return Scan {
+                    corruption: Some(Corruption {
+                        record: records.len() + 1,
+                        offset: offset as u64,
+                        reason,
+                        intact,
+                    }),
+                    records,
+                    truncated_tail: 0,
+                };
+            }
+        }
+
+        offset = end;
+    }
+
+    Scan {
+        records,
+        corruption: None,
+        truncated_tail: 0,
+    }
+}
+
+/// Verify a single record's checksum, returning its contents.
+fn decode_record(line: &[u8]) -> Result<&str, String> {
+    let line = std::str::from_utf8(line).map_err(|_| "Not Valid UTF-8.".to_string())?;
+    let (checksum, payload) = line
+        .split_once(' ')
+        .ok_or_else(|| "Missing Checksum.".to_string())?;
+
+    let expected = u32::from_str_radix(checksum, 16)
+        .ok()
+        .filter(|_| checksum.len() == 8)
+        .ok_or_else(|| format!("\"{checksum}\" Is Not A Valid Checksum."))?;
+    let actual = crc32fast::hash(payload.as_bytes());
+    if expected != actual {
+        return Err(format!(
+            "Checksum Mismatch (Expected {expected:08x}, Found {actual:08x})."
+        ));
+    }
+
+    Ok(payload)
+}
+
+/// Verify a record is a well formed mutating command.
+///
+/// NOTE: Compressed or encrypted records (prefixed with `~`) can only be
+/// verified up to their encoding here, as decoding them may need a key.
+fn validate_payload(payload: &str) -> Result<(), String> {
+    if let Some(encoded) = payload.strip_prefix('~') {
+        let (transforms, data) = encoded
+            .split_once(' ')
+            .ok_or_else(|| "Encoded Record Is Missing Its Data.".to_string())?;
+        if let Some(transform) = transforms
+            .split('+')
+            .find(|transform| !matches!(*transform, "zstd" | "lz4" | "aead"))
+        {
+            return Err(format!("\"{transform}\" Is Not A Known Record Encoding."));
+        }
+        return BASE64
+            .decode(data)
+            .map(|_| ())
+            .map_err(|e| format!("Encoded Record Is Not Valid Base64: {e}"));
+    }
+
+    // Split the record exactly like it's parsed on replay, so quoted keys are fine
+    let mut tokens = Tokens::new(payload);
+    let verb = tokens
+        .next()
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    let verb = verb.to_uppercase();
+    let key = tokens
+        .next()
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    if key.is_empty() {
+        return Err(format!("\"{verb}\" Record Is Missing A Key."));
+    }
+
+    match verb.as_str() {
+        "SET" => match tokens.rest() {
+            "" => Err("SET Record Is Missing A Value.".to_string()),
+            value => serde_json::from_str::<serde_json::Value>(value)
+                .map(|_| ())
+                .map_err(|e| format!("Bad Data Structure: {e}")),
+        },
+        "SETBLOB" => {
+            let blob = tokens
+                .next()
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| "SETBLOB Record Is Missing A Blob.".to_string())?;
+            BASE64
+                .decode(blob)
+                .map(|_| ())
+                .map_err(|e| format!("Blob Is Not Valid Base64: {e}"))?;
+            no_more_tokens(&mut tokens, &verb)
+        }
+        "REMOVE" => no_more_tokens(&mut tokens, &verb),
+        _ => Err(format!("\"{verb}\" Is Not A Valid Record.")),
+    }
+}
+
+/// Verify a record has no arguments left over, like a command must not.
+fn no_more_tokens(tokens: &mut Tokens, verb: &str) -> Result<(), String> {
+    match tokens.next() {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err(TokenError::WrongArity(verb.to_string()).to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_valid_records() {
+        let contents = [
+            encode_record("SET a 1"),
+            encode_record("SETBLOB b AA=="),
+            encode_record("REMOVE a"),
+        ]
+        .concat();
+
+        let scan = scan(contents.as_bytes());
+        assert!(scan.corruption.is_none());
+        assert_eq!(scan.truncated_tail, 0);
+        assert_eq!(scan.valid_len(), contents.len() as u64);
+        let payloads: Vec<&str> = scan.records.iter().map(|record| record.payload).collect();
+        assert_eq!(payloads, vec!["SET a 1", "SETBLOB b AA==", "REMOVE a"]);
+    }
+
+    #[test]
+    fn detects_checksum_mismatch() {
+        let first = encode_record("SET a 1");
+        let contents = [first.clone(), encode_record("SET b 2").replace('2', "3")].concat();
+
+        let scan = scan(contents.as_bytes());
+        let corruption = scan.corruption.as_ref().unwrap();
+        assert_eq!(corruption.record, 2);
+        assert_eq!(corruption.offset, first.len() as u64);
+        assert!(!corruption.intact);
+        assert_eq!(scan.valid_len(), first.len() as u64);
+    }
+
+    #[test]
+    fn detects_malformed_records() {
+        for record in [
+            "GET a",
+            "SET a",
+            "SET a {bad",
+            "SETBLOB a",
+            "SETBLOB a !!",
+            "REMOVE",
+            "nonsense",
+        ] {
+            let contents = encode_record(record);
+            let corruption = scan(contents.as_bytes()).corruption;
+            assert!(corruption.is_some_and(|c| c.intact), "{record}");
+        }
+
+        assert!(scan(b"SET a 1\n").corruption.is_some_and(|c| !c.intact));
+        assert!(scan(encode_record("~gzip AAAA").as_bytes())
+            .corruption
+            .is_some());
+        assert!(scan(encode_record("~zstd !!").as_bytes())
+            .corruption
+            .is_some());
+        assert!(scan(encode_record("~zstd+aead AAAA").as_bytes())
+            .corruption
+            .is_none());
+    }
+
+    #[test]
+    fn reports_truncated_tail() {
+        let first = encode_record("SET a 1");
+        let contents = format!("{first}0badc0de SET b {{\"x\":");
+
+        let scan = scan(contents.as_bytes());
+        assert!(scan.corruption.is_none());
+        assert_eq!(scan.records.len(), 1);
+        assert_eq!(scan.truncated_tail, (contents.len() - first.len()) as u64);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::{command::Command, shutdown::ShutdownMode, Error, ThreadSafeFabric};
+use serde_json::{json, Map, Value};
+
+/// Invalid JSON was received.
+const PARSE_ERROR: i64 = -32700;
+/// The JSON sent is not a valid request object.
+const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// Invalid method parameters.
+const INVALID_PARAMS: i64 = -32602;
+/// A Fabric error, with its code in the error's `data`.
+const SERVER_ERROR: i64 = -32000;
+
+/// Whether a request is a JSON-RPC 2.0 request or batch of them,
+/// which no Fabric command starts like.
+pub fn is_request(client_input: &str) -> bool {
+    client_input.starts_with(['{', '['])
+}
+
+/// Handle a JSON-RPC 2.0 request, or batch of requests, returning the
+/// response to send back, if there is one.
+///
+/// NOTE: Notifications (requests without an `id`) are never responded
+/// to, so a batch of only notifications gets no response at all.
+pub async fn handle(client_input: &str, fabric: &ThreadSafeFabric) -> Option<String> {
+    let request = match serde_json::from_str::<Value>(client_input) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = error(PARSE_ERROR, format!("Parse error: {e}"), None);
+            return Some(response(Value::Null, Err(error)).to_string());
+        }
+    };
+
+    match request {
+        Value::Array(batch) if batch.is_empty() => {
+            let error = error(INVALID_REQUEST, "Empty batch".into(), None);
+            Some(response(Value::Null, Err(error)).to_string())
+        }
+        Value::Array(batch) => {
+            let mut responses = Vec::new();
+            for request in batch {
+                responses.extend(handle_one(request, fabric).await);
+            }
+            (!responses.is_empty()).then(|| Value::Array(responses).to_string())
+        }
+        request => handle_one(request, fabric)
+            .await
+            .map(|response| response.to_string()),
+    }
+}
+
+/// Handle a single request out of a batch, or on its own.
+async fn handle_one(request: Value, fabric: &ThreadSafeFabric) -> Option<Value> {
+    let Value::Object(mut request) = request else {
+        let error = error(INVALID_REQUEST, "Request must be an object".into(), None);
+        return Some(response(Value::Null, Err(error)));
+    };
+
+    // A request without an id is a notification, but a malformed one is still
+    // answered, since there's no telling what the client meant it to be
+    let id = request.remove("id");
+    let valid_id = matches!(
+        id,
+        None | Some(Value::Null | Value::String(_) | Value::Number(_))
+    );
+    let version = request.remove("jsonrpc");
+    let method = match (version, request.remove("method")) {
+        (Some(Value::String(version)), Some(Value::String(method)))
+            if version == "2.0" && valid_id =>
+        {
+            method
+        }
+        _ => {
+            let error = error(INVALID_REQUEST, "Invalid request".into(), None);
+            return Some(response(Value::Null, Err(error)));
+        }
+    };
+
+    let result = match request.remove("params") {
+        None => dispatch(&method, Map::new(), fabric).await,
+        Some(Value::Object(params)) => dispatch(&method, params, fabric).await,
+        Some(_) => Err(error(
+            INVALID_PARAMS,
+            "Params must be an object".into(),
+            None,
+        )),
+    };
+    id.map(|id| response(id, result))
+}
+
+/// Build the `Command` a method calls for from its params and handle it.
+async fn dispatch(
+    method: &str,
+    mut params: Map<String, Value>,
+    fabric: &ThreadSafeFabric,
+) -> Result<Value, Value> {
+    let cmd = match method {
+        "get" => Command::Get {
+            key: path(&mut params)?,
+        },
+        "set" => Command::Set {
+            key: path(&mut params)?,
+            value: param(&mut params, "value")?.to_string(),
+        },
+        "remove" => Command::Remove {
+            key: path(&mut params)?,
+        },
+        "bgrewriteaof" => Command::BgRewriteAof,
+        "stats" => Command::Stats,
+        "dump" => Command::Dump {
+            key: path(&mut params)?,
+        },
+        "restore" => Command::Restore {
+            key: path(&mut params)?,
+            blob: string_param(&mut params, "blob")?,
+            replace: match params.remove("replace") {
+                None => false,
+                Some(Value::Bool(replace)) => replace,
+                Some(_) => return Err(invalid_param("replace")),
+            },
+        },
+        "export" => Command::Export {
+            key: path(&mut params)?,
+            file: string_param(&mut params, "file")?,
+        },
+        "import" => Command::Import {
+            key: path(&mut params)?,
+            file: string_param(&mut params, "file")?,
+        },
+        "shutdown" => Command::Shutdown {
+            mode: match params.remove("save") {
+                None => ShutdownMode::Default,
+                Some(Value::Bool(true)) => ShutdownMode::Save,
+                Some(Value::Bool(false)) => ShutdownMode::NoSave,
+                Some(_) => return Err(invalid_param("save")),
+            },
+        },
+        _ => {
+            let message = format!("Method not found: {method}");
+            return Err(error(METHOD_NOT_FOUND, message, None));
+        }
+    };
+    if let Some(unknown) = params.keys().next() {
+        return Err(invalid_param(unknown));
+    }
+
+    let output = cmd.handle(fabric).await.map_err(|e| {
+        let data = json!({ "code": e.code() });
+        error(SERVER_ERROR, e.to_string(), Some(data))
+    })?;
+    let output = String::from_utf8_lossy(&output);
+    let output = output.trim_end();
+
+    // Values come back as JSON and blobs as strings, where everything else is just `OK`
+    Ok(match cmd {
+        Command::Get { .. } | Command::Stats => serde_json::from_str(output).unwrap_or(Value::Null),
+        _ => Value::String(output.to_string()),
+    })
+}
+
+/// Take the `path` param, an array of key segments, as a key path.
+///
+/// NOTE: Segments are joined with `.`, so they can hold anything but
+/// a `.` of their own, like whitespace or quotes, without any escaping.
+fn path(params: &mut Map<String, Value>) -> Result<String, Value> {
+    let Value::Array(segments) = param(params, "path")? else {
+        return Err(invalid_param("path"));
+    };
+
+    let mut keys = Vec::with_capacity(segments.len());
+    for segment in &segments {
+        match segment {
+            Value::String(key) if !key.is_empty() && !key.contains('.') => keys.push(key.as_str()),
+            _ => {
+                let e = Error::InvalidKeyPath(Value::Array(segments.clone()).to_string());
+                return Err(error(INVALID_PARAMS, e.to_string(), None));
+            }
+        }
+    }
+    if keys.is_empty() {
+        return Err(invalid_param("path"));
+    }
+    Ok(keys.join("."))
+}
+
+/// Take a required param.
+fn param(params: &mut Map<String, Value>, name: &str) -> Result<Value, Value> {
+    params.remove(name).ok_or_else(|| {
+        let message = format!("Missing param: {name}");
+        error(INVALID_PARAMS, message, None)
+    })
+}
+
+/// Take a required string param.
+fn string_param(params: &mut Map<String, Value>, name: &str) -> Result<String, Value> {
+    match param(params, name)? {
+        Value::String(s) => Ok(s),
+        _ => Err(invalid_param(name)),
+    }
+}
+
+/// The error for a param that's unknown or of the wrong type.
+fn invalid_param(name: &str) -> Value {
+    error(INVALID_PARAMS, format!("Invalid param: {name}"), None)
+}
+
+/// A JSON-RPC error object.
+fn error(code: i64, message: String, data: Option<Value>) -> Value {
+    let mut error = json!({ "code": code, "message": message });
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    error
+}
+
+/// A JSON-RPC response object.
+fn response(id: Value, result: Result<Value, Value>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fabric::Fabric;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    async fn call(request: Value, fabric: &ThreadSafeFabric) -> Option<Value> {
+        let response = handle(&request.to_string(), fabric).await?;
+        Some(serde_json::from_str(&response).unwrap())
+    }
+
+    #[tokio::test]
+    async fn dispatches_requests_to_commands() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+
+        let set = json!({
+            "jsonrpc": "2.0",
+            "method": "set",
+            "params": {"path": ["my user", "name"], "value": {"first": "a \"b\""}},
+            "id": 1
+        });
+        assert_eq!(
+            call(set, &fabric).await.unwrap(),
+            json!({"jsonrpc": "2.0", "result": "OK", "id": 1})
+        );
+
+        let get = json!({
+            "jsonrpc": "2.0",
+            "method": "get",
+            "params": {"path": ["my user"]},
+            "id": "a"
+        });
+        assert_eq!(
+            call(get, &fabric).await.unwrap(),
+            json!({"jsonrpc": "2.0", "result": {"name": {"first": "a \"b\""}}, "id": "a"})
+        );
+
+        let missing =
+            json!({"jsonrpc": "2.0", "method": "get", "params": {"path": ["x"]}, "id": 2});
+        assert_eq!(
+            call(missing, &fabric).await.unwrap()["error"],
+            json!({"code": SERVER_ERROR, "message": "Key: \"x\" Not Found.", "data": {"code": "KEY_NOT_FOUND"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_batches_without_notifications() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "set", "params": {"path": ["a"], "value": 1}},
+            {"jsonrpc": "2.0", "method": "get", "params": {"path": ["a"]}, "id": 1},
+            {"jsonrpc": "2.0", "method": "flush", "id": 2},
+            {"jsonrpc": "2.0", "method": "get", "params": {"path": ["a.b"]}, "id": 3},
+            {"jsonrpc": "2.0", "method": "get", "params": {"path": ["a"], "extra": 1}, "id": 4},
+            {"jsonrpc": "1.0", "method": "get", "id": 5},
+            7,
+        ]);
+        let response = call(batch, &fabric).await.unwrap();
+        let codes: Vec<_> = response
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|response| (response["id"].clone(), response["error"]["code"].clone()))
+            .collect();
+        assert_eq!(response[0]["result"], json!(1));
+        assert_eq!(
+            codes,
+            [
+                (json!(1), Value::Null),
+                (json!(2), json!(METHOD_NOT_FOUND)),
+                (json!(3), json!(INVALID_PARAMS)),
+                (json!(4), json!(INVALID_PARAMS)),
+                (Value::Null, json!(INVALID_REQUEST)),
+                (Value::Null, json!(INVALID_REQUEST)),
+            ]
+        );
+
+        let notifications =
+            json!([{"jsonrpc": "2.0", "method": "remove", "params": {"path": ["a"]}}]);
+        assert_eq!(call(notifications, &fabric).await, None);
+        assert!(fabric.read().await.get(vec!["a"]).is_err());
+
+        for request in ["[]", "{\"jsonrpc\": "] {
+            let response: Value =
+                serde_json::from_str(&handle(request, &fabric).await.unwrap()).unwrap();
+            assert!(response["error"]["code"].is_i64(), "{request}");
+            assert_eq!(response["id"], Value::Null);
+        }
+    }
+}
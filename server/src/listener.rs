@@ -0,0 +1,172 @@
+use crate::{config::Config, tls, Error};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(unix)]
+use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// The longest a client can take to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A client connection, no matter what kind of listener accepted it.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// A socket the server accepts client connections on.
+pub enum Listener {
+    Tcp(TcpListener, Option<TlsAcceptor>),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+impl Listener {
+    /// Bind every listener in the config.
+    pub async fn bind_all(config: &Config) -> Result<Vec<Listener>, Error> {
+        let mut listeners = Vec::new();
+
+        let tls = tls::acceptor(config)?;
+        for ip in &config.bind {
+            let listener = bind_tcp(SocketAddr::new(*ip, config.port))?;
+            listeners.push(Listener::Tcp(listener, tls.clone()));
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = &config.unixsocket {
+            listeners.push(bind_unix(path, config.unixsocketperm)?);
+        }
+
+        Ok(listeners)
+    }
+
+    /// Bind a gateway's listeners on every address in the config at its
+    /// port, if it's enabled, served over TLS like the rest.
+    pub fn bind_gateway(config: &Config, port: Option<u16>) -> Result<Vec<Listener>, Error> {
+        let Some(port) = port else {
+            return Ok(Vec::new());
+        };
+
+        let tls = tls::acceptor(config)?;
+        config
+            .bind
+            .iter()
+            .map(|ip| {
+                let listener = bind_tcp(SocketAddr::new(*ip, port))?;
+                Ok(Listener::Tcp(listener, tls.clone()))
+            })
+            .collect()
+    }
+
+    /// Accept the next client connection.
+    ///
+    /// NOTE: The TLS handshake isn't done here, so a slow client can't hold
+    /// up the accept loop, it's done by `establish` once the client has its
+    /// own task.
+    pub async fn accept(&self) -> Result<Box<dyn Connection>, Error> {
+        match self {
+            Listener::Tcp(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                Ok(Box::new(socket))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                Ok(Box::new(socket))
+            }
+        }
+    }
+
+    /// The TLS acceptor connections from this listener are served through, if any.
+    pub fn tls(&self) -> Option<TlsAcceptor> {
+        match self {
+            Listener::Tcp(_, tls) => tls.clone(),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+}
+
+/// Establish an accepted connection, doing the TLS handshake if its listener uses TLS.
+///
+/// NOTE: Clients that don't finish the handshake within the idle timeout, or
+/// `HANDSHAKE_TIMEOUT` if that's sooner or there's none, are given up on.
+pub async fn establish(
+    socket: Box<dyn Connection>,
+    tls: Option<TlsAcceptor>,
+    idle_timeout: Option<Duration>,
+) -> Result<Box<dyn Connection>, Error> {
+    let Some(tls) = tls else {
+        return Ok(socket);
+    };
+    let timeout = idle_timeout.map_or(HANDSHAKE_TIMEOUT, |idle| idle.min(HANDSHAKE_TIMEOUT));
+    match tokio::time::timeout(timeout, tls.accept(socket)).await {
+        Ok(socket) => Ok(Box::new(socket?)),
+        Err(_) => Err(Error::Tls("Handshake Timed Out".to_string())),
+    }
+}
+/// Display where the listener accepts connections.
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listener, tls) => {
+                let scheme = if tls.is_some() { "tls" } else { "tcp" };
+                match listener.local_addr() {
+                    Ok(addr) => write!(f, "{scheme}://{addr}"),
+                    Err(_) => write!(f, "{scheme}://<unknown>"),
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+#[cfg(unix)]
+impl Drop for Listener {
+    /// Clean up the socket file once a unix listener is closed.
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Bind a TCP listener.
+///
+/// NOTE: IPv6 sockets are bound as IPv6 only, so listening on both
+/// `0.0.0.0` and `::` on the same port doesn't conflict.
+fn bind_tcp(addr: SocketAddr) -> Result<TcpListener, Error> {
+    let domain = socket2::Domain::for_address(addr);
+    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Bind a unix domain socket listener, restricting who can connect
+/// to it with the file permissions in `perm`.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, perm: u32) -> Result<Listener, Error> {
+    // A socket file left behind by a server that didn't shut down cleanly
+    // would make binding fail, so remove it (but never a regular file)
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(perm))?;
+
+    Ok(Listener::Unix(listener, path.to_path_buf()))
+}
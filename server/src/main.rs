@@ -1,66 +1,419 @@
+mod aof;
+mod changes;
 mod command;
+mod config;
+mod dump;
+mod encoding;
 mod error;
 mod fabric;
+mod frame;
+mod hello;
+mod http;
+mod integrity;
+mod jsonrpc;
+mod listener;
+mod output;
+mod pubsub;
+mod resp;
+mod shutdown;
+mod stats;
+mod storage;
+mod tls;
+mod tokens;
+mod websocket;
 
-use self::{command::Command, error::Error, fabric::Fabric};
-use std::sync::Arc;
+use self::{
+    aof::AppendOnlyLog,
+    command::Command,
+    config::{Cli, Config},
+    encoding::Encoding,
+    error::Error,
+    fabric::{Fabric, Limits},
+    frame::Framing,
+    hello::Hello,
+    listener::{establish, Connection, Listener},
+    output::Output,
+    shutdown::SHUTDOWN,
+    stats::{ClientSlot, STATS},
+    storage::StorageCodec,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader},
     sync::RwLock,
+    task::JoinSet,
 };
 
 pub type ThreadSafeFabric = Arc<RwLock<Fabric>>;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    // Start listening for TCP connections at localhost on port 8731
-    // TODO / NOTE: This should be configurable, both the ip address and the port.
-    let tcp_listener = TcpListener::bind("127.0.0.1:8731").await?;
+    let cli = Cli::parse(std::env::args().skip(1))?;
+    if cli.help {
+        print!("{}", Cli::usage());
+        return Ok(());
+    }
+
+    let config = Config::load(&cli, |var| std::env::var(var).ok())?;
+    if cli.print_config {
+        print!("{}", config);
+        return Ok(());
+    }
 
     // Initialize a thread safe instance of `Fabric`
     let fabric: ThreadSafeFabric = Arc::new(RwLock::new(Fabric::new()));
 
+    // Restore the cache from the append-only log before accepting any
+    // connections, then start recording every mutating command in it.
+    if config.appendonly {
+        let codec = StorageCodec::new(config.aof_compression, config.encryption_key.as_ref());
+        let replaying = if config.aof_migrate_plaintext {
+            codec.clone().accepting_plaintext()
+        } else {
+            codec.clone()
+        };
+        let replayed = aof::replay(
+            &config.appendfilename,
+            &fabric,
+            &replaying,
+            config.aof_on_corruption,
+        )
+        .await?;
+        println!(
+            "Replayed {} records from append-only log {:?}",
+            replayed, config.appendfilename
+        );
+
+        // Records written before there was a key are only ever trusted once,
+        // to rewrite them encrypted before anything else is appended
+        if config.aof_migrate_plaintext {
+            aof::rewrite_now(&config.appendfilename, &*fabric.read().await, &codec)?;
+            println!(
+                "Rewrote append-only log {:?} with every record encrypted",
+                config.appendfilename
+            );
+        }
+
+        let log = AppendOnlyLog::open(&config.appendfilename, config.appendfsync, codec)?;
+        fabric.write().await.aof = Some(log);
+        aof::spawn_cron(
+            fabric.clone(),
+            config.auto_aof_rewrite_percentage,
+            config.auto_aof_rewrite_min_size,
+        );
+    }
+
+    // The limits only apply to clients, not to what was replayed from the log
+    fabric.write().await.limits = Limits::from(&config);
+    fabric.write().await.export_dir = config.export_dir.clone();
+
+    // Shut down gracefully on SIGTERM or SIGINT, like on a SHUTDOWN command
+    shutdown::spawn_signal_handler();
+
+    // Start listening for connections on every configured address
+    let listeners = Listener::bind_all(&config).await?;
+    let mut accept_loops = JoinSet::new();
+    for listener in listeners {
+        println!("Listening on {}", listener);
+        accept_loops.spawn(accept_clients(listener, Service::Fabric, fabric.clone()));
+    }
+    for listener in Listener::bind_gateway(&config, config.http_port)? {
+        println!("Serving HTTP on {}", listener);
+        accept_loops.spawn(accept_clients(listener, Service::Http, fabric.clone()));
+    }
+    for listener in Listener::bind_gateway(&config, config.websocket_port)? {
+        println!("Serving WebSocket subscriptions on {}", listener);
+        accept_loops.spawn(accept_clients(listener, Service::WebSocket, fabric.clone()));
+    }
+
+    // Serve until asked to shut down, or any of the accept loops panics
+    let mode = loop {
+        tokio::select! {
+            mode = SHUTDOWN.requested() => break mode,
+            Some(result) = accept_loops.join_next() => result.map_err(|e| Error::IO(e.into()))?,
+        }
+    };
+
+    // Stop accepting clients, then give the connected ones a chance to finish
+    // the commands they're running before the log is flushed for good
+    accept_loops.shutdown().await;
+    println!(
+        "Waiting up to {}s for {} clients to finish",
+        config.shutdown_timeout,
+        STATS.connected_clients.load(Ordering::SeqCst)
+    );
+    let remaining = shutdown::drain(Duration::from_secs(config.shutdown_timeout)).await;
+    if remaining > 0 {
+        println!(
+            "Disconnecting {} clients that didn't finish in time",
+            remaining
+        );
+    }
+    let _fabric = shutdown::persist(&fabric, mode).await?;
+    println!("Shut down cleanly");
+
+    Ok(())
+}
+
+/// What the clients of a listener speak.
+#[derive(Clone, Copy)]
+enum Service {
+    /// Fabric, or RESP, or JSON-RPC
+    Fabric,
+    /// HTTP, through the REST gateway
+    Http,
+    /// WebSocket, to subscribe to changes
+    WebSocket,
+}
+
+/// Accept client connections from a listener for as long as the server runs.
+async fn accept_clients(listener: Listener, service: Service, fabric: ThreadSafeFabric) {
+    let limits = fabric.read().await.limits;
+
     loop {
-        // Accept incoming TCP connections into a socket (TCP Stream)
-        let (socket, _) = tcp_listener.accept().await?;
+        // Accept incoming connections into a socket, where failing to accept
+        // one, like when out of file descriptors, is no reason to stop serving
+        let mut socket = match listener.accept().await {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("Error accepting client connection on {}: {}", listener, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
 
         // The shared data structure store between clients
         let fabric = fabric.clone();
+        let tls = listener.tls();
+        let connection_id = STATS.total_connections.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let Some(slot) = ClientSlot::claim(limits.max_clients) else {
+            // Clients over the limit are told why they're turned away, unless
+            // that takes a TLS handshake, which isn't spent on them
+            if tls.is_none() {
+                let e = Error::LimitExceeded("Max Number Of Clients Reached".to_string());
+                let reply = match service {
+                    Service::Fabric => e.reply(),
+                    Service::Http | Service::WebSocket => http::reject(&e),
+                };
+                tokio::spawn(async move {
+                    let _ = socket.write_all(&reply).await;
+                    let _ = socket.shutdown().await;
+                });
+            }
+            continue;
+        };
 
         // Start the server and handle the client streams
         tokio::spawn(async move {
-            if let Err(e) = handle_client(socket, fabric).await {
+            let _slot = slot;
+            let secure = tls.is_some();
+            let socket = match establish(socket, tls, limits.idle_timeout()).await {
+                Ok(socket) => socket,
+                Err(e) => return eprintln!("Error establishing client connection: {}", e),
+            };
+            let handled = match service {
+                Service::Fabric => handle_client(socket, connection_id, secure, fabric).await,
+                Service::Http => http::serve(socket, &fabric).await,
+                Service::WebSocket => websocket::serve(socket, &fabric).await,
+            };
+            if let Err(e) = handled {
                 eprintln!("Error handling client: {:?}", e);
             }
         });
     }
 }
 
-/// Handle a client's TCP stream.
-async fn handle_client(socket: TcpStream, fabric: ThreadSafeFabric) -> Result<(), Error> {
-    // The IO for the TCP stream between client and server
-    let (reader, mut writer) = socket.into_split();
+/// Handle a client's connection.
+async fn handle_client(
+    socket: Box<dyn Connection>,
+    connection_id: u64,
+    tls: bool,
+    fabric: ThreadSafeFabric,
+) -> Result<(), Error> {
+    let limits = fabric.read().await.limits;
+
+    // The IO for the stream between client and server
+    let (reader, writer) = tokio::io::split(socket);
     let mut reader = BufReader::new(reader);
-    let mut client_input = String::new();
+    let output = Output::new(
+        writer,
+        limits.client_output_buffer_limit,
+        limits.idle_timeout(),
+    );
+
+    // RESP clients send every command as an array, which is
+    // how they're told apart from clients speaking Fabric
+    let first_byte =
+        match frame::until_idle_or_shutdown(limits.idle_timeout(), reader.fill_buf()).await {
+            Some(buf) => buf?.first().copied(),
+            None => None,
+        };
+    let served = match first_byte {
+        Some(resp::ARRAY_PREFIX) => resp::serve(&mut reader, &output, &fabric).await,
+        Some(_) => serve(&mut reader, &output, connection_id, tls, &fabric).await,
+        // Client disconnected, or never said anything
+        None => Ok(()),
+    };
+
+    output.close().await?;
+    served
+}
+
+/// Serve a client speaking Fabric until it disconnects.
+async fn serve<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    output: &Output,
+    connection_id: u64,
+    tls: bool,
+    fabric: &ThreadSafeFabric,
+) -> Result<(), Error> {
+    // Clients start out speaking in lines, until they switch protocol versions,
+    // and sending values as JSON, until they switch encodings
+    let mut framing = Framing::Lines;
+    let mut encoding = Encoding::Json;
+    let limits = fabric.read().await.limits;
 
     loop {
-        // Read the client input from the tcp stream
-        client_input.clear();
-        let bytes_read = reader.read_line(&mut client_input).await?;
-        if bytes_read == 0 {
-            // Client disconnected
-            break;
-        }
+        // Read the client input from the stream, unless the client idles for too long
+        let read = frame::until_idle_or_shutdown(
+            limits.idle_timeout(),
+            framing.read(reader, limits.max_request_size),
+        );
+        let client_input = match read.await {
+            Some(Ok(Some(client_input))) => client_input,
+            // Client disconnected or idled out
+            Some(Ok(None)) | None => break,
+            Some(Err(e @ (Error::Protocol(_) | Error::LimitExceeded(_)))) => {
+                // A bad or oversized frame leaves the stream out of sync, so reply and hang up
+                output.send(framing.encode(&e.reply()))?;
+                return Err(e);
+            }
+            Some(Err(e)) => return Err(e),
+        };
+        // Framed requests can carry a raw payload after their command line
+        let (client_input, payload) = match framing {
+            Framing::LengthPrefixed => encoding.split_payload(&client_input),
+            Framing::Lines => (&client_input[..], None),
+        };
+        let Ok(client_input) = std::str::from_utf8(client_input) else {
+            let e = Error::Protocol("Request Is Not UTF-8".to_string());
+            output.send(framing.encode(&e.reply()))?;
+            continue;
+        };
         let client_input = client_input.trim();
 
-        // Parse the client input into a `Command` and handle the
-        // functionality behind the command returning the output
-        // to then send back to the client.
-        let cmd = Command::from(client_input)?;
-        let output = cmd.handle(client_input, &fabric).await?;
-        writer.write_all(&output).await?;
+        // Blank lines, like a stray enter over telnet, aren't commands
+        if client_input.is_empty() && framing == Framing::Lines {
+            continue;
+        }
+
+        // Requests can be tagged with an ID that's echoed back in their response
+        let (id, client_input) = frame::split_tag(client_input);
+
+        let response = if let Some(version) = client_input.strip_prefix("PROTOCOL ") {
+            // Switching protocol versions is acknowledged in the version switched from
+            match Framing::from_version(version) {
+                Some(next) => {
+                    output.send(framing.encode(&frame::tag(id, b"OK\n")))?;
+                    framing = next;
+                    if framing == Framing::Lines {
+                        encoding = Encoding::Json;
+                    }
+                    continue;
+                }
+                None => Error::Protocol(format!("Unsupported Version {}", version.trim())).reply(),
+            }
+        } else if let Some(version) = hello::parse(client_input) {
+            // Like switching protocol versions, except the server describes itself
+            match version {
+                Ok(version) => {
+                    let next = version.unwrap_or(framing);
+                    let hello = Hello {
+                        framing: next,
+                        connection_id,
+                        tls,
+                        appendonly: fabric.read().await.aof.is_some(),
+                    };
+                    let reply = format!("{}\n", hello.reply());
+                    output.send(framing.encode(&frame::tag(id, reply.as_bytes())))?;
+                    framing = next;
+                    if framing == Framing::Lines {
+                        encoding = Encoding::Json;
+                    }
+                    continue;
+                }
+                Err(e) => e.reply(),
+            }
+        } else if let Some(next) = encoding::parse(client_input) {
+            // Binary values can hold newlines, so they're only ever sent in frames
+            match next {
+                Ok(next) if next != Encoding::Json && framing == Framing::Lines => {
+                    Error::Protocol("Binary Encodings Need Protocol Version 2".to_string()).reply()
+                }
+                Ok(next) => {
+                    encoding = next;
+                    b"OK\n".to_vec()
+                }
+                Err(e) => e.reply(),
+            }
+        } else if let Some(request) = pubsub::parse(client_input) {
+            // Subscribing switches the connection to having messages pushed
+            // to it, until it's unsubscribed from everything again
+            match request {
+                Ok(request) => {
+                    let subscribed =
+                        pubsub::serve_subscribed(reader, output, framing, id, request, fabric);
+                    if !subscribed.await? {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => e.reply(),
+            }
+        } else if jsonrpc::is_request(client_input) {
+            // Structured requests are answered in kind, where
+            // notifications don't get any response at all
+            match jsonrpc::handle(client_input, fabric).await {
+                Some(response) => response.into_bytes(),
+                None => continue,
+            }
+        } else {
+            // Parse the client input into a `Command` and handle the
+            // functionality behind the command returning the output
+            // to then send back to the client.
+            run_command(client_input, payload, framing, encoding, fabric).await
+        };
+        output.send(framing.encode(&frame::tag(id, &response)))?;
     }
 
     Ok(())
 }
+
+/// Run a single command, turning any failure to parse or handle
+/// it into an error reply, so the connection survives bad input.
+///
+/// NOTE: The payload following the command line of a framed request
+/// is its last argument, like the blob of a SETBLOB.
+async fn run_command(
+    client_input: &str,
+    payload: Option<&[u8]>,
+    framing: Framing,
+    encoding: Encoding,
+    fabric: &ThreadSafeFabric,
+) -> Vec<u8> {
+    let output = async {
+        let cmd = match payload {
+            Some(payload) => {
+                let limits = fabric.read().await.limits;
+                Command::parse_with_payload(client_input, payload, encoding, &limits)?
+            }
+            None => Command::parse(client_input)?,
+        };
+        cmd.handle_with(fabric, framing, encoding).await
+    };
+    output.await.unwrap_or_else(|e| e.reply())
+}
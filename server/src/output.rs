@@ -0,0 +1,132 @@
+use crate::{stats::STATS, Error};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// The replies waiting to be written to a client, which are written by a
+/// task of their own, so a client that's slow to read them can't hold up
+/// handling its requests, only fall behind until it's disconnected.
+pub struct Output {
+    queue: mpsc::UnboundedSender<Vec<u8>>,
+    /// The bytes queued but not written yet
+    pending: Arc<AtomicUsize>,
+    /// How many bytes can be pending before the client is disconnected
+    limit: usize,
+    /// Whether the client fell too far behind
+    overflowed: AtomicBool,
+    writer: JoinHandle<Result<(), Error>>,
+}
+impl Output {
+    /// Start writing replies to a client as they're queued.
+    ///
+    /// NOTE: With a `timeout`, a client that doesn't read any of a
+    /// reply for that long is given up on.
+    pub fn new<W>(mut writer: W, limit: usize, timeout: Option<Duration>) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (queue, mut replies) = mpsc::unbounded_channel::<Vec<u8>>();
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let written = pending.clone();
+        let writer = tokio::spawn(async move {
+            while let Some(reply) = replies.recv().await {
+                match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, writer.write_all(&reply))
+                        .await
+                        .map_err(|_| Error::Protocol("Client Stopped Reading".to_string()))??,
+                    None => writer.write_all(&reply).await?,
+                }
+                written.fetch_sub(reply.len(), Ordering::SeqCst);
+            }
+            writer.shutdown().await?;
+            Ok(())
+        });
+
+        Output {
+            queue,
+            pending,
+            limit,
+            overflowed: AtomicBool::new(false),
+            writer,
+        }
+    }
+
+    /// Queue a reply to be written.
+    ///
+    /// NOTE: A reply is always queued when nothing else is pending, so a
+    /// single reply larger than the limit still makes it to the client.
+    pub fn send(&self, reply: Vec<u8>) -> Result<(), Error> {
+        let pending = self.pending.load(Ordering::SeqCst);
+        if pending > 0 && pending + reply.len() > self.limit {
+            self.overflowed.store(true, Ordering::SeqCst);
+            STATS.slow_consumers.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::LimitExceeded(format!(
+                "Client Output Buffer Is Over {} Bytes",
+                self.limit
+            )));
+        }
+
+        self.pending.fetch_add(reply.len(), Ordering::SeqCst);
+        self.queue.send(reply).map_err(|_| {
+            let e = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client Disconnected");
+            Error::IO(e)
+        })
+    }
+
+    /// Wait for every queued reply to be written, then end the connection,
+    /// unless the client fell too far behind, where it's cut off right away.
+    pub async fn close(self) -> Result<(), Error> {
+        if self.overflowed.load(Ordering::SeqCst) {
+            self.writer.abort();
+            return Ok(());
+        }
+
+        drop(self.queue);
+        self.writer.await.map_err(|e| Error::IO(e.into()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn writes_replies_in_order() {
+        let (client, server) = tokio::io::duplex(64);
+        let output = Output::new(server, 1024, None);
+        output.send(b"OK\n".to_vec()).unwrap();
+        output.send(b"1\n".to_vec()).unwrap();
+        output.close().await.unwrap();
+
+        let mut replies = String::new();
+        let (mut client, _) = tokio::io::split(client);
+        client.read_to_string(&mut replies).await.unwrap();
+        assert_eq!(replies, "OK\n1\n");
+    }
+
+    #[tokio::test]
+    async fn cuts_off_clients_that_fall_behind() {
+        // Nothing is ever read, so only the duplex buffer's worth is written
+        let (_client, server) = tokio::io::duplex(8);
+        let output = Output::new(server, 32, None);
+
+        // A lone reply goes out no matter how large it is
+        output.send(vec![b'a'; 64]).unwrap();
+        assert!(matches!(
+            output.send(vec![b'b'; 16]),
+            Err(Error::LimitExceeded(_))
+        ));
+        output.close().await.unwrap();
+    }
+}
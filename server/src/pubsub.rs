@@ -0,0 +1,689 @@
+use crate::{
+    changes::Change,
+    frame::{self, Framing},
+    output::Output,
+    shutdown::SHUTDOWN,
+    stats::STATS,
+    tokens::Tokens,
+    Error, ThreadSafeFabric,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Arc, Mutex, MutexGuard, PoisonError},
+};
+use tokio::{io::AsyncBufRead, sync::mpsc};
+
+/// What the channels keyspace notifications are published to start with,
+/// followed by the key path that changed, like `__keyspace__:users.alice`.
+pub const KEYSPACE_PREFIX: &str = "__keyspace__:";
+
+/// The most messages that can wait for a subscriber to be pushed them,
+/// before it's considered too slow and its subscription is ended.
+const MAX_PENDING_MESSAGES: usize = 1024;
+
+/// A message published to a channel, as a subscriber receives it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub channel: String,
+    /// The pattern the channel matched, if it was subscribed to by pattern
+    pub pattern: Option<String>,
+    pub message: String,
+}
+impl Message {
+    /// How the message is pushed to a subscribed connection.
+    fn reply(&self) -> Value {
+        match &self.pattern {
+            None => json!({
+                "type": "message",
+                "channel": self.channel,
+                "message": self.message,
+            }),
+            Some(pattern) => json!({
+                "type": "pmessage",
+                "pattern": pattern,
+                "channel": self.channel,
+                "message": self.message,
+            }),
+        }
+    }
+}
+
+/// What a single subscriber listens to, and where its messages go.
+struct Subscriber {
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    /// Where its messages go, until it falls too far behind and is cut off
+    messages: Option<mpsc::Sender<Message>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    subscribers: HashMap<u64, Subscriber>,
+}
+
+/// Delivers messages published to channels to whoever subscribed to them.
+///
+/// NOTE: Messages are fire and forget, they're never stored or logged,
+/// so subscribers only get what's published while they're subscribed.
+#[derive(Default)]
+pub struct PubSub(Arc<Mutex<Registry>>);
+impl PubSub {
+    /// Start a subscription, which listens to nothing until it subscribes.
+    pub fn subscription(&self) -> Subscription {
+        let (sender, messages) = mpsc::channel(MAX_PENDING_MESSAGES);
+        let mut registry = lock(&self.0);
+        registry.next_id += 1;
+        let id = registry.next_id;
+        registry.subscribers.insert(
+            id,
+            Subscriber {
+                channels: HashSet::new(),
+                patterns: HashSet::new(),
+                messages: Some(sender),
+            },
+        );
+
+        Subscription {
+            id,
+            registry: self.0.clone(),
+            messages,
+        }
+    }
+
+    /// Publish a message to a channel, returning how many subscribers got it.
+    ///
+    /// NOTE: A subscriber gets the message once for the channel and once
+    /// more for every pattern of its own the channel matches.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        self.deliver(&[channel.to_string()], None, message)
+    }
+
+    /// Whether anyone is subscribed, so keyspace notifications are worth publishing.
+    pub fn is_watched(&self) -> bool {
+        !lock(&self.0).subscribers.is_empty()
+    }
+
+    /// Publish a keyspace notification of a change, as its operation followed
+    /// by the key path that changed, on the channel of that key path, on the
+    /// channel of every ancestor, whose value changed along with it, and on
+    /// every channel subscribed to below it, whose value it replaced.
+    ///
+    /// NOTE: A pattern matching several of those channels gets the
+    /// notification once, on the channel of the deepest key path, and one
+    /// that could only match channels below it gets it on the key path's own.
+    pub fn notify(&self, change: &Change) {
+        let path = change.path();
+        let message = format!("{} {}", change.operation(), path.join("."));
+        let channels = (1..=path.len())
+            .rev()
+            .map(|depth| format!("{KEYSPACE_PREFIX}{}", path[..depth].join(".")))
+            .collect::<Vec<_>>();
+        self.deliver(&channels, Some(&channels[0]), &message);
+    }
+
+    /// Deliver a message published to any of `channels` to every channel
+    /// subscribed to, and once to every pattern matching the first of them,
+    /// as well as to the channels and patterns below `below`, if given.
+    fn deliver(&self, channels: &[String], below: Option<&str>, message: &str) -> usize {
+        let mut registry = lock(&self.0);
+        let mut received = 0;
+        for subscriber in registry.subscribers.values_mut() {
+            let exact = channels
+                .iter()
+                .filter(|channel| subscriber.channels.contains(*channel))
+                .chain(
+                    subscriber
+                        .channels
+                        .iter()
+                        .filter(|channel| below.is_some_and(|below| is_below(channel, below))),
+                )
+                .map(|channel| (channel, None));
+            let patterns = subscriber.patterns.iter().filter_map(|pattern| {
+                let channel = channels
+                    .iter()
+                    .find(|channel| glob_match(pattern, channel))
+                    .or_else(|| {
+                        below
+                            .is_some_and(|below| glob_match_below(pattern, below))
+                            .then(|| &channels[0])
+                    })?;
+                Some((channel, Some(pattern.clone())))
+            });
+            for (channel, pattern) in exact.chain(patterns) {
+                let message = Message {
+                    channel: channel.clone(),
+                    pattern,
+                    message: message.to_string(),
+                };
+                let Some(messages) = &subscriber.messages else {
+                    break;
+                };
+                match messages.try_send(message) {
+                    Ok(()) => received += 1,
+                    // Dropping the sender ends the subscription once the
+                    // messages already waiting have been pushed
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        STATS.slow_consumers.fetch_add(1, Ordering::Relaxed);
+                        subscriber.messages = None;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => {}
+                }
+            }
+        }
+        received
+    }
+}
+
+/// The channels and patterns a connection is subscribed to,
+/// which it's unsubscribed from all of once dropped.
+pub struct Subscription {
+    id: u64,
+    registry: Arc<Mutex<Registry>>,
+    messages: mpsc::Receiver<Message>,
+}
+impl Subscription {
+    /// Change what's subscribed to, returning how many
+    /// channels and patterns are subscribed to after.
+    fn update(&self, change: impl FnOnce(&mut Subscriber)) -> usize {
+        let mut registry = lock(&self.registry);
+        let subscriber = registry
+            .subscribers
+            .get_mut(&self.id)
+            .expect("subscribers are registered until dropped");
+        change(subscriber);
+        subscriber.channels.len() + subscriber.patterns.len()
+    }
+
+    pub fn subscribe(&self, channel: &str) -> usize {
+        self.update(|subscriber| {
+            subscriber.channels.insert(channel.to_string());
+        })
+    }
+
+    pub fn psubscribe(&self, pattern: &str) -> usize {
+        self.update(|subscriber| {
+            subscriber.patterns.insert(pattern.to_string());
+        })
+    }
+
+    pub fn unsubscribe(&self, channel: &str) -> usize {
+        self.update(|subscriber| {
+            subscriber.channels.remove(channel);
+        })
+    }
+
+    pub fn punsubscribe(&self, pattern: &str) -> usize {
+        self.update(|subscriber| {
+            subscriber.patterns.remove(pattern);
+        })
+    }
+
+    /// How many channels and patterns are subscribed to.
+    pub fn count(&self) -> usize {
+        self.update(|_| {})
+    }
+
+    /// The channels subscribed to, in no particular order.
+    pub fn channels(&self) -> Vec<String> {
+        let registry = lock(&self.registry);
+        registry.subscribers[&self.id]
+            .channels
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// The patterns subscribed to, in no particular order.
+    pub fn patterns(&self) -> Vec<String> {
+        let registry = lock(&self.registry);
+        registry.subscribers[&self.id]
+            .patterns
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Wait for the next message published to what's subscribed to, returning
+    /// `None` once the subscriber fell too far behind and was cut off.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.messages.recv().await
+    }
+}
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        lock(&self.registry).subscribers.remove(&self.id);
+    }
+}
+
+/// Lock the registry, even if a thread panicked while holding it,
+/// since none of its updates can leave it half done.
+fn lock(registry: &Mutex<Registry>) -> MutexGuard<'_, Registry> {
+    registry.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A request to change what a connection is subscribed to.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Subscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    /// Unsubscribe from some channels, or every one of them when none are given
+    Unsubscribe(Vec<String>),
+    /// Unsubscribe from some patterns, or every one of them when none are given
+    PUnsubscribe(Vec<String>),
+}
+
+/// Parse a `SUBSCRIBE`, `PSUBSCRIBE`, `UNSUBSCRIBE` or `PUNSUBSCRIBE`
+/// request, returning `None` when it's none of them.
+///
+/// NOTE: Channels and patterns are split like the arguments of any other
+/// command, so they can be quoted to hold whitespace.
+pub fn parse(request: &str) -> Option<Result<Request, Error>> {
+    let mut tokens = Tokens::new(request);
+    let verb = tokens.next().ok()??;
+    // Only the rest of a subscription request is worth splitting into tokens
+    let _ = parse_args(&verb, Vec::new())?;
+    match tokens.all() {
+        Ok(names) => parse_args(&verb, names),
+        Err(e) => Some(Err(e.into())),
+    }
+}
+
+/// Parse a subscription request already split into its verb and the
+/// channels or patterns, returning `None` when it's not one.
+pub fn parse_args(verb: &str, names: Vec<String>) -> Option<Result<Request, Error>> {
+    let verb = verb.to_uppercase();
+    Some(match verb.as_str() {
+        // Subscribing to nothing at all is a mistake, unlike unsubscribing from everything
+        "SUBSCRIBE" | "PSUBSCRIBE" if names.is_empty() => Err(Error::WrongArity(verb)),
+        "SUBSCRIBE" => Ok(Request::Subscribe(names)),
+        "PSUBSCRIBE" => Ok(Request::PSubscribe(names)),
+        "UNSUBSCRIBE" => Ok(Request::Unsubscribe(names)),
+        "PUNSUBSCRIBE" => Ok(Request::PUnsubscribe(names)),
+        _ => return None,
+    })
+}
+
+/// Serve a connection that subscribed with `request`, pushing it every message
+/// published to what it's subscribed to, until it unsubscribes from everything.
+/// Returns whether the client is still connected, to be served as usual.
+///
+/// NOTE: While subscribed, a connection can only change its subscriptions or
+/// `PING`, and since subscribers mostly listen, it's never disconnected for idling.
+pub async fn serve_subscribed<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    output: &Output,
+    framing: Framing,
+    id: Option<&str>,
+    request: Request,
+    fabric: &ThreadSafeFabric,
+) -> Result<bool, Error> {
+    let (mut subscription, limits) = {
+        let fabric = fabric.read().await;
+        (fabric.pubsub.subscription(), fabric.limits)
+    };
+    for ack in acknowledge(&subscription, request) {
+        output.send(framing.encode(&frame::tag(id, &ack)))?;
+    }
+
+    while subscription.count() > 0 {
+        // Reads can't be cancelled without losing what they read so far,
+        // so the same read carries on across every message pushed meanwhile
+        let read = framing.read(reader, limits.max_request_size);
+        tokio::pin!(read);
+        let client_input = loop {
+            tokio::select! {
+                client_input = &mut read => break client_input,
+                message = subscription.recv() => {
+                    let Some(message) = message else {
+                        let e = fell_behind();
+                        output.send(framing.encode(&e.reply()))?;
+                        return Err(e);
+                    };
+                    let push = format!("{}\n", message.reply());
+                    output.send(framing.encode(push.as_bytes()))?;
+                }
+                _ = SHUTDOWN.requested() => return Ok(false),
+            }
+        };
+        let client_input = match client_input {
+            Ok(Some(client_input)) => client_input,
+            Ok(None) => return Ok(false),
+            Err(e @ (Error::Protocol(_) | Error::LimitExceeded(_))) => {
+                output.send(framing.encode(&e.reply()))?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let Ok(client_input) = std::str::from_utf8(&client_input) else {
+            let e = Error::Protocol("Request Is Not UTF-8".to_string());
+            output.send(framing.encode(&e.reply()))?;
+            continue;
+        };
+        let client_input = client_input.trim();
+        if client_input.is_empty() && framing == Framing::Lines {
+            continue;
+        }
+
+        let (id, client_input) = frame::split_tag(client_input);
+        let replies = match parse(client_input) {
+            Some(Ok(request)) => acknowledge(&subscription, request),
+            Some(Err(e)) => vec![e.reply()],
+            None if client_input.eq_ignore_ascii_case("PING") => {
+                vec![format!("{}\n", json!({"type": "pong"})).into_bytes()]
+            }
+            None => {
+                let e = Error::Protocol(
+                    "Only (P)SUBSCRIBE, (P)UNSUBSCRIBE And PING Are Allowed While Subscribed"
+                        .to_string(),
+                );
+                vec![e.reply()]
+            }
+        };
+        for reply in replies {
+            output.send(framing.encode(&frame::tag(id, &reply)))?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// The error a subscriber that fell too far behind is disconnected with.
+pub fn fell_behind() -> Error {
+    Error::LimitExceeded(format!(
+        "Subscriber Fell More Than {MAX_PENDING_MESSAGES} Messages Behind"
+    ))
+}
+
+/// The acknowledgement of a single channel or pattern being subscribed
+/// to or unsubscribed from, with how many are subscribed to after it.
+pub struct Ack {
+    /// The request's verb, in lowercase, like `subscribe`
+    pub kind: &'static str,
+    /// The channel or pattern, unless there was none to unsubscribe from
+    pub name: Option<String>,
+    pub count: usize,
+}
+
+/// Carry out a request on a subscription, acknowledging every channel or pattern.
+pub fn apply(subscription: &Subscription, request: Request) -> Vec<Ack> {
+    let (kind, names, apply): (_, _, fn(&Subscription, &str) -> usize) = match request {
+        Request::Subscribe(names) => ("subscribe", names, Subscription::subscribe),
+        Request::PSubscribe(names) => ("psubscribe", names, Subscription::psubscribe),
+        Request::Unsubscribe(names) if names.is_empty() => (
+            "unsubscribe",
+            subscription.channels(),
+            Subscription::unsubscribe,
+        ),
+        Request::Unsubscribe(names) => ("unsubscribe", names, Subscription::unsubscribe),
+        Request::PUnsubscribe(names) if names.is_empty() => (
+            "punsubscribe",
+            subscription.patterns(),
+            Subscription::punsubscribe,
+        ),
+        Request::PUnsubscribe(names) => ("punsubscribe", names, Subscription::punsubscribe),
+    };
+
+    // Unsubscribing from everything when there's nothing is still acknowledged
+    if names.is_empty() {
+        let count = subscription.count();
+        return vec![Ack {
+            kind,
+            name: None,
+            count,
+        }];
+    }
+    names
+        .into_iter()
+        .map(|name| Ack {
+            kind,
+            count: apply(subscription, &name),
+            name: Some(name),
+        })
+        .collect()
+}
+
+/// Carry out a request on a subscription, returning a JSON reply
+/// acknowledging every channel or pattern.
+fn acknowledge(subscription: &Subscription, request: Request) -> Vec<Vec<u8>> {
+    apply(subscription, request)
+        .into_iter()
+        .map(|ack| {
+            let field = if ack.kind.starts_with('p') {
+                "pattern"
+            } else {
+                "channel"
+            };
+            let ack = json!({"type": ack.kind, field: ack.name, "count": ack.count});
+            format!("{ack}\n").into_bytes()
+        })
+        .collect()
+}
+
+/// Whether `text` matches a glob `pattern`, where `*` matches any run of
+/// characters, `?` any one, `[...]` any one in a set or range, or not in it
+/// when it starts with `^`, and `\` matches the character after it as is.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    // Where to retry from after the last `*`, having it match one more character
+    let mut retry = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            retry = Some((p, t));
+            continue;
+        }
+        if let Some(next) = match_one(&pattern, p, text[t]) {
+            (p, t) = (next, t + 1);
+            continue;
+        }
+        let Some((star_p, star_t)) = retry else {
+            return false;
+        };
+        (p, t) = (star_p, star_t + 1);
+        retry = Some((star_p, star_t + 1));
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Whether a channel is for a key path below that of `above`.
+fn is_below(channel: &str, above: &str) -> bool {
+    channel
+        .strip_prefix(above)
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Whether a glob pattern is for channels below `channel`, having more
+/// dot separated segments than it, the first of which match its own.
+fn glob_match_below(pattern: &str, channel: &str) -> bool {
+    let mut segments = pattern.split('.');
+    let above = channel.split('.').all(|key| {
+        segments
+            .next()
+            .is_some_and(|segment| glob_match(segment, key))
+    });
+    above && segments.next().is_some()
+}
+
+/// Match a single character against the pattern at `p`, returning
+/// where the pattern carries on from if it matches.
+fn match_one(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match *pattern.get(p)? {
+        '?' => Some(p + 1),
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        // A `[` that's never closed is just a `[`
+        '[' => match match_class(pattern, p + 1, c) {
+            Some((matched, next)) => matched.then_some(next),
+            None => (c == '[').then_some(p + 1),
+        },
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+/// Match a character against the set starting at `p`, just after its
+/// `[`, returning whether it matched and where the set ends, if it does.
+fn match_class(pattern: &[char], mut p: usize, c: char) -> Option<(bool, usize)> {
+    let negated = pattern.get(p) == Some(&'^');
+    if negated {
+        p += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match *pattern.get(p)? {
+            ']' => return Some((matched != negated, p + 1)),
+            '\\' => {
+                matched |= *pattern.get(p + 1)? == c;
+                p += 2;
+            }
+            low => match (pattern.get(p + 1), pattern.get(p + 2)) {
+                (Some('-'), Some(&high)) if high != ']' => {
+                    matched |= (low..=high).contains(&c);
+                    p += 3;
+                }
+                _ => {
+                    matched |= low == c;
+                    p += 1;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_glob_patterns() {
+        assert!(glob_match("news.*", "news.sports"));
+        assert!(glob_match("news.*", "news."));
+        assert!(!glob_match("news.*", "news"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("*.*.done", "jobs.42.done"));
+        assert!(!glob_match("*.*.done", "jobs.42.failed"));
+
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("shard-[0-9]", "shard-7"));
+        assert!(!glob_match("shard-[0-9]", "shard-x"));
+
+        // Escaped and unclosed special characters match themselves
+        assert!(glob_match(r"what\?", "what?"));
+        assert!(!glob_match(r"what\?", "whats"));
+        assert!(glob_match(r"\*", "*"));
+        assert!(!glob_match(r"\*", "x"));
+        assert!(glob_match("[oops", "[oops"));
+    }
+
+    #[test]
+    fn matches_glob_patterns_below_channels() {
+        assert!(glob_match_below("strategies.*.open_trade", "strategies.42"));
+        assert!(glob_match_below(
+            "strategies.4?.open_trade",
+            "strategies.42"
+        ));
+        assert!(glob_match_below("strategies.*", "strategies"));
+        assert!(!glob_match_below("strategies.*", "strategies.42"));
+        assert!(!glob_match_below(
+            "strategies.*.open_trade",
+            "strategies.42.closed"
+        ));
+        assert!(!glob_match_below(
+            "strategies.7.open_trade",
+            "strategies.42"
+        ));
+    }
+
+    #[test]
+    fn parses_subscription_requests() {
+        assert!(parse("GET news").is_none());
+        assert_eq!(
+            parse("subscribe news weather").unwrap().unwrap(),
+            Request::Subscribe(vec!["news".into(), "weather".into()])
+        );
+        assert_eq!(
+            parse("PSUBSCRIBE news.*").unwrap().unwrap(),
+            Request::PSubscribe(vec!["news.*".into()])
+        );
+        assert_eq!(
+            parse("UNSUBSCRIBE").unwrap().unwrap(),
+            Request::Unsubscribe(vec![])
+        );
+        assert!(matches!(
+            parse("SUBSCRIBE"),
+            Some(Err(Error::WrongArity(_)))
+        ));
+
+        // Like PUBLISH, a channel can be quoted to hold whitespace
+        assert_eq!(
+            parse("SUBSCRIBE \"my channel\"").unwrap().unwrap(),
+            Request::Subscribe(vec!["my channel".into()])
+        );
+        assert!(matches!(
+            parse("SUBSCRIBE \"my channel"),
+            Some(Err(Error::Syntax(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn delivers_messages_to_matching_subscribers() {
+        let pubsub = PubSub::default();
+        let mut news = pubsub.subscription();
+        let mut everything = pubsub.subscription();
+        assert_eq!(news.subscribe("news"), 1);
+        assert_eq!(everything.psubscribe("*"), 1);
+        assert_eq!(everything.subscribe("news"), 2);
+
+        assert_eq!(pubsub.publish("news", "hi"), 3);
+        assert_eq!(pubsub.publish("weather", "sunny"), 1);
+        let message = |channel: &str, pattern: Option<&str>, message: &str| Message {
+            channel: channel.to_string(),
+            pattern: pattern.map(str::to_string),
+            message: message.to_string(),
+        };
+        assert_eq!(news.recv().await, Some(message("news", None, "hi")));
+        assert_eq!(everything.recv().await, Some(message("news", None, "hi")));
+        assert_eq!(
+            everything.recv().await,
+            Some(message("news", Some("*"), "hi"))
+        );
+        assert_eq!(
+            everything.recv().await,
+            Some(message("weather", Some("*"), "sunny"))
+        );
+
+        // Unsubscribing, or dropping the subscription, stops the messages
+        assert_eq!(everything.punsubscribe("*"), 1);
+        drop(news);
+        assert_eq!(pubsub.publish("news", "bye"), 1);
+        assert_eq!(pubsub.publish("weather", "rain"), 0);
+    }
+
+    #[tokio::test]
+    async fn cuts_off_subscribers_that_fall_behind() {
+        let pubsub = PubSub::default();
+        let mut slow = pubsub.subscription();
+        slow.subscribe("news");
+
+        for i in 0..MAX_PENDING_MESSAGES {
+            assert_eq!(pubsub.publish("news", &i.to_string()), 1);
+        }
+        assert_eq!(pubsub.publish("news", "one too many"), 0);
+        assert_eq!(pubsub.publish("news", "and more"), 0);
+
+        // What was already waiting is still pushed, then the subscription ends
+        for _ in 0..MAX_PENDING_MESSAGES {
+            assert!(slow.recv().await.is_some());
+        }
+        assert_eq!(slow.recv().await, None);
+    }
+}
@@ -0,0 +1,613 @@
+use crate::{
+    aof,
+    command::Command,
+    dump, frame,
+    output::Output,
+    pubsub::{self, Ack, Message, Request},
+    shutdown::{ShutdownMode, SHUTDOWN},
+    stats::STATS,
+    Error, ThreadSafeFabric,
+};
+use serde_json::{json, Value};
+use tokio::io::AsyncBufRead;
+
+/// The first byte of a RESP array, which is how RESP clients like
+/// `redis-cli` send every command, and no Fabric command starts with.
+pub const ARRAY_PREFIX: u8 = b'*';
+
+/// The RESP protocol version a client speaks, negotiated with `HELLO`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// A reply to a RESP command.
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// A short status like `OK`
+    Status(String),
+    /// An error message
+    Error(String),
+    /// A value in cache, encoded as its RESP equivalent
+    Value(Value),
+    /// Something pushed to a subscribed client, as an array in RESP2
+    Push(Vec<Value>),
+}
+impl Reply {
+    /// Encode the reply in the RESP version the client speaks.
+    pub fn encode(&self, protocol: Protocol) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Reply::Status(status) => out.extend(format!("+{}\r\n", one_line(status)).bytes()),
+            Reply::Error(e) => out.extend(format!("-ERR {}\r\n", one_line(e)).bytes()),
+            Reply::Value(value) => encode_value(value, protocol, &mut out),
+            Reply::Push(values) => {
+                let prefix = if protocol == Protocol::Resp3 {
+                    '>'
+                } else {
+                    '*'
+                };
+                out.extend(format!("{prefix}{}\r\n", values.len()).bytes());
+                for value in values {
+                    encode_value(value, protocol, &mut out);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Serve a client speaking RESP until it disconnects.
+///
+/// NOTE: Clients start out speaking RESP2, like with Redis, until they
+/// switch to RESP3 with `HELLO 3`.
+pub async fn serve<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    output: &Output,
+    fabric: &ThreadSafeFabric,
+) -> Result<(), Error> {
+    let mut protocol = Protocol::Resp2;
+    let limits = fabric.read().await.limits;
+
+    loop {
+        let read = frame::until_idle_or_shutdown(
+            limits.idle_timeout(),
+            read_command(reader, limits.max_request_size),
+        );
+        let args = match read.await {
+            Some(Ok(Some(args))) => args
+                .into_iter()
+                .map(String::from_utf8)
+                .collect::<Result<Vec<_>, _>>(),
+            Some(Ok(None)) | None => return Ok(()),
+            Some(Err(e)) => {
+                // The stream can't be trusted after a framing error or an
+                // oversized command, so hang up
+                output.send(Reply::Error(e.to_string()).encode(protocol))?;
+                return Err(e);
+            }
+        };
+
+        let Ok(args) = args else {
+            let reply = Reply::Error("Protocol Error: Arguments Must Be UTF-8".to_string());
+            output.send(reply.encode(protocol))?;
+            continue;
+        };
+
+        // Subscribing switches the connection to having messages pushed to it
+        if let Some((verb, names)) = args.split_first() {
+            match pubsub::parse_args(verb, names.to_vec()) {
+                Some(Ok(request)) => {
+                    if !serve_subscribed(reader, output, protocol, request, fabric).await? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Some(Err(e)) => {
+                    output.send(Reply::Error(e.to_string()).encode(protocol))?;
+                    continue;
+                }
+                None => {}
+            }
+        }
+
+        let reply = handle(&args, &mut protocol, fabric)
+            .await
+            .unwrap_or_else(|e| Reply::Error(e.to_string()));
+        output.send(reply.encode(protocol))?;
+    }
+}
+
+/// Serve a client that subscribed with `request`, pushing it every message
+/// published to what it's subscribed to, until it unsubscribes from everything.
+/// Returns whether the client is still connected, to be served as usual.
+///
+/// NOTE: Like with Redis, acknowledgements and messages are arrays, like
+/// `["message", channel, message]`, sent as RESP3 pushes in RESP3.
+async fn serve_subscribed<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    output: &Output,
+    protocol: Protocol,
+    request: Request,
+    fabric: &ThreadSafeFabric,
+) -> Result<bool, Error> {
+    let (mut subscription, limits) = {
+        let fabric = fabric.read().await;
+        (fabric.pubsub.subscription(), fabric.limits)
+    };
+    for ack in pubsub::apply(&subscription, request) {
+        output.send(acknowledge(ack).encode(protocol))?;
+    }
+
+    while subscription.count() > 0 {
+        // Reads can't be cancelled without losing what they read so far,
+        // so the same read carries on across every message pushed meanwhile
+        let read = read_command(reader, limits.max_request_size);
+        tokio::pin!(read);
+        let args = loop {
+            tokio::select! {
+                args = &mut read => break args,
+                message = subscription.recv() => {
+                    let Some(message) = message else {
+                        let e = pubsub::fell_behind();
+                        output.send(Reply::Error(e.to_string()).encode(protocol))?;
+                        return Err(e);
+                    };
+                    output.send(push(message).encode(protocol))?;
+                }
+                _ = SHUTDOWN.requested() => return Ok(false),
+            }
+        };
+        let args = match args {
+            Ok(Some(args)) => args
+                .into_iter()
+                .map(String::from_utf8)
+                .collect::<Result<Vec<_>, _>>(),
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                output.send(Reply::Error(e.to_string()).encode(protocol))?;
+                return Err(e);
+            }
+        };
+        let Ok(args) = args else {
+            let reply = Reply::Error("Protocol Error: Arguments Must Be UTF-8".to_string());
+            output.send(reply.encode(protocol))?;
+            continue;
+        };
+        let Some((verb, names)) = args.split_first() else {
+            continue;
+        };
+
+        let replies = match pubsub::parse_args(verb, names.to_vec()) {
+            Some(Ok(request)) => pubsub::apply(&subscription, request)
+                .into_iter()
+                .map(acknowledge)
+                .collect(),
+            Some(Err(e)) => vec![Reply::Error(e.to_string())],
+            None if verb.eq_ignore_ascii_case("PING") => {
+                let message = names.first().cloned().unwrap_or_default();
+                vec![Reply::Push(vec![json!("pong"), Value::String(message)])]
+            }
+            None => {
+                let e = Error::Protocol(
+                    "Only (P)SUBSCRIBE, (P)UNSUBSCRIBE And PING Are Allowed While Subscribed"
+                        .to_string(),
+                );
+                vec![Reply::Error(e.to_string())]
+            }
+        };
+        for reply in replies {
+            output.send(reply.encode(protocol))?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// The push acknowledging a channel or pattern was subscribed to or
+/// unsubscribed from, as `[kind, name, count]`.
+fn acknowledge(ack: Ack) -> Reply {
+    Reply::Push(vec![
+        json!(ack.kind),
+        ack.name.map_or(Value::Null, Value::String),
+        json!(ack.count),
+    ])
+}
+
+/// The push of a published message, as `["message", channel, message]`,
+/// or `["pmessage", pattern, channel, message]` when it matched a pattern.
+fn push(message: Message) -> Reply {
+    let mut values = match message.pattern {
+        Some(pattern) => vec![json!("pmessage"), Value::String(pattern)],
+        None => vec![json!("message")],
+    };
+    values.push(Value::String(message.channel));
+    values.push(Value::String(message.message));
+    Reply::Push(values)
+}
+
+/// Read the next command, either as a RESP array of bulk strings or
+/// as an inline command, returning `None` once the client disconnects.
+///
+/// NOTE: Arguments aren't checked to be UTF-8 here, since a command
+/// with any that aren't is answered with an error, not a hang up.
+pub async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    let Some(line) = read_line(reader, max_len).await? else {
+        return Ok(None);
+    };
+
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty());
+        return Ok(Some(args.map(<[u8]>::to_vec).collect()));
+    };
+    let count: usize =
+        parse_len(count).ok_or_else(|| Error::Protocol("Invalid Array Length".to_string()))?;
+
+    // Every header and argument counts towards the size of the command, so
+    // neither a huge count nor huge bulk strings are allocated up front
+    let too_large = || Error::LimitExceeded(format!("Request Is Larger Than {max_len} Bytes"));
+    let mut remaining = max_len.checked_sub(line.len()).ok_or_else(too_large)?;
+    if count > remaining {
+        return Err(too_large());
+    }
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let header = read_line(reader, remaining)
+            .await?
+            .ok_or_else(|| Error::Protocol("Unexpected End Of Stream".to_string()))?;
+        let len: usize = header
+            .strip_prefix(b"$")
+            .and_then(parse_len)
+            .ok_or_else(|| Error::Protocol("Expected A Bulk String".to_string()))?;
+        remaining = remaining
+            .checked_sub(header.len() + len)
+            .ok_or_else(too_large)?;
+
+        let mut arg = frame::read_bytes(reader, len + 2).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(Error::Protocol(
+                "Bulk String Not Terminated By CRLF".to_string(),
+            ));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// Read a CRLF (or just LF) terminated line.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    let Some(mut line) = frame::read_line(reader, max_len).await? else {
+        return Ok(None);
+    };
+    while line.last().is_some_and(|b| *b == b'\r' || *b == b'\n') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parse the length of an array or bulk string.
+fn parse_len(len: &[u8]) -> Option<usize> {
+    std::str::from_utf8(len).ok()?.parse().ok()
+}
+
+/// Handle a RESP command by mapping it onto the Fabric command behind it.
+pub async fn handle(
+    args: &[String],
+    protocol: &mut Protocol,
+    fabric: &ThreadSafeFabric,
+) -> Result<Reply, Error> {
+    let Some((name, args)) = args.split_first() else {
+        return Ok(Reply::Error("Empty Command.".to_string()));
+    };
+    let name = name.to_uppercase();
+    let wrong_arity = || {
+        Ok(Reply::Error(format!(
+            "Wrong Number Of Arguments For {name}."
+        )))
+    };
+
+    match (name.as_str(), args) {
+        ("PING", []) => Ok(Reply::Status("PONG".to_string())),
+        ("PING", [message]) => Ok(Reply::Value(Value::String(message.clone()))),
+        ("HELLO", []) => Ok(hello(*protocol)),
+        ("HELLO", [version]) => {
+            *protocol = match version.as_str() {
+                "2" => Protocol::Resp2,
+                "3" => Protocol::Resp3,
+                _ => {
+                    return Ok(Reply::Error(format!(
+                        "Unsupported Protocol Version {version}."
+                    )))
+                }
+            };
+            Ok(hello(*protocol))
+        }
+        // `redis-cli` asks for the command docs on startup, there are none
+        ("COMMAND", _) => Ok(Reply::Value(Value::Array(Vec::new()))),
+        // Missing keys are nil, like in Redis, but blobs aren't values to get
+        ("GET", [key]) => match fabric.read().await.get(key.split('.').collect()) {
+            Ok(value) => Ok(Reply::Value(value)),
+            Err(e @ Error::WrongType(_)) => Err(e),
+            Err(_) => Ok(Reply::Value(Value::Null)),
+        },
+        ("DUMP", [key]) => match fabric.read().await.get(key.split('.').collect()) {
+            Ok(value) => Ok(Reply::Value(Value::String(dump::serialize(&value)?))),
+            Err(e @ Error::WrongType(_)) => Err(e),
+            Err(_) => Ok(Reply::Value(Value::Null)),
+        },
+        ("STATS", []) => {
+            let limits = fabric.read().await.limits;
+            Ok(Reply::Value(STATS.report(&limits)))
+        }
+        ("BGREWRITEAOF", []) => match aof::start_rewrite(fabric).await {
+            Ok(_) => Ok(Reply::Status(
+                "Background append-only log rewrite started".to_string(),
+            )),
+            Err(e) => Ok(Reply::Error(e.to_string())),
+        },
+        ("SET", [key, value]) => {
+            let (key, value) = (key.clone(), value.clone());
+            run(fabric, Command::Set { key, value }).await
+        }
+        ("REMOVE" | "DEL", [key]) => run(fabric, Command::Remove { key: key.clone() }).await,
+        ("RESTORE", [key, blob, rest @ ..]) if rest.len() <= 1 => {
+            let replace = match rest {
+                [] => false,
+                [flag] if flag.eq_ignore_ascii_case("REPLACE") => true,
+                [flag] => {
+                    let e = Error::Syntax(format!("Unknown Flag \"{flag}\""));
+                    return Ok(Reply::Error(e.to_string()));
+                }
+                _ => unreachable!(),
+            };
+            let (key, blob) = (key.clone(), blob.clone());
+            run(fabric, Command::Restore { key, blob, replace }).await
+        }
+        ("EXPORT", [key, file]) => {
+            let (key, file) = (key.clone(), file.clone());
+            run(fabric, Command::Export { key, file }).await
+        }
+        ("IMPORT", [key, file]) => {
+            let (key, file) = (key.clone(), file.clone());
+            run(fabric, Command::Import { key, file }).await
+        }
+        ("SHUTDOWN", rest) if rest.len() <= 1 => {
+            let mode = match rest.first().map(|flag| flag.parse()).transpose() {
+                Ok(mode) => mode.unwrap_or(ShutdownMode::Default),
+                Err(e) => return Ok(Reply::Error(e.to_string())),
+            };
+            run(fabric, Command::Shutdown { mode }).await
+        }
+        ("PUBLISH", [channel, message]) => {
+            let received = fabric.read().await.pubsub.publish(channel, message);
+            Ok(Reply::Value(Value::from(received)))
+        }
+        (
+            "PING" | "HELLO" | "GET" | "DUMP" | "BGREWRITEAOF" | "SET" | "REMOVE" | "DEL"
+            | "RESTORE" | "EXPORT" | "IMPORT" | "STATS" | "SHUTDOWN" | "PUBLISH",
+            _,
+        ) => wrong_arity(),
+        _ => Ok(Reply::Error(Error::UnsupportedCommand(name).to_string())),
+    }
+}
+
+/// Run a Fabric command that only replies `OK` on success.
+async fn run(fabric: &ThreadSafeFabric, cmd: Command) -> Result<Reply, Error> {
+    match cmd.handle(fabric).await {
+        Ok(_) => Ok(Reply::Status("OK".to_string())),
+        Err(e) => Ok(Reply::Error(e.to_string())),
+    }
+}
+
+/// The reply to `HELLO`, describing the server.
+fn hello(protocol: Protocol) -> Reply {
+    Reply::Value(json!({
+        "server": "fabric",
+        "version": env!("CARGO_PKG_VERSION"),
+        "proto": if protocol == Protocol::Resp3 { 3 } else { 2 },
+    }))
+}
+
+/// Encode a value as its RESP equivalent.
+///
+/// NOTE: RESP2 has no maps, booleans, doubles or a null of its own, so
+/// objects are flattened into arrays of keys and values, booleans become
+/// integers, floats bulk strings and null a null bulk string.
+fn encode_value(value: &Value, protocol: Protocol, out: &mut Vec<u8>) {
+    let resp3 = protocol == Protocol::Resp3;
+    match value {
+        Value::Null if resp3 => out.extend(b"_\r\n"),
+        Value::Null => out.extend(b"$-1\r\n"),
+        Value::Bool(b) if resp3 => out.extend(if *b { b"#t\r\n" } else { b"#f\r\n" }),
+        Value::Bool(b) => out.extend(format!(":{}\r\n", *b as u8).bytes()),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => out.extend(format!(":{n}\r\n").bytes()),
+            None if resp3 => out.extend(format!(",{n}\r\n").bytes()),
+            None => encode_bulk(&n.to_string(), out),
+        },
+        Value::String(s) => encode_bulk(s, out),
+        Value::Array(values) => {
+            out.extend(format!("*{}\r\n", values.len()).bytes());
+            for value in values {
+                encode_value(value, protocol, out);
+            }
+        }
+        Value::Object(map) => {
+            if resp3 {
+                out.extend(format!("%{}\r\n", map.len()).bytes());
+            } else {
+                out.extend(format!("*{}\r\n", map.len() * 2).bytes());
+            }
+            for (key, value) in map {
+                encode_bulk(key, out);
+                encode_value(value, protocol, out);
+            }
+        }
+    }
+}
+
+/// Encode a bulk string.
+fn encode_bulk(s: &str, out: &mut Vec<u8>) {
+    out.extend(format!("${}\r\n", s.len()).bytes());
+    out.extend(s.bytes());
+    out.extend(b"\r\n");
+}
+
+/// Collapse a message onto one line, since status and error replies can't span lines.
+fn one_line(message: &str) -> String {
+    message.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fabric::Fabric;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn encode(value: Value, protocol: Protocol) -> String {
+        String::from_utf8(Reply::Value(value).encode(protocol)).unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn encodes_values_as_resp3() {
+        let value = json!({"name": "bob", "scores": [1, 2.5], "admin": false, "team": null});
+        assert_eq!(
+            encode(value, Protocol::Resp3),
+            "%4\r\n$5\r\nadmin\r\n#f\r\n$4\r\nname\r\n$3\r\nbob\r\n\
+             $6\r\nscores\r\n*2\r\n:1\r\n,2.5\r\n$4\r\nteam\r\n_\r\n"
+        );
+    }
+
+    #[test]
+    fn encodes_values_as_resp2() {
+        let value = json!({"admin": true, "team": null, "score": 2.5});
+        assert_eq!(
+            encode(value, Protocol::Resp2),
+            "*6\r\n$5\r\nadmin\r\n:1\r\n$5\r\nscore\r\n$3\r\n2.5\r\n$4\r\nteam\r\n$-1\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(Reply::Error("Bad\nThing".into()).encode(Protocol::Resp2)).unwrap(),
+            "-ERR Bad Thing\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_arrays_and_inline_commands() {
+        for mut input in [&b"*1\r\n$3\r\nGETxx"[..], b"*x\r\n", b"*1\r\nGET\r\n"] {
+            assert!(read_command(&mut input, 64).await.is_err());
+        }
+
+        // Huge counts and bulk strings are refused before they're allocated
+        for mut input in [&b"*9999999999\r\n$1\r\na\r\n"[..], b"*1\r\n$9999999999\r\n"] {
+            assert!(matches!(
+                read_command(&mut input, 64).await,
+                Err(Error::LimitExceeded(_))
+            ));
+        }
+
+        // A bulk string within the limit is read as its bytes arrive, so
+        // claiming a huge one without sending it costs nothing up front
+        let mut input: &[u8] = b"*1\r\n$536870000\r\nGET";
+        assert!(read_command(&mut input, usize::MAX).await.is_err());
+
+        let mut input: &[u8] =
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$8\r\n{\"b\": 1}\r\nPING  hi\r\n*1\r\n$1\r\n\xff\r\n";
+        let bytes = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        assert_eq!(
+            read_command(&mut input, 64).await.unwrap(),
+            Some(bytes(&["SET", "a", "{\"b\": 1}"]))
+        );
+        assert_eq!(
+            read_command(&mut input, 64).await.unwrap(),
+            Some(bytes(&["PING", "hi"]))
+        );
+        assert_eq!(
+            read_command(&mut input, 64).await.unwrap(),
+            Some(vec![vec![0xff]])
+        );
+        assert_eq!(read_command(&mut input, 64).await.unwrap(), None);
+    }
+
+    #[test]
+    fn encodes_pushes() {
+        let message = Message {
+            channel: "news.sports".into(),
+            pattern: Some("news.*".into()),
+            message: "hi".into(),
+        };
+        assert_eq!(
+            String::from_utf8(push(message).encode(Protocol::Resp3)).unwrap(),
+            ">4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$11\r\nnews.sports\r\n$2\r\nhi\r\n"
+        );
+
+        let ack = Ack {
+            kind: "unsubscribe",
+            name: None,
+            count: 0,
+        };
+        assert_eq!(
+            String::from_utf8(acknowledge(ack).encode(Protocol::Resp2)).unwrap(),
+            "*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_commands_onto_fabric() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+        let mut protocol = Protocol::Resp2;
+
+        for (command, expected) in [
+            (
+                &["set", "user", "{\"name\":\n \"a b\"}"][..],
+                Reply::Status("OK".into()),
+            ),
+            (&["GET", "user.name"], Reply::Value(json!("a b"))),
+            (&["SET", "a b", "1"], Reply::Status("OK".into())),
+            (&["GET", "a b"], Reply::Value(json!(1))),
+            (&["DEL", "user"], Reply::Status("OK".into())),
+            (&["GET", "user"], Reply::Value(Value::Null)),
+            (&["PING"], Reply::Status("PONG".into())),
+            (&["PUBLISH", "news", "hi"], Reply::Value(json!(0))),
+        ] {
+            let reply = handle(&args(command), &mut protocol, &fabric).await;
+            assert_eq!(reply.unwrap(), expected, "{command:?}");
+        }
+
+        let reply = handle(&args(&["HELLO", "3"]), &mut protocol, &fabric).await;
+        assert!(matches!(reply.unwrap(), Reply::Value(Value::Object(_))));
+        assert_eq!(protocol, Protocol::Resp3);
+
+        for command in [
+            &["GET"][..],
+            &["SET", "a", "not json"],
+            &["RESTORE", "a", "b", "FORCE"],
+            &["FLUSHALL"],
+        ] {
+            let reply = handle(&args(command), &mut protocol, &fabric).await;
+            assert!(matches!(reply.unwrap(), Reply::Error(_)), "{command:?}");
+        }
+
+        // A blob isn't a missing value
+        fabric.write().await.set_blob("img", vec![1]).unwrap();
+        for command in [&["GET", "img"][..], &["DUMP", "img"]] {
+            let reply = handle(&args(command), &mut protocol, &fabric).await;
+            assert!(matches!(reply, Err(Error::WrongType(_))), "{command:?}");
+        }
+    }
+}
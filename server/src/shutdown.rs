@@ -0,0 +1,171 @@
+use crate::{stats::STATS, Error, Fabric, ThreadSafeFabric};
+use std::{
+    sync::{atomic::Ordering, OnceLock},
+    time::Duration,
+};
+use tokio::sync::{Notify, RwLockWriteGuard};
+
+/// Whether the server has been asked to shut down, and how.
+pub static SHUTDOWN: Shutdown = Shutdown::new();
+
+/// What's done with the append-only log when shutting down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownMode {
+    /// Flush the append-only log to disk, if it's enabled
+    Default,
+    /// Flush the append-only log to disk, refusing to shut down without one
+    Save,
+    /// Leave the append-only log as is, without flushing it
+    NoSave,
+}
+impl std::str::FromStr for ShutdownMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "SAVE" => Ok(ShutdownMode::Save),
+            "NOSAVE" => Ok(ShutdownMode::NoSave),
+            _ => Err(Error::Syntax(format!("Unknown Flag \"{s}\""))),
+        }
+    }
+}
+
+/// A request to shut down, which every connection and
+/// the server itself can wait on.
+pub struct Shutdown {
+    /// How the server was asked to shut down, once it has been
+    mode: OnceLock<ShutdownMode>,
+    notify: Notify,
+}
+impl Shutdown {
+    const fn new() -> Self {
+        Shutdown {
+            mode: OnceLock::new(),
+            notify: Notify::const_new(),
+        }
+    }
+
+    /// Ask the server to shut down, returning whether it wasn't already.
+    ///
+    /// NOTE: Only the first request counts, later ones don't change the mode.
+    pub fn request(&self, mode: ShutdownMode) -> bool {
+        let first = self.mode.set(mode).is_ok();
+        self.notify.notify_waiters();
+        first
+    }
+
+    /// Wait for the server to be asked to shut down, returning how.
+    pub async fn requested(&self) -> ShutdownMode {
+        loop {
+            // Register interest before checking, so a request in between isn't missed
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(mode) = self.mode.get() {
+                return *mode;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Ask the server to shut down on the first SIGTERM or SIGINT.
+pub fn spawn_signal_handler() {
+    tokio::spawn(async move {
+        let signal = wait_for_signal().await;
+        if SHUTDOWN.request(ShutdownMode::Default) {
+            println!("Received {signal}, shutting down");
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut sigterm), Ok(mut sigint)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        eprintln!("Error installing signal handlers, shut down with SHUTDOWN instead");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    if tokio::signal::ctrl_c().await.is_err() {
+        eprintln!("Error installing signal handlers, shut down with SHUTDOWN instead");
+        return std::future::pending().await;
+    }
+    "Ctrl-C"
+}
+
+/// Wait up to `timeout` for the connected clients to finish the commands
+/// they're running and disconnect, returning how many are still connected.
+pub async fn drain(timeout: Duration) -> usize {
+    let connected = || STATS.connected_clients.load(Ordering::SeqCst);
+    let drained = tokio::time::timeout(timeout, async {
+        while connected() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    let _ = drained.await;
+    connected()
+}
+
+/// Flush the append-only log to disk as asked to by `mode`, holding the
+/// write lock on `fabric` after, so nothing else is written to it.
+pub async fn persist(
+    fabric: &ThreadSafeFabric,
+    mode: ShutdownMode,
+) -> Result<RwLockWriteGuard<'_, Fabric>, Error> {
+    let fabric = fabric.write().await;
+    match (mode, fabric.aof.as_ref()) {
+        (ShutdownMode::NoSave, _) => println!("Not flushing the append-only log, as asked"),
+        (_, Some(aof)) => {
+            aof.flush()?;
+            println!("Flushed the append-only log to disk");
+        }
+        (ShutdownMode::Save, None) => return Err(Error::AppendOnlyDisabled),
+        (ShutdownMode::Default, None) => {}
+    }
+    Ok(fabric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn wakes_everyone_waiting_on_the_first_request() {
+        let shutdown: &'static Shutdown = Box::leak(Box::new(Shutdown::new()));
+        let waiting = tokio::spawn(shutdown.requested());
+        tokio::task::yield_now().await;
+
+        assert!(shutdown.request(ShutdownMode::NoSave));
+        assert!(!shutdown.request(ShutdownMode::Save));
+        assert_eq!(waiting.await.unwrap(), ShutdownMode::NoSave);
+
+        // Anyone waiting after the fact hears about it right away
+        assert_eq!(shutdown.requested().await, ShutdownMode::NoSave);
+    }
+
+    #[test]
+    fn parses_shutdown_modes() {
+        assert_eq!("save".parse::<ShutdownMode>().unwrap(), ShutdownMode::Save);
+        assert_eq!(
+            "NOSAVE".parse::<ShutdownMode>().unwrap(),
+            ShutdownMode::NoSave
+        );
+        assert!(matches!(
+            "ABORT".parse::<ShutdownMode>(),
+            Err(Error::Syntax(_))
+        ));
+    }
+}
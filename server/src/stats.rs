@@ -0,0 +1,72 @@
+use crate::fabric::Limits;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The counters about the server's clients.
+pub static STATS: Stats = Stats {
+    connected_clients: AtomicUsize::new(0),
+    total_connections: AtomicU64::new(0),
+    rejected_connections: AtomicU64::new(0),
+    idle_timeouts: AtomicU64::new(0),
+    slow_consumers: AtomicU64::new(0),
+};
+
+/// Counters about the server's clients, since it started.
+pub struct Stats {
+    /// The clients connected right now
+    pub connected_clients: AtomicUsize,
+    /// Every connection accepted, including the rejected ones
+    pub total_connections: AtomicU64,
+    /// The connections turned away for exceeding `maxclients`
+    pub rejected_connections: AtomicU64,
+    /// The clients disconnected for idling longer than `timeout`
+    pub idle_timeouts: AtomicU64,
+    /// The clients disconnected for falling behind reading their replies
+    pub slow_consumers: AtomicU64,
+}
+impl Stats {
+    /// The reply to `STATS`, as a JSON object with the limits in effect.
+    pub fn report(&self, limits: &Limits) -> Value {
+        json!({
+            "connected_clients": self.connected_clients.load(Ordering::Relaxed),
+            "total_connections": self.total_connections.load(Ordering::Relaxed),
+            "rejected_connections": self.rejected_connections.load(Ordering::Relaxed),
+            "idle_timeouts": self.idle_timeouts.load(Ordering::Relaxed),
+            "slow_consumers": self.slow_consumers.load(Ordering::Relaxed),
+            "limits": {
+                "maxclients": limits.max_clients,
+                "timeout": limits.timeout,
+                "client_output_buffer_limit": limits.client_output_buffer_limit,
+                "max_request_size": limits.max_request_size,
+                "max_json_depth": limits.max_json_depth,
+                "max_value_size": limits.max_value_size,
+            },
+        })
+    }
+}
+
+/// A connected client, counted in `connected_clients` until it's dropped.
+pub struct ClientSlot(());
+impl ClientSlot {
+    /// Count a newly connected client, unless `max_clients` are already connected.
+    pub fn claim(max_clients: usize) -> Option<Self> {
+        let claimed =
+            STATS
+                .connected_clients
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connected| {
+                    (connected < max_clients).then_some(connected + 1)
+                });
+        match claimed {
+            Ok(_) => Some(ClientSlot(())),
+            Err(_) => {
+                STATS.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+}
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        STATS.connected_clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
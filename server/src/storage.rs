@@ -0,0 +1,325 @@
+use crate::Error;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use std::path::Path;
+
+/// The length of the nonce stored in front of every encrypted record.
+const NONCE_LEN: usize = 24;
+
+/// How records are compressed on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Store records as they are
+    #[default]
+    None,
+    /// Compress records with zstd, smaller but slower
+    Zstd,
+    /// Compress records with lz4, faster but larger
+    Lz4,
+}
+impl std::str::FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "none" | "no" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(Error::InvalidConfig(format!(
+                "\"{s}\" Is Not A Valid Compression (none, zstd, lz4)."
+            ))),
+        }
+    }
+}
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+/// A 256 bit key records are encrypted with.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+impl EncryptionKey {
+    /// Parse a key from its 64 character hex representation.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(Error::InvalidConfig(
+                "The Encryption Key Must Be 64 Hex Characters (32 Bytes).".to_string(),
+            ));
+        }
+
+        let mut key = [0; 32];
+        for (idx, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).map_err(|_| {
+                Error::InvalidConfig("The Encryption Key Must Be Hex Encoded.".to_string())
+            })?;
+        }
+
+        Ok(Self(key))
+    }
+
+    /// Read a hex encoded key from a file.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Self::from_hex(&std::fs::read_to_string(path)?)
+    }
+}
+/// Never leak the key into logs.
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EncryptionKey(<redacted>)")
+    }
+}
+
+/// How records are compressed and encrypted on disk.
+///
+/// An encoded record looks like `~<transforms> <base64>`, where the
+/// transforms (ex: `zstd+aead`) say how to decode it. Records without
+/// the `~` prefix are stored as they are, so logs written with different
+/// compression (or before any was enabled) still load transparently.
+///
+/// NOTE: Once there's an encryption key, records that aren't encrypted are
+/// rejected, since anyone able to write to the log could have added them,
+/// unless the codec is migrating a log written before there was a key.
+#[derive(Clone, Default)]
+pub struct StorageCodec {
+    compression: Compression,
+    cipher: Option<XChaCha20Poly1305>,
+    /// Whether records that aren't encrypted are decoded despite the key
+    accept_plaintext: bool,
+}
+impl StorageCodec {
+    /// Initialize a new codec.
+    pub fn new(compression: Compression, key: Option<&EncryptionKey>) -> Self {
+        Self {
+            compression,
+            cipher: key.map(|key| XChaCha20Poly1305::new(&key.0.into())),
+            accept_plaintext: false,
+        }
+    }
+
+    /// Decode records that aren't encrypted too, to load a log
+    /// written before there was an encryption key, once.
+    pub fn accepting_plaintext(self) -> Self {
+        Self {
+            accept_plaintext: true,
+            ..self
+        }
+    }
+
+    /// Whether a record encoded with `transforms` can be trusted,
+    /// which, with an encryption key, it only can when encrypted.
+    fn check_authenticated(&self, transforms: &str) -> Result<(), Error> {
+        let encrypted = transforms.split('+').any(|transform| transform == "aead");
+        if self.cipher.is_none() || self.accept_plaintext || encrypted {
+            return Ok(());
+        }
+        Err(Error::Encryption(
+            "Found An Unencrypted Record While An Encryption Key Is Configured.".to_string(),
+        ))
+    }
+
+    /// Encode a record to be stored on disk.
+    pub fn encode(&self, record: &str) -> Result<String, Error> {
+        let mut transforms = Vec::new();
+        let mut bytes = record.as_bytes().to_vec();
+
+        let compressed = match self.compression {
+            Compression::None => None,
+            Compression::Zstd => Some(("zstd", zstd::encode_all(bytes.as_slice(), 3)?)),
+            Compression::Lz4 => Some(("lz4", lz4_flex::compress_prepend_size(&bytes))),
+        };
+        // Tiny records can grow when compressed, so only keep it if it paid off
+        if let Some((transform, compressed)) = compressed {
+            if compressed.len() < bytes.len() {
+                transforms.push(transform);
+                bytes = compressed;
+            }
+        }
+
+        if let Some(cipher) = &self.cipher {
+            transforms.push("aead");
+            let tag = transforms.join("+");
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: &bytes,
+                        aad: tag.as_bytes(),
+                    },
+                )
+                .map_err(|_| Error::Encryption("Could Not Encrypt Record.".to_string()))?;
+            bytes = [nonce.as_slice(), &ciphertext].concat();
+        }
+
+        if transforms.is_empty() {
+            return Ok(record.to_string());
+        }
+        Ok(format!(
+            "~{} {}",
+            transforms.join("+"),
+            BASE64.encode(bytes)
+        ))
+    }
+
+    /// Decode a record read from disk.
+    pub fn decode(&self, stored: &str) -> Result<String, Error> {
+        let Some(encoded) = stored.strip_prefix('~') else {
+            self.check_authenticated("")?;
+            return Ok(stored.to_string());
+        };
+        let (tag, data) = encoded
+            .split_once(' ')
+            .ok_or_else(|| Error::CorruptLog("Encoded Record Is Missing Its Data.".to_string()))?;
+        self.check_authenticated(tag)?;
+        let mut bytes = BASE64
+            .decode(data)
+            .map_err(|e| Error::CorruptLog(format!("Encoded Record Is Not Valid Base64: {e}")))?;
+
+        for transform in tag.split('+').rev() {
+            bytes = match transform {
+                "aead" => {
+                    let cipher = self.cipher.as_ref().ok_or_else(|| {
+                        Error::Encryption(
+                            "Found An Encrypted Record But No Encryption Key Is Configured."
+                                .to_string(),
+                        )
+                    })?;
+                    if bytes.len() < NONCE_LEN {
+                        return Err(Error::CorruptLog(
+                            "Encrypted Record Is Too Short.".to_string(),
+                        ));
+                    }
+                    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+                    cipher
+                        .decrypt(
+                            XNonce::from_slice(nonce),
+                            Payload {
+                                msg: ciphertext,
+                                aad: tag.as_bytes(),
+                            },
+                        )
+                        .map_err(|_| {
+                            Error::Encryption(
+                                "Could Not Decrypt Record, Is The Encryption Key Right?"
+                                    .to_string(),
+                            )
+                        })?
+                }
+                "zstd" => zstd::decode_all(bytes.as_slice())?,
+                "lz4" => lz4_flex::decompress_size_prepended(&bytes)
+                    .map_err(|e| Error::CorruptLog(format!("Bad lz4 Record: {e}")))?,
+                _ => {
+                    return Err(Error::CorruptLog(format!(
+                        "\"{transform}\" Is Not A Known Record Encoding."
+                    )))
+                }
+            };
+        }
+
+        String::from_utf8(bytes)
+            .map_err(|_| Error::CorruptLog("Decoded Record Is Not Valid UTF-8.".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn record() -> String {
+        format!(
+            "SET strategies {{\"id\": \"b7be9512\", \"account_number\": \"some_id\", \"history\": {:?}}}",
+            vec![2830; 64]
+        )
+    }
+
+    #[test]
+    fn round_trips_every_combination() {
+        let key = EncryptionKey::from_hex(KEY).unwrap();
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            for key in [None, Some(&key)] {
+                let codec = StorageCodec::new(compression, key);
+                let encoded = codec.encode(&record()).unwrap();
+                assert!(!encoded.contains('\n'));
+                assert_eq!(codec.decode(&encoded).unwrap(), record());
+            }
+        }
+    }
+
+    #[test]
+    fn encrypted_records_hide_their_contents() {
+        let key = EncryptionKey::from_hex(KEY).unwrap();
+        let codec = StorageCodec::new(Compression::None, Some(&key));
+
+        let encoded = codec.encode(&record()).unwrap();
+        assert!(encoded.starts_with("~aead "));
+        assert!(!encoded.contains("account_number"));
+    }
+
+    #[test]
+    fn only_keeps_compression_when_it_pays_off() {
+        let codec = StorageCodec::new(Compression::Zstd, None);
+
+        assert_eq!(codec.encode("SET a 1").unwrap(), "SET a 1");
+        assert!(codec.encode(&record()).unwrap().starts_with("~zstd "));
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_key() {
+        let key = EncryptionKey::from_hex(KEY).unwrap();
+        let encoded = StorageCodec::new(Compression::Lz4, Some(&key))
+            .encode(&record())
+            .unwrap();
+
+        let other_key = EncryptionKey::from_hex(&KEY.replace('0', "f")).unwrap();
+        assert!(matches!(
+            StorageCodec::new(Compression::Lz4, Some(&other_key)).decode(&encoded),
+            Err(Error::Encryption(_))
+        ));
+        assert!(matches!(
+            StorageCodec::default().decode(&encoded),
+            Err(Error::Encryption(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unencrypted_records_once_there_is_a_key() {
+        let key = EncryptionKey::from_hex(KEY).unwrap();
+        let codec = StorageCodec::new(Compression::None, Some(&key));
+        let compressed = StorageCodec::new(Compression::Zstd, None)
+            .encode(&record())
+            .unwrap();
+
+        for stored in ["SET a 1", &compressed] {
+            assert!(matches!(codec.decode(stored), Err(Error::Encryption(_))));
+            assert!(codec.clone().accepting_plaintext().decode(stored).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_transforms() {
+        let key = EncryptionKey::from_hex(KEY).unwrap();
+        let codec = StorageCodec::new(Compression::Zstd, Some(&key));
+
+        let encoded = codec.encode(&record()).unwrap();
+        let tampered = encoded.replacen("~zstd+aead", "~aead", 1);
+        assert!(codec.decode(&tampered).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert!(EncryptionKey::from_hex("abc").is_err());
+        assert!(EncryptionKey::from_hex(&KEY.replace('0', "g")).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{config::Config, Error};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::TlsAcceptor;
+
+/// Build the TLS acceptor for TCP connections from the config,
+/// if a certificate is configured.
+///
+/// NOTE: When `tls_ca_cert_file` is set every client has to present
+/// a certificate signed by one of its CAs to connect.
+pub fn acceptor(config: &Config) -> Result<Option<TlsAcceptor>, Error> {
+    let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Ok(None);
+    };
+
+    let certs = load_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| Error::Tls(format!("Bad Private Key {}: {e}", key_file.display())))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
+    let builder = match &config.tls_ca_cert_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert).map_err(|e| Error::Tls(e.to_string()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| Error::Tls(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Load every certificate in a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::Tls(format!("Bad Certificates {}: {e}", path.display())))?;
+
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("No Certificates In {}", path.display())));
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("fabric-tls-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn no_acceptor_without_a_certificate() {
+        assert!(acceptor(&Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn builds_acceptor_from_pem_files() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let (cert_file, key_file) = (temp_path("cert.pem"), temp_path("key.pem"));
+        std::fs::write(&cert_file, generated.cert.pem()).unwrap();
+        std::fs::write(&key_file, generated.key_pair.serialize_pem()).unwrap();
+
+        let mut config = Config {
+            tls_cert_file: Some(cert_file.clone()),
+            tls_key_file: Some(key_file.clone()),
+            ..Config::default()
+        };
+        assert!(acceptor(&config).unwrap().is_some());
+
+        // The certificate doubles as its own CA for client certificates
+        config.tls_ca_cert_file = Some(cert_file.clone());
+        assert!(acceptor(&config).unwrap().is_some());
+
+        // A key is not a certificate
+        config.tls_ca_cert_file = Some(key_file.clone());
+        assert!(matches!(acceptor(&config), Err(Error::Tls(_))));
+
+        std::fs::remove_file(&cert_file).unwrap();
+        std::fs::remove_file(&key_file).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+//! Splitting commands into tokens, and quoting tokens so they split back.
+//!
+//! NOTE: This module only depends on `std` so that the `fabric-check`
+//! binary can validate log records exactly like the server parses them.
+
+use std::borrow::Cow;
+
+/// Why input couldn't be split into the tokens expected of it.
+#[derive(Debug)]
+pub enum TokenError {
+    /// The input isn't quoted or escaped properly
+    Syntax(String),
+    /// The verb is missing a required argument
+    WrongArity(String),
+}
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TokenError::Syntax(reason) => write!(f, "Syntax Error: {}.", reason),
+            TokenError::WrongArity(verb) => write!(f, "Wrong Number Of Arguments For {}.", verb),
+        }
+    }
+}
+
+/// Quote an argument if it wouldn't be parsed back as a single token as is.
+pub fn quote(arg: &str) -> Cow<'_, str> {
+    let plain =
+        !arg.is_empty() && !arg.starts_with(['"', '\'']) && !arg.contains(char::is_whitespace);
+    if plain {
+        return Cow::Borrowed(arg);
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// Splits client input into whitespace separated tokens.
+///
+/// NOTE: A token can be double quoted to hold whitespace, with `\"`, `\\`, `\n`,
+/// `\r` and `\t` escapes, or single quoted to be taken literally, except for `\'`.
+pub struct Tokens<'a> {
+    input: &'a str,
+}
+impl<'a> Tokens<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self { input }
+    }
+
+    /// Take the rest of the input, that hasn't been split into tokens.
+    pub fn rest(&mut self) -> &'a str {
+        let rest = self.input.trim();
+        self.input = "";
+        rest
+    }
+
+    /// The next required argument of `verb`.
+    pub fn arg(&mut self, verb: &str) -> Result<String, TokenError> {
+        self.next()?
+            .ok_or_else(|| TokenError::WrongArity(verb.to_string()))
+    }
+
+    /// Split what's left of the input into tokens.
+    pub fn all(&mut self) -> Result<Vec<String>, TokenError> {
+        let mut all = Vec::new();
+        while let Some(token) = self.next()? {
+            all.push(token);
+        }
+        Ok(all)
+    }
+
+    /// The next token, if there's any left.
+    pub fn next(&mut self) -> Result<Option<String>, TokenError> {
+        self.input = self.input.trim_start();
+        let mut chars = self.input.char_indices();
+        let Some((_, first)) = chars.next() else {
+            return Ok(None);
+        };
+
+        if first != '"' && first != '\'' {
+            let end = self
+                .input
+                .find(char::is_whitespace)
+                .unwrap_or(self.input.len());
+            let token = self.input[..end].to_string();
+            self.input = &self.input[end..];
+            return Ok(Some(token));
+        }
+
+        let mut token = String::new();
+        loop {
+            let Some((i, c)) = chars.next() else {
+                return Err(TokenError::Syntax("Unterminated Quotes".to_string()));
+            };
+            match c {
+                c if c == first => {
+                    self.input = &self.input[i + 1..];
+                    if !self.input.is_empty() && !self.input.starts_with(char::is_whitespace) {
+                        return Err(TokenError::Syntax(
+                            "Closing Quote Must Be Followed By Whitespace".to_string(),
+                        ));
+                    }
+                    return Ok(Some(token));
+                }
+                '\\' => {
+                    let escaped = chars.next().map(|(_, c)| c);
+                    match (first, escaped) {
+                        ('\'', Some('\'')) => token.push('\''),
+                        ('\'', Some(c)) => {
+                            token.push('\\');
+                            token.push(c);
+                        }
+                        (_, Some(c @ ('"' | '\\'))) => token.push(c),
+                        (_, Some('n')) => token.push('\n'),
+                        (_, Some('r')) => token.push('\r'),
+                        (_, Some('t')) => token.push('\t'),
+                        (_, Some(c)) => {
+                            return Err(TokenError::Syntax(format!("Unknown Escape \"\\{c}\"")))
+                        }
+                        (_, None) => {
+                            return Err(TokenError::Syntax("Unterminated Quotes".to_string()))
+                        }
+                    }
+                }
+                c => token.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenize(input: &str) -> Result<Vec<String>, TokenError> {
+        let mut tokens = Tokens::new(input);
+        let mut all = Vec::new();
+        while let Some(token) = tokens.next()? {
+            all.push(token);
+        }
+        Ok(all)
+    }
+
+    #[test]
+    fn tokenizes_quoted_strings_and_escapes() {
+        assert_eq!(
+            tokenize(r#"  EXPORT "my \"key\"\t"  'C:\data files\x.json'  plain "" "#).unwrap(),
+            [
+                "EXPORT",
+                "my \"key\"\t",
+                r"C:\data files\x.json",
+                "plain",
+                ""
+            ]
+        );
+        assert_eq!(tokenize(r"'it\'s'").unwrap(), ["it's"]);
+
+        for input in [r#""open"#, r#""bad \q escape""#, r#""a"b"#, r"'open\'"] {
+            assert!(
+                matches!(tokenize(input), Err(TokenError::Syntax(_))),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn quotes_what_would_not_parse_back() {
+        for key in [
+            "plain",
+            "with space",
+            "\"quoted\"",
+            "back\\slash",
+            "",
+            "new\nline",
+        ] {
+            let quoted = quote(key);
+            let mut tokens = Tokens::new(&quoted);
+            assert_eq!(tokens.next().unwrap().as_deref(), Some(key));
+        }
+        assert_eq!(quote("plain"), "plain");
+    }
+}
@@ -0,0 +1,518 @@
+use crate::{
+    changes::Change,
+    frame,
+    http::{self, Head, Response},
+    listener::Connection,
+    output::Output,
+    shutdown::SHUTDOWN,
+    Error, ThreadSafeFabric,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufRead, AsyncReadExt, BufReader},
+    sync::{broadcast::error::RecvError, mpsc},
+};
+
+/// Where clients ask to upgrade their connection to a WebSocket.
+const SUBSCRIBE_PATH: &str = "/v1/subscribe";
+/// Appended to the client's key to prove the server speaks WebSocket (RFC 6455).
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The opcodes of WebSocket frames.
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// The status codes a connection is closed with.
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// A message from a client, reassembled from its frames.
+#[derive(Debug, PartialEq)]
+enum Message {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Serve a client of the WebSocket listener, sending it the value at every
+/// key path it subscribes to, then every change at or under those paths.
+///
+/// NOTE: Subscribers mostly listen, so they're never disconnected for idling,
+/// only for falling too far behind reading what they're sent.
+pub async fn serve(socket: Box<dyn Connection>, fabric: &ThreadSafeFabric) -> Result<(), Error> {
+    let limits = fabric.read().await.limits;
+    let (reader, writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(reader);
+    let output = Output::new(
+        writer,
+        limits.client_output_buffer_limit,
+        limits.idle_timeout(),
+    );
+
+    // Connections open with an HTTP request to upgrade to a WebSocket
+    let read = frame::until_idle_or_shutdown(limits.idle_timeout(), http::read_head(&mut reader));
+    let response = match read.await {
+        Some(Ok(Some(head))) => upgrade(&head),
+        Some(Ok(None)) | None => return output.close().await,
+        Some(Err(e)) => Err(Response::from(e)),
+    };
+    let served = match response {
+        Ok(response) => {
+            output.send(response.into_bytes())?;
+            serve_upgraded(reader, &output, fabric).await
+        }
+        Err(response) => output.send(response.encode(false)),
+    };
+
+    output.close().await?;
+    served
+}
+
+/// Serve a client once its connection was upgraded to a WebSocket.
+async fn serve_upgraded<R: AsyncBufRead + Send + Unpin + 'static>(
+    reader: R,
+    output: &Output,
+    fabric: &ThreadSafeFabric,
+) -> Result<(), Error> {
+    let limits = fabric.read().await.limits;
+
+    // Changes are received from before the first snapshot is taken, so none are missed
+    let mut changes = fabric.read().await.changes.subscribe();
+    let (messages, mut incoming) = mpsc::channel(16);
+    let reading = tokio::spawn(read_messages(reader, limits.max_request_size, messages));
+
+    let mut subscriptions = Vec::new();
+    let served = loop {
+        let sent = tokio::select! {
+            message = incoming.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = handle(&text, &mut subscriptions, fabric).await;
+                    output.send(encode_frame(TEXT, reply.to_string().as_bytes()))
+                }
+                Some(Ok(Message::Ping(payload))) => output.send(encode_frame(PONG, &payload)),
+                Some(Ok(Message::Close)) | None => break close(output, NORMAL_CLOSURE),
+                Some(Err(e)) => {
+                    let code = match e {
+                        Error::LimitExceeded(_) => MESSAGE_TOO_BIG,
+                        _ => PROTOCOL_ERROR,
+                    };
+                    close(output, code)?;
+                    break Err(e);
+                }
+            },
+            change = changes.recv() => {
+                let seen = match change {
+                    Ok(change) => seen_changes(&change, &subscriptions),
+                    // Fell too far behind to know what changed, so start over
+                    Err(RecvError::Lagged(_)) => {
+                        let fabric = fabric.read().await;
+                        subscriptions.iter().map(|path| snapshot(&fabric, path)).collect()
+                    }
+                    Err(RecvError::Closed) => break close(output, GOING_AWAY),
+                };
+                seen.into_iter().try_for_each(|message| {
+                    output.send(encode_frame(TEXT, message.to_string().as_bytes()))
+                })
+            },
+            _ = SHUTDOWN.requested() => break close(output, GOING_AWAY),
+        };
+        if let Err(e) = sent {
+            break Err(e);
+        }
+    };
+
+    reading.abort();
+    served
+}
+
+/// Check a request is asking to upgrade to a WebSocket, returning
+/// the response accepting it, or the one turning it down.
+fn upgrade(head: &Head) -> Result<String, Response> {
+    let path = head.target.split('?').next().unwrap_or_default();
+    if path != SUBSCRIBE_PATH {
+        let message = format!("No Such Route {path}");
+        return Err(Response::error(404, "NOT_FOUND", &message));
+    }
+
+    let upgrading = head.method == "GET"
+        && head
+            .header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        && head.header("sec-websocket-version") == Some("13");
+    let Some(key) = head.header("sec-websocket-key").filter(|_| upgrading) else {
+        let e = Error::Protocol("Expected A WebSocket Upgrade".to_string());
+        return Err(Response::from(e));
+    };
+
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    ))
+}
+
+/// The `Sec-WebSocket-Accept` answering a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let proof = format!("{}{ACCEPT_GUID}", key.trim());
+    let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, proof.as_bytes());
+    BASE64.encode(digest)
+}
+
+/// Handle a message from a client, returning the reply to it.
+///
+/// NOTE: Messages are `{"op": "subscribe", "path": [...]}`, answered with a
+/// snapshot of the value at the path, or `{"op": "unsubscribe", "path": [...]}`.
+async fn handle(
+    text: &str,
+    subscriptions: &mut Vec<Vec<String>>,
+    fabric: &ThreadSafeFabric,
+) -> Value {
+    let request = match serde_json::from_str::<Value>(text) {
+        Ok(request) => request,
+        Err(e) => return error(&e.into()),
+    };
+    let path = match key_path(&request["path"]) {
+        Ok(path) => path,
+        Err(e) => return error(&e),
+    };
+
+    match request["op"].as_str() {
+        Some("subscribe") => {
+            if !subscriptions.contains(&path) {
+                subscriptions.push(path.clone());
+            }
+            snapshot(&*fabric.read().await, &path)
+        }
+        Some("unsubscribe") => {
+            subscriptions.retain(|subscription| *subscription != path);
+            json!({ "type": "unsubscribed", "path": path })
+        }
+        op => {
+            let op = op.unwrap_or_default().to_string();
+            error(&Error::UnsupportedCommand(op))
+        }
+    }
+}
+
+/// Take a key path given as an array of segments, which
+/// can't be empty or contain a `.` of their own.
+fn key_path(path: &Value) -> Result<Vec<String>, Error> {
+    let segments = match path.as_array() {
+        Some(segments) if !segments.is_empty() => segments,
+        _ => return Err(Error::InvalidKeyPath(path.to_string())),
+    };
+    segments
+        .iter()
+        .map(|segment| match segment.as_str() {
+            Some(key) if !key.is_empty() && !key.contains('.') => Ok(key.to_string()),
+            _ => Err(Error::InvalidKeyPath(path.to_string())),
+        })
+        .collect()
+}
+
+/// The message with the current value at a key path,
+/// which is left out when there isn't one.
+///
+/// NOTE: A blob is seen as its base64 encoding, like in the changes to it.
+fn snapshot(fabric: &crate::Fabric, path: &[String]) -> Value {
+    let keys = path.iter().map(String::as_str).collect();
+    let value = match fabric.get(keys) {
+        Ok(value) => Some(value),
+        Err(Error::WrongType(_)) => match path {
+            [key] => fabric
+                .get_blob(key)
+                .ok()
+                .map(|blob| json!(BASE64.encode(blob))),
+            _ => None,
+        },
+        Err(_) => None,
+    };
+    match value {
+        Some(value) => json!({ "type": "snapshot", "path": path, "value": value }),
+        None => json!({ "type": "snapshot", "path": path }),
+    }
+}
+
+/// The messages for a change, as seen from every subscription it affects.
+///
+/// NOTE: Overlapping subscriptions can see a change the same
+/// way, in which case it's only sent once.
+fn seen_changes(change: &Change, subscriptions: &[Vec<String>]) -> Vec<Value> {
+    let mut seen = Vec::new();
+    for subscription in subscriptions {
+        let message = match change.seen_from(subscription) {
+            Some(Change::Set { path, value }) => {
+                json!({ "type": "set", "path": path, "value": value })
+            }
+            Some(Change::Remove { path }) => json!({ "type": "remove", "path": path }),
+            None => continue,
+        };
+        if !seen.contains(&message) {
+            seen.push(message);
+        }
+    }
+    seen
+}
+
+/// The message for an error, with its code.
+fn error(e: &Error) -> Value {
+    json!({ "type": "error", "code": e.code(), "message": e.to_string() })
+}
+
+/// Close the connection with a status code, once what's queued before it is written.
+fn close(output: &Output, code: u16) -> Result<(), Error> {
+    output.send(encode_frame(CLOSE, &code.to_be_bytes()))
+}
+
+/// Read messages from a client until it closes the connection, reassembling
+/// fragmented ones, and hand them over to be handled.
+async fn read_messages<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    max_len: usize,
+    messages: mpsc::Sender<Result<Message, Error>>,
+) {
+    let mut fragments: Option<Vec<u8>> = None;
+    loop {
+        let message = match read_frame(&mut reader, max_len).await {
+            Ok(Some((fin, opcode, payload))) => match (opcode, fragments.as_mut()) {
+                (PING, _) => Ok(Message::Ping(payload)),
+                (PONG, _) => continue,
+                (CLOSE, _) => Ok(Message::Close),
+                (TEXT, None) if fin => text(payload),
+                (TEXT, None) => {
+                    fragments = Some(payload);
+                    continue;
+                }
+                (CONTINUATION, Some(message)) if message.len() + payload.len() > max_len => Err(
+                    Error::LimitExceeded(format!("Message Is Larger Than {max_len} Bytes")),
+                ),
+                (CONTINUATION, Some(message)) => {
+                    message.extend_from_slice(&payload);
+                    if !fin {
+                        continue;
+                    }
+                    text(fragments.take().unwrap_or_default())
+                }
+                (BINARY, _) => Err(Error::Protocol(
+                    "Binary Messages Are Not Supported".to_string(),
+                )),
+                _ => Err(Error::Protocol("Unexpected Frame".to_string())),
+            },
+            // Client hung up without closing
+            Ok(None) => Ok(Message::Close),
+            Err(e) => Err(e),
+        };
+
+        let done = !matches!(message, Ok(Message::Text(_) | Message::Ping(_)));
+        if messages.send(message).await.is_err() || done {
+            return;
+        }
+    }
+}
+
+/// A text message, which has to be UTF-8.
+fn text(payload: Vec<u8>) -> Result<Message, Error> {
+    String::from_utf8(payload)
+        .map(Message::Text)
+        .map_err(|_| Error::Protocol("Text Message Is Not UTF-8".to_string()))
+}
+
+/// Read a frame from a client, as whether it's the final one of its
+/// message, its opcode and its unmasked payload, returning `None` at
+/// the end of the stream.
+async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<(bool, u8, Vec<u8>)>, Error> {
+    let mut head = [0; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        return Err(Error::Protocol("Reserved Bits Are Set".to_string()));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(Error::Protocol("Client Frames Must Be Masked".to_string()));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > max_len as u64 {
+        return Err(Error::LimitExceeded(format!(
+            "Message Is Larger Than {max_len} Bytes"
+        )));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = frame::read_bytes(reader, len as usize).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// Encode a single, unmasked frame, as servers send them.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => out.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Fabric;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    /// Encode a frame the way a client sends it, masked.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut out = encode_frame(opcode, payload);
+        let start = out.len() - payload.len();
+        out[0] = if fin { out[0] } else { out[0] & 0x7F };
+        out[1] |= 0x80;
+        out.splice(start..start, mask);
+        for (i, b) in out[start + 4..].iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        out
+    }
+
+    #[test]
+    fn accepts_websocket_keys() {
+        // The example handshake from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragmented_messages() {
+        let mut input = client_frame(false, TEXT, b"{\"op\": ");
+        input.extend(client_frame(true, PING, b"hi"));
+        input.extend(client_frame(true, CONTINUATION, b"\"subscribe\"}"));
+        input.extend(client_frame(true, TEXT, &[b'x'; 300]));
+        input.extend(client_frame(true, CLOSE, &[]));
+
+        let (messages, mut received) = mpsc::channel(8);
+        read_messages(&input[..], 1024, messages).await;
+        assert_eq!(
+            received.recv().await.unwrap().unwrap(),
+            Message::Ping(b"hi".to_vec())
+        );
+        assert_eq!(
+            received.recv().await.unwrap().unwrap(),
+            Message::Text("{\"op\": \"subscribe\"}".into())
+        );
+        assert_eq!(
+            received.recv().await.unwrap().unwrap(),
+            Message::Text("x".repeat(300))
+        );
+        assert_eq!(received.recv().await.unwrap().unwrap(), Message::Close);
+
+        // Unmasked and oversized frames end the connection
+        for (input, max_len) in [
+            (encode_frame(TEXT, b"hi"), 1024),
+            (client_frame(true, TEXT, b"hi"), 1),
+        ] {
+            let (messages, mut received) = mpsc::channel(8);
+            read_messages(&input[..], max_len, messages).await;
+            assert!(received.recv().await.unwrap().is_err());
+        }
+
+        // Claiming a huge frame without sending it costs nothing up front
+        let mut input = vec![0x80 | TEXT, 0x80 | 127];
+        input.extend(u64::MAX.to_be_bytes());
+        input.extend([1, 2, 3, 4, b'h', b'i']);
+        assert!(read_frame(&mut &input[..], usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn subscribes_to_key_paths() {
+        let fabric = Arc::new(RwLock::new(Fabric::new()));
+        fabric
+            .write()
+            .await
+            .set(vec!["users", "alice"], "1")
+            .unwrap();
+        let mut subscriptions = Vec::new();
+
+        let subscribe = r#"{"op": "subscribe", "path": ["users"]}"#;
+        assert_eq!(
+            handle(subscribe, &mut subscriptions, &fabric).await,
+            json!({"type": "snapshot", "path": ["users"], "value": {"alice": 1}})
+        );
+        let subscribe = r#"{"op": "subscribe", "path": ["users", "alice"]}"#;
+        handle(subscribe, &mut subscriptions, &fabric).await;
+        assert_eq!(subscriptions.len(), 2);
+
+        // A change both subscriptions see the same way is only sent once
+        let change = Change::Set {
+            path: vec!["users".into(), "alice".into()],
+            value: json!(2),
+        };
+        assert_eq!(
+            seen_changes(&change, &subscriptions),
+            vec![json!({"type": "set", "path": ["users", "alice"], "value": 2})]
+        );
+
+        let unsubscribe = r#"{"op": "unsubscribe", "path": ["users"]}"#;
+        handle(unsubscribe, &mut subscriptions, &fabric).await;
+        assert_eq!(
+            subscriptions,
+            vec![vec!["users".to_string(), "alice".to_string()]]
+        );
+
+        for (request, code) in [
+            ("{", "BAD_DATA_STRUCTURE"),
+            (r#"{"op": "subscribe", "path": []}"#, "INVALID_KEY_PATH"),
+            (
+                r#"{"op": "subscribe", "path": ["a.b"]}"#,
+                "INVALID_KEY_PATH",
+            ),
+            (r#"{"op": "watch", "path": ["a"]}"#, "UNSUPPORTED_COMMAND"),
+        ] {
+            let reply = handle(request, &mut subscriptions, &fabric).await;
+            assert_eq!(reply["code"], code, "{request}");
+        }
+
+        // Blobs are seen base64 encoded, like in the changes to them
+        fabric.write().await.set_blob("img", vec![1, 2, 3]).unwrap();
+        let subscribe = r#"{"op": "subscribe", "path": ["img"]}"#;
+        assert_eq!(
+            handle(subscribe, &mut subscriptions, &fabric).await,
+            json!({"type": "snapshot", "path": ["img"], "value": "AQID"})
+        );
+    }
+}
@@ -1,7 +1,11 @@
-use fabric_cache_client::FabricClient;
+use fabric_cache_client::{Encoding, Error, FabricClient, Message, Pipeline, TlsConfig};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::{process::Command, thread};
+use std::io::{BufRead, Write};
+use std::{
+    process::{Child, Command},
+    thread,
+};
 
 #[tokio::test]
 async fn can_start_and_connect_to_server() {
@@ -73,6 +77,34 @@ async fn can_handle_basic_use() {
     }
 }
 
+#[tokio::test]
+async fn can_round_trip_keys_that_need_quoting() {
+    let _server = TestServer::start(&["--port", "18758"], &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18758").await.unwrap();
+
+    for key in ["my key", "\"quoted", "'single", "tab\tand\\slash"] {
+        client.set(key, &vec![1, 2]).await.unwrap();
+        assert_eq!(client.get::<_, Vec<i32>>(key).await.unwrap(), vec![1, 2]);
+
+        let dump = client.dump(key).await.unwrap();
+        client.restore(key, &dump, true).await.unwrap();
+
+        client.remove(key).await.unwrap();
+        assert!(matches!(
+            client.get::<_, Vec<i32>>(key).await,
+            Err(Error::KeyNotFound(_))
+        ));
+    }
+
+    client.set_blob("my blob", b"\x00\x01").await.unwrap();
+    assert_eq!(client.get_blob("my blob").await.unwrap(), b"\x00\x01");
+
+    let mut pipeline = Pipeline::new();
+    pipeline.set("my key", &1).unwrap().get("my key");
+    let replies = client.execute(&pipeline).await.unwrap();
+    assert_eq!(replies[1].as_ref().unwrap(), &serde_json::json!(1));
+}
+
 #[tokio::test]
 async fn can_handle_nested_structs() {
     thread::spawn(move || {
@@ -197,3 +229,1261 @@ struct SettingsTestStruct {
     max_replays: i32,
     default_volume: f64,
 }
+
+#[test]
+fn can_check_and_fix_corrupted_append_only_log() {
+    let path = std::env::temp_dir().join(format!("fabric-check-{}.aof", std::process::id()));
+    let valid_record = "302af431 SET a 1\n";
+    std::fs::write(&path, format!("{valid_record}00000000 SET b 2\n")).unwrap();
+
+    let check = Command::new("./../target/debug/fabric-check")
+        .arg(&path)
+        .output()
+        .expect("Failed to run fabric-check");
+    assert_eq!(check.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&check.stdout).contains("Corrupted: Record 2"));
+
+    let fix = Command::new("./../target/debug/fabric-check")
+        .arg("--fix")
+        .arg(&path)
+        .output()
+        .expect("Failed to run fabric-check");
+    assert_eq!(fix.status.code(), Some(0));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), valid_record);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn checks_records_of_quoted_keys_like_the_server_parses_them() {
+    let path = std::env::temp_dir().join(format!("fabric-check-quoted-{}.aof", std::process::id()));
+    std::fs::write(&path, "d3f10300 SET \"my key\" 1\n").unwrap();
+
+    let check = Command::new("./../target/debug/fabric-check")
+        .arg(&path)
+        .output()
+        .expect("Failed to run fabric-check");
+    assert_eq!(
+        check.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&check.stdout)
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn can_dump_and_restore_subtrees() {
+    thread::spawn(move || {
+        Command::new("./../target/debug/fabric-cache")
+            .output()
+            .expect("Failed to start Fabric Cache Server");
+    });
+
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    let mut client = FabricClient::connect("127.0.0.1:8731").await.unwrap();
+
+    let coordinates: HashMap<String, ThreeDimensionalCoordinate> = HashMap::from([(
+        "House".into(),
+        ThreeDimensionalCoordinate { x: 10, y: 29, z: 6 },
+    )]);
+    client
+        .set("tenants.dump_test.coordinates", &coordinates)
+        .await
+        .unwrap();
+
+    // Move the tenant's subtree to a new key
+    let blob = client.dump("tenants.dump_test").await.unwrap();
+    client
+        .restore("tenants.restored", &blob, false)
+        .await
+        .unwrap();
+    let house: ThreeDimensionalCoordinate = client
+        .get("tenants.restored.coordinates.House")
+        .await
+        .unwrap();
+    assert_eq!(house.y, 29);
+
+    // Restoring onto an existing key needs REPLACE
+    assert!(matches!(
+        client.restore("tenants.restored", &blob, false).await,
+        Err(Error::KeyExists(_))
+    ));
+    assert!(client
+        .restore("tenants.restored", &blob, true)
+        .await
+        .is_ok());
+
+    assert!(matches!(
+        client.dump("tenants.does_not_exist").await,
+        Err(Error::KeyNotFound(key)) if key == "tenants.does_not_exist"
+    ));
+    assert!(matches!(
+        client.restore("tenants.bad", "bm90IGEgZHVtcA==", false).await,
+        Err(Error::Server { code, .. }) if code == "BAD_DUMP"
+    ));
+    assert!(matches!(
+        client.get::<_, i32>("tenants.does_not_exist").await,
+        Err(Error::KeyNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn can_export_and_import_json_files() {
+    let dir = std::env::temp_dir().join(format!("fabric-export-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let _server = TestServer::start(
+        &["--port", "18755", "--export-dir", dir.to_str().unwrap()],
+        &[],
+    );
+
+    let mut client = FabricClient::connect("127.0.0.1:18755").await.unwrap();
+
+    let coordinates = ThreeDimensionalCoordinate { x: 3, y: 33, z: 12 };
+    client.set("export_test", &coordinates).await.unwrap();
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:18755").unwrap();
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    let mut resp = String::new();
+
+    writeln!(stream, "EXPORT export_test \"export test.json\"").unwrap();
+    reader.read_line(&mut resp).unwrap();
+    assert_eq!(resp, "OK\n");
+
+    let exported: ThreeDimensionalCoordinate =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("export test.json")).unwrap())
+            .unwrap();
+    assert_eq!(exported.z, 12);
+
+    resp.clear();
+    writeln!(stream, "IMPORT import_test \"export test.json\"").unwrap();
+    reader.read_line(&mut resp).unwrap();
+    assert_eq!(resp, "OK\n");
+
+    let imported: ThreeDimensionalCoordinate = client.get("import_test").await.unwrap();
+    assert_eq!(imported.x, 3);
+
+    // Nothing outside of the export directory can be reached
+    resp.clear();
+    writeln!(stream, "IMPORT import_test ../etc/passwd").unwrap();
+    reader.read_line(&mut resp).unwrap();
+    assert!(resp.starts_with("ERR SYNTAX "), "{resp}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn can_configure_listener_with_flags_and_env() {
+    let _server = TestServer::start(&["--bind", "127.0.0.1"], &[("FABRIC_PORT", "18731")]);
+
+    let mut client = FabricClient::connect("127.0.0.1:18731").await.unwrap();
+    client.set("configured", &1).await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn can_serve_ipv4_ipv6_and_unix_listeners_at_once() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let socket = std::env::temp_dir().join(format!("fabric-{}.sock", std::process::id()));
+    let _server = TestServer::start(
+        &[
+            "--port",
+            "18733",
+            "--bind",
+            "127.0.0.1,::1",
+            "--unixsocket",
+            socket.to_str().unwrap(),
+            "--unixsocketperm",
+            "700",
+        ],
+        &[],
+    );
+
+    let mut ipv4 = FabricClient::connect("127.0.0.1:18733").await.unwrap();
+    ipv4.set("shared", &"ipv4").await.unwrap();
+
+    // Not every sandbox has a loopback IPv6 interface
+    if let Ok(mut ipv6) = FabricClient::connect("[::1]:18733").await {
+        assert_eq!(ipv6.get::<_, String>("shared").await.unwrap(), "ipv4");
+        ipv6.set("shared", &"ipv6").await.unwrap();
+    }
+
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+
+    let mut unix = FabricClient::connect_unix(&socket).await.unwrap();
+    let value: String = unix.get("shared").await.unwrap();
+    assert!(value == "ipv4" || value == "ipv6");
+}
+
+#[test]
+fn can_print_effective_config() {
+    let path = std::env::temp_dir().join(format!("fabric-{}.toml", std::process::id()));
+    std::fs::write(&path, "port = 9000\nappendfsync = \"always\"\n").unwrap();
+
+    let output = Command::new("./../target/debug/fabric-cache")
+        .arg("--config")
+        .arg(&path)
+        .args(["--appendonly", "yes", "--print-config"])
+        .env("FABRIC_PORT", "9001")
+        .output()
+        .expect("Failed to start Fabric Cache Server");
+    let printed = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success());
+    assert!(printed.contains("port = 9001"));
+    assert!(printed.contains("appendonly = true"));
+    assert!(printed.contains("appendfsync = \"always\""));
+
+    let output = Command::new("./../target/debug/fabric-cache")
+        .args(["--port", "not-a-port", "--print-config"])
+        .output()
+        .expect("Failed to start Fabric Cache Server");
+    assert!(!output.status.success());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn can_restore_state_from_append_only_log_after_restart() {
+    let path = std::env::temp_dir().join(format!("fabric-restart-{}.aof", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let args = [
+        "--port",
+        "18732",
+        "--appendonly",
+        "yes",
+        "--appendfsync",
+        "always",
+        "--appendfilename",
+        path.to_str().unwrap(),
+    ];
+
+    let server = TestServer::start(&args, &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18732").await.unwrap();
+    let house = ThreeDimensionalCoordinate { x: 10, y: 29, z: 6 };
+    client.set("coordinates.House", &house).await.unwrap();
+    client.set("coordinates.House.x", &12).await.unwrap();
+    client.set("coordinates.Park", &house).await.unwrap();
+    client.remove("coordinates.Park").await.unwrap();
+    drop(client);
+    drop(server);
+
+    let _server = TestServer::start(&args, &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18732").await.unwrap();
+    let restored: HashMap<String, ThreeDimensionalCoordinate> =
+        client.get("coordinates").await.unwrap();
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored["House"].x, 12);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn can_serve_clients_over_mutual_tls() {
+    let dir = std::env::temp_dir().join(format!("fabric-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // A private CA signing both the server's and the client's certificate
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    for (name, san) in [("server", "127.0.0.1"), ("client", "client")] {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec![san.to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        std::fs::write(dir.join(format!("{name}.pem")), cert.pem()).unwrap();
+        std::fs::write(dir.join(format!("{name}.key")), key.serialize_pem()).unwrap();
+    }
+
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let _server = TestServer::start(
+        &[
+            "--port",
+            "18734",
+            "--tls-cert-file",
+            &path("server.pem"),
+            "--tls-key-file",
+            &path("server.key"),
+            "--tls-ca-cert-file",
+            &path("ca.pem"),
+        ],
+        &[],
+    );
+
+    let tls = TlsConfig::new()
+        .with_root_ca(dir.join("ca.pem"))
+        .unwrap()
+        .with_client_cert(dir.join("client.pem"), dir.join("client.key"))
+        .unwrap();
+    let mut client = FabricClient::connect_tls("127.0.0.1:18734", &tls)
+        .await
+        .unwrap();
+    assert!(client.capabilities().supports("tls"));
+    client.set("secret", &"over tls").await.unwrap();
+    assert_eq!(client.get::<_, String>("secret").await.unwrap(), "over tls");
+
+    // Without a client certificate the server hangs up
+    let tls = TlsConfig::new().with_root_ca(dir.join("ca.pem")).unwrap();
+    if let Ok(mut client) = FabricClient::connect_tls("127.0.0.1:18734", &tls).await {
+        assert!(client.set("secret", &"no cert").await.is_err());
+    }
+
+    // The server's certificate isn't signed by a public CA
+    assert!(
+        FabricClient::connect_tls("127.0.0.1:18734", &TlsConfig::new())
+            .await
+            .is_err()
+    );
+
+    // Neither does it speak plaintext anymore, which the handshake gives away
+    assert!(FabricClient::connect("127.0.0.1:18734").await.is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn gives_up_on_tls_handshakes_that_never_finish() {
+    let dir = std::env::temp_dir().join(format!("fabric-tls-handshake-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = rcgen::CertificateParams::new(vec!["127.0.0.1".to_string()])
+        .unwrap()
+        .self_signed(&key)
+        .unwrap();
+    std::fs::write(dir.join("server.pem"), cert.pem()).unwrap();
+    std::fs::write(dir.join("server.key"), key.serialize_pem()).unwrap();
+
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let _server = TestServer::start(
+        &[
+            "--port",
+            "18756",
+            "--maxclients",
+            "1",
+            "--timeout",
+            "1",
+            "--tls-cert-file",
+            &path("server.pem"),
+            "--tls-key-file",
+            &path("server.key"),
+        ],
+        &[],
+    );
+    let tls = TlsConfig::new()
+        .with_root_ca(dir.join("server.pem"))
+        .unwrap();
+
+    // A client that never starts the handshake takes the only slot for now,
+    // and the next one is turned away before any handshake
+    let _silent = std::net::TcpStream::connect("127.0.0.1:18756").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert!(FabricClient::connect_tls("127.0.0.1:18756", &tls)
+        .await
+        .is_err());
+
+    // But only until it has idled out
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let mut client = FabricClient::connect_tls("127.0.0.1:18756", &tls)
+        .await
+        .unwrap();
+    client.set("handshake", &1).await.unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn can_speak_resp_to_redis_clients() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18735"], &[]);
+
+    let mut resp = tokio::net::TcpStream::connect("127.0.0.1:18735")
+        .await
+        .unwrap();
+    resp.write_all(
+        b"*3\r\n$3\r\nSET\r\n$4\r\nuser\r\n$21\r\n{\"name\":\"ops\",\"id\":7}\r\n\
+          *2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n\
+          *2\r\n$3\r\nGET\r\n$4\r\nuser\r\n\
+          *2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n",
+    )
+    .await
+    .unwrap();
+
+    let expected = "+OK\r\n\
+        %3\r\n$5\r\nproto\r\n:3\r\n$6\r\nserver\r\n$6\r\nfabric\r\n$7\r\nversion\r\n$5\r\n0.1.3\r\n\
+        %2\r\n$2\r\nid\r\n:7\r\n$4\r\nname\r\n$3\r\nops\r\n\
+        _\r\n";
+    let mut reply = vec![0; expected.len()];
+    resp.read_exact(&mut reply).await.unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected);
+
+    // Clients speaking Fabric's own protocol are still served alongside
+    let mut client = FabricClient::connect("127.0.0.1:18735").await.unwrap();
+    assert_eq!(client.get::<_, String>("user.name").await.unwrap(), "ops");
+}
+
+#[tokio::test]
+async fn can_subscribe_over_resp() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18757"], &[]);
+
+    let mut resp = tokio::net::TcpStream::connect("127.0.0.1:18757")
+        .await
+        .unwrap();
+    resp.write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n")
+        .await
+        .unwrap();
+    let expected = "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n";
+    let mut reply = vec![0; expected.len()];
+    resp.read_exact(&mut reply).await.unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected);
+
+    let mut client = FabricClient::connect("127.0.0.1:18757").await.unwrap();
+    assert_eq!(client.publish("news", "extra").await.unwrap(), 1);
+
+    // Anything but changing the subscription is refused until unsubscribed
+    resp.write_all(
+        b"*2\r\n$3\r\nGET\r\n$4\r\nuser\r\n\
+          *1\r\n$11\r\nUNSUBSCRIBE\r\n\
+          *1\r\n$4\r\nPING\r\n",
+    )
+    .await
+    .unwrap();
+    let expected = "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nextra\r\n\
+        -ERR Protocol Error: Only (P)SUBSCRIBE, (P)UNSUBSCRIBE And PING Are Allowed While Subscribed\r\n\
+        *3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n\
+        +PONG\r\n";
+    let mut reply = vec![0; expected.len()];
+    resp.read_exact(&mut reply).await.unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn can_negotiate_length_prefixed_framing() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18736"], &[]);
+
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:18736")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    stream.write_all(b"PROTOCOL 2\n").await.unwrap();
+    let mut ack = String::new();
+    stream.read_line(&mut ack).await.unwrap();
+    assert_eq!(ack, "OK\n");
+
+    // Requests can span lines once framed
+    for (request, expected) in [
+        ("SET user {\n  \"name\": \"framed\"\n}", "OK"),
+        ("GET user.name", "\"framed\""),
+    ] {
+        stream
+            .write_all(&(request.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = vec![0; stream.read_u32().await.unwrap() as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+    }
+
+    let mut client = FabricClient::connect("127.0.0.1:18736").await.unwrap();
+    client.use_framing().await.unwrap();
+    client.set("user.name", &"multi\nline").await.unwrap();
+    assert_eq!(
+        client.get::<_, String>("user.name").await.unwrap(),
+        "multi\nline"
+    );
+
+    // Clients that never negotiate keep speaking in lines
+    let mut client = FabricClient::connect("127.0.0.1:18736").await.unwrap();
+    assert_eq!(
+        client.get::<_, String>("user.name").await.unwrap(),
+        "multi\nline"
+    );
+}
+
+#[tokio::test]
+async fn can_serve_json_rpc_requests() {
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18738"], &[]);
+
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:18738")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    let mut call = async |request: Value| {
+        stream
+            .write_all(format!("{request}\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_line(&mut response).await.unwrap();
+        serde_json::from_str::<Value>(&response).unwrap()
+    };
+
+    // Path segments can hold anything a line command would need quoted
+    let set = json!({
+        "jsonrpc": "2.0",
+        "method": "set",
+        "params": {"path": ["user 1", "name"], "value": "rpc"},
+        "id": 1
+    });
+    assert_eq!(
+        call(set).await,
+        json!({"jsonrpc": "2.0", "result": "OK", "id": 1})
+    );
+
+    // The notification in the batch goes unanswered
+    let batch = json!([
+        {"jsonrpc": "2.0", "method": "set", "params": {"path": ["count"], "value": 2}},
+        {"jsonrpc": "2.0", "method": "get", "params": {"path": ["user 1"]}, "id": 2},
+        {"jsonrpc": "2.0", "method": "get", "params": {"path": ["nobody"]}, "id": 3},
+    ]);
+    assert_eq!(
+        call(batch).await,
+        json!([
+            {"jsonrpc": "2.0", "result": {"name": "rpc"}, "id": 2},
+            {
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32000,
+                    "message": "Key: \"nobody\" Not Found.",
+                    "data": {"code": "KEY_NOT_FOUND"}
+                },
+                "id": 3
+            },
+        ])
+    );
+
+    // Line commands keep working on the same connection
+    stream.write_all(b"GET count\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_line(&mut response).await.unwrap();
+    assert_eq!(response, "2\n");
+}
+
+#[tokio::test]
+async fn can_pipeline_requests_tagged_with_ids() {
+    let _server = TestServer::start(&["--port", "18739"], &[]);
+
+    let mut pipeline = Pipeline::new();
+    for i in 0..100 {
+        pipeline.set(&format!("user{i}"), &i).unwrap();
+    }
+    pipeline.get("user42").remove("user42").get("user42");
+
+    // Plain, tagged and tagged over framing all match every response up
+    for (use_request_ids, use_framing) in [(false, false), (true, false), (true, true)] {
+        let mut client = FabricClient::connect("127.0.0.1:18739").await.unwrap();
+        if use_request_ids {
+            client.use_request_ids();
+        }
+        if use_framing {
+            client.use_framing().await.unwrap();
+        }
+
+        let results = client.execute(&pipeline).await.unwrap();
+        assert_eq!(results.len(), 103);
+        assert!(results[..100].iter().all(|result| result.is_ok()));
+        assert_eq!(results[100].as_ref().unwrap(), &serde_json::json!(42));
+        assert!(results[101].is_ok());
+        assert!(matches!(&results[102], Err(Error::KeyNotFound(key)) if key == "user42"));
+
+        // Single commands keep working once tagged
+        assert_eq!(client.get::<_, u32>("user7").await.unwrap(), 7);
+    }
+}
+
+#[tokio::test]
+async fn can_greet_the_server_with_hello() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18740"], &[]);
+
+    // Every client is told about the server when it connects
+    let first = FabricClient::connect("127.0.0.1:18740").await.unwrap();
+    let second = FabricClient::connect("127.0.0.1:18740").await.unwrap();
+    let capabilities = first.capabilities();
+    assert_eq!(capabilities.server, "fabric-cache");
+    assert_eq!(capabilities.version, "0.1.3");
+    assert_eq!(capabilities.protocol, 1);
+    assert!(capabilities.supports("request-ids"));
+    assert!(!capabilities.supports("tls"));
+    assert!(capabilities.commands.iter().any(|cmd| cmd == "RESTORE"));
+    assert_ne!(
+        capabilities.connection_id,
+        second.capabilities().connection_id
+    );
+
+    // Asking for a version switches to it, once the reply is sent
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:18740")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    stream.write_all(b"#1 hello 2\n").await.unwrap();
+    let mut reply = String::new();
+    stream.read_line(&mut reply).await.unwrap();
+    let hello: serde_json::Value =
+        serde_json::from_str(reply.strip_prefix("#1 ").unwrap()).unwrap();
+    assert_eq!(hello["protocol"], 2);
+
+    stream.write_all(&[0, 0, 0, 7]).await.unwrap();
+    stream.write_all(b"HELLO 3").await.unwrap();
+    let mut reply = vec![0; stream.read_u32().await.unwrap() as usize];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, b"ERR PROTOCOL Protocol Error: Unsupported Version 3");
+}
+
+#[tokio::test]
+async fn enforces_request_and_value_limits() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(
+        &[
+            "--port",
+            "18741",
+            "--max-request-size",
+            "64",
+            "--max-value-size",
+            "16",
+            "--max-json-depth",
+            "2",
+        ],
+        &[],
+    );
+
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:18741")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    let mut send = async |request: &[u8]| {
+        stream.write_all(request).await.unwrap();
+        let mut reply = String::new();
+        stream.read_line(&mut reply).await.unwrap();
+        reply
+    };
+
+    // Values over the limits are refused, without losing the connection
+    assert_eq!(send(b"SET a [[1]]\n").await, "OK\n");
+    assert_eq!(
+        send(b"SET a [[[1]]]\n").await,
+        "ERR LIMIT_EXCEEDED Limit Exceeded: Value Nests Deeper Than 2 Levels.\n"
+    );
+    assert_eq!(
+        send(b"SET a \"0123456789abcdef\"\n").await,
+        "ERR LIMIT_EXCEEDED Limit Exceeded: Value Of 18 Bytes Is Larger Than 16 Bytes.\n"
+    );
+    assert_eq!(send(b"GET a\n").await, "[[1]]\n");
+
+    // A request over the limit gets the client disconnected, newline or not
+    assert_eq!(
+        send(&[b'x'; 65]).await,
+        "ERR LIMIT_EXCEEDED Limit Exceeded: Request Is Larger Than 64 Bytes.\n"
+    );
+    let mut rest = String::new();
+    assert_eq!(stream.read_line(&mut rest).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn enforces_max_clients_and_idle_timeouts() {
+    use tokio::io::AsyncReadExt;
+
+    let _server = TestServer::start(
+        &["--port", "18742", "--maxclients", "2", "--timeout", "1"],
+        &[],
+    );
+
+    let mut first = FabricClient::connect("127.0.0.1:18742").await.unwrap();
+    let _second = FabricClient::connect("127.0.0.1:18742").await.unwrap();
+
+    // A client over the limit is told why it's turned away
+    let mut third = tokio::net::TcpStream::connect("127.0.0.1:18742")
+        .await
+        .unwrap();
+    let mut reply = String::new();
+    third.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(
+        reply,
+        "ERR LIMIT_EXCEEDED Limit Exceeded: Max Number Of Clients Reached.\n"
+    );
+
+    let stats = first.stats().await.unwrap();
+    assert_eq!(stats["connected_clients"], 2);
+    assert_eq!(stats["rejected_connections"], 1);
+    assert_eq!(stats["limits"]["maxclients"], 2);
+    assert_eq!(stats["limits"]["timeout"], 1);
+
+    // Idle clients are disconnected, making room for new ones
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert!(first.get::<_, u32>("a").await.is_err());
+
+    let mut fresh = FabricClient::connect("127.0.0.1:18742").await.unwrap();
+    let stats = fresh.stats().await.unwrap();
+    assert_eq!(stats["connected_clients"], 1);
+    assert_eq!(stats["idle_timeouts"], 2);
+}
+
+#[tokio::test]
+async fn disconnects_slow_consumers() {
+    use tokio::io::AsyncWriteExt;
+
+    let _server = TestServer::start(
+        &["--port", "18743", "--client-output-buffer-limit", "4096"],
+        &[],
+    );
+
+    let mut client = FabricClient::connect("127.0.0.1:18743").await.unwrap();
+    client.set("big", &"x".repeat(1000)).await.unwrap();
+
+    // Ask for far more than the socket buffers hold, without reading any of it
+    let mut slow = tokio::net::TcpStream::connect("127.0.0.1:18743")
+        .await
+        .unwrap();
+    let requests = "GET big\n".repeat(50_000);
+    let _ = slow.write_all(requests.as_bytes()).await;
+
+    let mut slow_consumers = 0;
+    for _ in 0..50 {
+        slow_consumers = client.stats().await.unwrap()["slow_consumers"]
+            .as_u64()
+            .unwrap();
+        if slow_consumers > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(slow_consumers, 1);
+
+    // Everyone else is still served
+    assert_eq!(client.get::<_, String>("big").await.unwrap().len(), 1000);
+}
+
+#[tokio::test]
+async fn connections_survive_bad_input() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18737"], &[]);
+
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:18737")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    let mut send = async |request: &[u8]| {
+        stream.write_all(request).await.unwrap();
+        let mut reply = String::new();
+        stream.read_line(&mut reply).await.unwrap();
+        reply
+    };
+
+    // A typo, a malformed command and invalid UTF-8 are each answered with an error
+    assert_eq!(
+        send(b"GTE user\n").await,
+        "ERR UNSUPPORTED_COMMAND \"GTE\" Is Not A Supported Command.\n"
+    );
+    assert_eq!(
+        send(b"SET user\n").await,
+        "ERR WRONG_ARITY Wrong Number Of Arguments For SET.\n"
+    );
+    assert_eq!(
+        send(b"GET \"user\n").await,
+        "ERR SYNTAX Syntax Error: Unterminated Quotes.\n"
+    );
+    assert!(send(b"SET user {\"a\": \n")
+        .await
+        .starts_with("ERR BAD_DATA_STRUCTURE "));
+    assert_eq!(
+        send(b"GET \xff\xfe\n").await,
+        "ERR PROTOCOL Protocol Error: Request Is Not UTF-8\n"
+    );
+
+    // Blank lines are skipped without a reply
+    assert_eq!(send(b"\n\r\n  \nSET user 1\n").await, "OK\n");
+    assert_eq!(send(b"GET user\n").await, "1\n");
+    assert_eq!(send(b"get 'user'\n").await, "1\n");
+
+    // The same goes for clients speaking RESP
+    let mut resp = tokio::net::TcpStream::connect("127.0.0.1:18737")
+        .await
+        .unwrap();
+    resp.write_all(b"*1\r\n$4\r\nGTE!\r\n*1\r\n$1\r\n\xff\r\n*1\r\n$4\r\nPING\r\n")
+        .await
+        .unwrap();
+    let mut resp = tokio::io::BufReader::new(resp);
+    let mut replies = Vec::new();
+    for _ in 0..3 {
+        let mut reply = String::new();
+        resp.read_line(&mut reply).await.unwrap();
+        replies.push(reply);
+    }
+    assert!(replies[0].starts_with("-ERR "));
+    assert!(replies[1].starts_with("-ERR "));
+    assert_eq!(replies[2], "+PONG\r\n");
+}
+
+#[tokio::test]
+async fn can_shut_down_gracefully_with_shutdown_command() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let path = std::env::temp_dir().join(format!("fabric-shutdown-{}.aof", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let args = [
+        "--port",
+        "18744",
+        "--appendonly",
+        "yes",
+        "--appendfsync",
+        "no",
+        "--appendfilename",
+        path.to_str().unwrap(),
+    ];
+
+    let mut server = TestServer::start(&args, &[]);
+    let mut idle = tokio::net::TcpStream::connect("127.0.0.1:18744")
+        .await
+        .unwrap();
+    idle.write_all(b"SET idle 1\n").await.unwrap();
+    let mut reply = [0; 3];
+    idle.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"OK\n");
+
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:18744")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    stream
+        .write_all(b"SET user {\"name\": \"Antonio\"}\nSHUTDOWN SAVE\n")
+        .await
+        .unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).await.unwrap();
+    assert_eq!(replies, "OK\nOK\n");
+
+    // Idle clients are disconnected, before the server exits cleanly
+    assert_eq!(idle.read(&mut reply).await.unwrap(), 0);
+    assert!(server.wait_for_exit().success());
+
+    let _server = TestServer::start(&args, &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18744").await.unwrap();
+    assert_eq!(
+        client.get::<_, String>("user.name").await.unwrap(),
+        "Antonio"
+    );
+
+    // Without the append-only log there's nothing to save
+    let _plain = TestServer::start(&["--port", "18746"], &[]);
+    let mut plain = tokio::io::BufReader::new(
+        tokio::net::TcpStream::connect("127.0.0.1:18746")
+            .await
+            .unwrap(),
+    );
+    plain.write_all(b"SHUTDOWN SAVE\n").await.unwrap();
+    let mut reply = String::new();
+    plain.read_line(&mut reply).await.unwrap();
+    assert!(reply.starts_with("ERR APPEND_ONLY_DISABLED "));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn can_shut_down_gracefully_on_sigterm() {
+    let mut server = TestServer::start(&["--port", "18745"], &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18745").await.unwrap();
+    client.set("a", &1).await.unwrap();
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &server.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    assert!(server.wait_for_exit().success());
+    assert!(client.get::<_, u32>("a").await.is_err());
+}
+
+#[tokio::test]
+async fn can_serve_values_over_http() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18747", "--http-port", "18748"], &[]);
+
+    // Requests on a kept alive connection are answered in order
+    let mut http = tokio::net::TcpStream::connect("127.0.0.1:18748")
+        .await
+        .unwrap();
+    let body = r#"{"name": "ops", "age": 30}"#;
+    let requests = format!(
+        "PUT /v1/keys/users/user%201 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}\
+         PATCH /v1/keys/users/user%201 HTTP/1.1\r\nContent-Length: 13\r\n\r\n{{\"age\": null}}\
+         GET /v1/keys/nobody HTTP/1.1\r\n\r\n\
+         DELETE /v1/keys/users/user%201/name HTTP/1.1\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    http.write_all(requests.as_bytes()).await.unwrap();
+    let mut responses = String::new();
+    http.read_to_string(&mut responses).await.unwrap();
+    assert_eq!(
+        responses,
+        "HTTP/1.1 204 No Content\r\n\r\n\
+         HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 14\r\n\r\n{\"name\":\"ops\"}\
+         HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: 73\r\n\r\n\
+         {\"error\":{\"code\":\"KEY_NOT_FOUND\",\"message\":\"Key: \\\"nobody\\\" Not Found.\"}}\
+         HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"
+    );
+
+    // The same values are served to clients speaking Fabric
+    let mut client = FabricClient::connect("127.0.0.1:18747").await.unwrap();
+    let user: serde_json::Value = client.get("users").await.unwrap();
+    assert_eq!(user, serde_json::json!({"user 1": {}}));
+}
+
+#[tokio::test]
+async fn can_subscribe_to_changes_over_websocket() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18749", "--websocket-port", "18750"], &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18749").await.unwrap();
+    client.set("leaderboard", &[("alice", 1)]).await.unwrap();
+
+    let socket = tokio::net::TcpStream::connect("127.0.0.1:18750")
+        .await
+        .unwrap();
+    let mut socket = tokio::io::BufReader::new(socket);
+    socket
+        .write_all(
+            b"GET /v1/subscribe HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut handshake = String::new();
+    while !handshake.ends_with("\r\n\r\n") {
+        socket.read_line(&mut handshake).await.unwrap();
+    }
+    assert!(handshake.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(handshake.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+    // Client frames are masked, server frames aren't
+    let request = br#"{"op": "subscribe", "path": ["leaderboard"]}"#;
+    let mask = [7, 7, 7, 7];
+    let mut frame = vec![0x81, 0x80 | request.len() as u8];
+    frame.extend(mask);
+    frame.extend(request.iter().map(|b| b ^ 7));
+    socket.write_all(&frame).await.unwrap();
+
+    async fn read_message<R: AsyncReadExt + Unpin>(socket: &mut R) -> serde_json::Value {
+        let mut head = [0; 2];
+        socket.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 0x81);
+        let mut payload = vec![0; head[1] as usize];
+        socket.read_exact(&mut payload).await.unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    // The current value comes first, then every change under it
+    assert_eq!(
+        read_message(&mut socket).await,
+        serde_json::json!({"type": "snapshot", "path": ["leaderboard"], "value": [["alice", 1]]})
+    );
+    client.set("leaderboard", &[("bob", 2)]).await.unwrap();
+    client.set("elsewhere", &1).await.unwrap();
+    client.remove("leaderboard").await.unwrap();
+    assert_eq!(
+        read_message(&mut socket).await,
+        serde_json::json!({"type": "set", "path": ["leaderboard"], "value": [["bob", 2]]})
+    );
+    assert_eq!(
+        read_message(&mut socket).await,
+        serde_json::json!({"type": "remove", "path": ["leaderboard"]})
+    );
+}
+
+#[tokio::test]
+async fn disconnects_slow_websocket_subscribers() {
+    use tokio::io::AsyncWriteExt;
+
+    let _server = TestServer::start(
+        &[
+            "--port",
+            "18759",
+            "--websocket-port",
+            "18760",
+            "--client-output-buffer-limit",
+            "4096",
+        ],
+        &[],
+    );
+    let mut client = FabricClient::connect("127.0.0.1:18759").await.unwrap();
+
+    // Subscribe, then never read any of the changes
+    let mut socket = tokio::net::TcpStream::connect("127.0.0.1:18760")
+        .await
+        .unwrap();
+    let request = br#"{"op": "subscribe", "path": ["big"]}"#;
+    let mut upgrade = b"GET /v1/subscribe HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+          Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+          Sec-WebSocket-Version: 13\r\n\r\n"
+        .to_vec();
+    upgrade.extend([0x81, 0x80 | request.len() as u8, 0, 0, 0, 0]);
+    upgrade.extend(request);
+    socket.write_all(&upgrade).await.unwrap();
+
+    let mut slow_consumers = 0;
+    for i in 0..200 {
+        client
+            .set("big", &format!("{i}").repeat(10_000))
+            .await
+            .unwrap();
+        slow_consumers = client.stats().await.unwrap()["slow_consumers"]
+            .as_u64()
+            .unwrap();
+        if slow_consumers > 0 {
+            break;
+        }
+    }
+    assert_eq!(slow_consumers, 1);
+}
+
+#[tokio::test]
+async fn can_negotiate_binary_value_encodings() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let _server = TestServer::start(&["--port", "18751"], &[]);
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Series {
+        name: String,
+        points: Vec<(i64, f64)>,
+    }
+    let series = Series {
+        name: "latency".into(),
+        points: vec![(1_700_000_000, 0.25), (-1, -1024.5), (i64::MAX, 3.0)],
+    };
+
+    for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+        let mut client = FabricClient::connect("127.0.0.1:18751").await.unwrap();
+        client.use_request_ids();
+        client.use_encoding(encoding).await.unwrap();
+
+        client.set("series", &series).await.unwrap();
+        assert_eq!(client.get::<_, Series>("series").await.unwrap(), series);
+        assert_eq!(
+            client.get::<_, String>("series.name").await.unwrap(),
+            "latency"
+        );
+        assert!(matches!(
+            client.get::<_, Series>("missing").await,
+            Err(Error::KeyNotFound(key)) if key == "missing"
+        ));
+
+        let mut pipeline = Pipeline::new();
+        pipeline.set("count", &7).unwrap().get("count");
+        let results = client.execute(&pipeline).await.unwrap();
+        assert_eq!(results[1].as_ref().unwrap(), &serde_json::json!(7));
+
+        // Values are stored as JSON, whatever they were sent as
+        let mut json_client = FabricClient::connect("127.0.0.1:18751").await.unwrap();
+        assert_eq!(
+            json_client.get::<_, Series>("series").await.unwrap(),
+            series
+        );
+        json_client.remove("series").await.unwrap();
+    }
+
+    // Binary values can hold newlines, so they're only sent in frames
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:18751")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    stream.write_all(b"ENCODING cbor\n").await.unwrap();
+    let mut reply = String::new();
+    stream.read_line(&mut reply).await.unwrap();
+    assert!(reply.starts_with("ERR PROTOCOL"), "{reply}");
+}
+
+#[tokio::test]
+async fn can_store_binary_blobs_alongside_values() {
+    let path = std::env::temp_dir().join(format!("fabric-blobs-{}.aof", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let args = [
+        "--port",
+        "18752",
+        "--appendonly",
+        "yes",
+        "--appendfilename",
+        path.to_str().unwrap(),
+    ];
+    let png: Vec<u8> = [&b"\x89PNG\r\n\x1a\n"[..], &[0, 255, 10, 13]].concat();
+
+    let server = TestServer::start(&args, &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18752").await.unwrap();
+    client.set_blob("avatar", &png).await.unwrap();
+    client.set("profile", &"ops").await.unwrap();
+    assert_eq!(client.get_blob("avatar").await.unwrap(), png);
+    assert!(matches!(
+        client.get::<_, String>("avatar").await,
+        Err(Error::WrongType(key)) if key == "avatar"
+    ));
+    assert!(matches!(
+        client.get_blob("profile").await,
+        Err(Error::WrongType(key)) if key == "profile"
+    ));
+    drop(client);
+    drop(server);
+
+    // Blobs are persisted like any other value
+    let _server = TestServer::start(&args, &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18752").await.unwrap();
+    assert_eq!(client.get_blob("avatar").await.unwrap(), png);
+    client.remove("avatar").await.unwrap();
+    assert!(matches!(
+        client.get_blob("avatar").await,
+        Err(Error::KeyNotFound(_))
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn can_publish_to_subscribed_channels() {
+    let _server = TestServer::start(&["--port", "18753"], &[]);
+    let connect = || FabricClient::connect("127.0.0.1:18753");
+
+    let mut news = connect().await.unwrap().subscribe(&["news"]).await.unwrap();
+    let mut jobs = connect()
+        .await
+        .unwrap()
+        .psubscribe(&["jobs.*"])
+        .await
+        .unwrap();
+    let mut publisher = connect().await.unwrap();
+
+    assert_eq!(publisher.publish("news", "extra, extra").await.unwrap(), 1);
+    assert_eq!(publisher.publish("jobs.42", "done").await.unwrap(), 1);
+    assert_eq!(publisher.publish("weather", "sunny").await.unwrap(), 0);
+    assert_eq!(
+        news.next().await.unwrap().unwrap(),
+        Message {
+            channel: "news".into(),
+            pattern: None,
+            message: "extra, extra".into()
+        }
+    );
+    assert_eq!(
+        jobs.next().await.unwrap().unwrap(),
+        Message {
+            channel: "jobs.42".into(),
+            pattern: Some("jobs.*".into()),
+            message: "done".into()
+        }
+    );
+
+    // Subscriptions can change on the fly, and messages can span lines
+    news.unsubscribe(&["news"]).await.unwrap();
+    news.subscribe(&["weather"]).await.unwrap();
+    while publisher.publish("weather", "rain\nlater").await.unwrap() == 0 {}
+    assert_eq!(publisher.publish("news", "ignored").await.unwrap(), 0);
+    let message = news.next().await.unwrap().unwrap();
+    assert_eq!(
+        (&*message.channel, &*message.message),
+        ("weather", "rain\nlater")
+    );
+
+    // Only subscription commands are allowed until unsubscribed from everything
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:18753").unwrap();
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    let mut read_line = || {
+        let mut resp = String::new();
+        reader.read_line(&mut resp).unwrap();
+        resp
+    };
+    writeln!(stream, "SUBSCRIBE a b").unwrap();
+    assert_eq!(
+        read_line(),
+        "{\"channel\":\"a\",\"count\":1,\"type\":\"subscribe\"}\n"
+    );
+    assert_eq!(
+        read_line(),
+        "{\"channel\":\"b\",\"count\":2,\"type\":\"subscribe\"}\n"
+    );
+    writeln!(stream, "GET a").unwrap();
+    assert!(read_line().starts_with("ERR PROTOCOL "));
+    writeln!(stream, "PING").unwrap();
+    assert_eq!(read_line(), "{\"type\":\"pong\"}\n");
+    writeln!(stream, "UNSUBSCRIBE").unwrap();
+    let (first, second) = (read_line(), read_line());
+    assert!(first.contains("\"count\":1") && second.contains("\"count\":0"));
+    writeln!(stream, "PUBLISH a hi").unwrap();
+    assert_eq!(read_line(), "0\n");
+}
+
+#[tokio::test]
+async fn can_subscribe_to_keyspace_notifications() {
+    let _server = TestServer::start(&["--port", "18754"], &[]);
+    let mut client = FabricClient::connect("127.0.0.1:18754").await.unwrap();
+    let mut trades = FabricClient::connect("127.0.0.1:18754")
+        .await
+        .unwrap()
+        .psubscribe(&["__keyspace__:strategies.*.open_trade"])
+        .await
+        .unwrap();
+
+    client.set("strategies.7.open_trade", &3).await.unwrap();
+    client.set("strategies.7.closed", &true).await.unwrap();
+    client.remove("strategies.7.open_trade").await.unwrap();
+
+    let message = trades.next().await.unwrap().unwrap();
+    assert_eq!(message.channel, "__keyspace__:strategies.7.open_trade");
+    assert_eq!(message.message, "set strategies.7.open_trade");
+    let message = trades.next().await.unwrap().unwrap();
+    assert_eq!(message.message, "remove strategies.7.open_trade");
+}
+
+/// A fabric server running in its own process for the
+/// duration of a test, killed once it's dropped.
+struct TestServer(Child);
+impl TestServer {
+    /// Start a server, waiting until it accepts connections.
+    fn start(args: &[&str], envs: &[(&str, &str)]) -> Self {
+        let process = Command::new("./../target/debug/fabric-cache")
+            .args(args)
+            .envs(envs.iter().copied())
+            .spawn()
+            .expect("Failed to start Fabric Cache Server");
+
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        TestServer(process)
+    }
+
+    /// Wait for the server to exit on its own, failing the test if it takes too long.
+    fn wait_for_exit(&mut self) -> std::process::ExitStatus {
+        for _ in 0..100 {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                return status;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        panic!("Server didn't exit");
+    }
+}
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}